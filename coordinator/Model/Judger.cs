@@ -31,6 +31,16 @@ namespace Karenia.Rurikawa.Models.Judger {
         /// </summary>
         public bool CanAcceptNewTask { get; set; } = false;
 
+        /// <summary>
+        /// What this judger supports, or null if it hasn't said so.
+        /// </summary>
+        public ClientHelloMsg? Hello { get; set; }
+
+        /// <summary>
+        /// The last job request of this judger, with its resources and caches.
+        /// </summary>
+        public JobRequestMsg? LastRequest { get; set; }
+
         public Judger(
             string id,
             JudgerEntry dbJudgerEntry,
@@ -137,6 +147,14 @@ namespace Karenia.Rurikawa.Models.Judger {
         [JsonIgnore]
         public string? Judger { get; set; }
 
+        /// <summary>
+        /// The span that dispatched this job, in the W3C Trace Context format
+        /// (<c>traceparent</c> and optionally <c>tracestate</c>). Only filled
+        /// when sending this job to a judger.
+        /// </summary>
+        [NotMapped]
+        public Dictionary<string, string>? TraceContext { get; set; }
+
         public double Score {
             get {
                 var s = 0.0;
@@ -12,8 +12,18 @@ namespace Karenia.Rurikawa.Models.Judger {
     /// </summary>
     public class ServerMsg { }
 
+    /// <summary>
+    /// Message sent once a judger connects, and again in reply to
+    /// <c>ClientHelloMsg</c>.
+    /// </summary>
     [JsonDiscriminator("server_hello")]
-    public class ServerHelloMsg : ServerMsg { }
+    public class ServerHelloMsg : ServerMsg {
+        /// <summary>
+        /// The protocol version accepted for this connection. Absent in the
+        /// message sent on connection.
+        /// </summary>
+        public int? ProtocolVersion { get; set; }
+    }
 
     /// <summary>
     /// Message that provides a new job to judger with given id and specification.
@@ -116,6 +126,15 @@ namespace Karenia.Rurikawa.Models.Judger {
 
     /// <summary>
     /// Message that sends the output of a job in client
+    /// <para>
+    ///     Since protocol version 2, judgers also send the structured build
+    ///     event that the output comes from in an <c>event</c> field. It is
+    ///     deliberately not read: the output is only kept as plain text for
+    ///     <c>BuildOutputFile</c>, and the frontend has no view of build steps
+    ///     to forward events to. Judgers keep sending the plain-text form in
+    ///     <c>Stream</c> and <c>Error</c>, so nothing is lost until such a view
+    ///     exists.
+    /// </para>
     /// </summary>
     [JsonDiscriminator("job_output")]
     public class JobOutputMsg : ClientMsg {
@@ -188,6 +207,84 @@ namespace Karenia.Rurikawa.Models.Judger {
         /// An ID for tracking job requests.
         /// </summary>
         public FlowSnake? MessageId { get; set; }
+
+        /// <summary>
+        /// Resources of this judger available for new jobs, if probed
+        /// </summary>
+        public FreeResources? Resources { get; set; }
+
+        /// <summary>
+        /// Tags of this judger
+        /// </summary>
+        public List<string>? Tags { get; set; }
+
+        /// <summary>
+        /// Test suites already downloaded by this judger
+        /// </summary>
+        public List<FlowSnake>? CachedTestSuites { get; set; }
+
+        /// <summary>
+        /// Tags of images present on this judger, or null if they haven't
+        /// changed since its last request
+        /// </summary>
+        public List<string>? CachedImages { get; set; }
+    }
+
+    /// <summary>
+    /// Resources of a judger available for new jobs. Values are null if the
+    /// judger can't probe them.
+    /// </summary>
+    public class FreeResources {
+        /// <summary>
+        /// Idle CPU cores
+        /// </summary>
+        public double? Cpu { get; set; }
+
+        /// <summary>
+        /// Available memory, in bytes
+        /// </summary>
+        public long? Memory { get; set; }
+
+        /// <summary>
+        /// Free disk space, in bytes
+        /// </summary>
+        public long? Disk { get; set; }
+    }
+
+    /// <summary>
+    /// Message that tells what a judger supports, sent right after it connects.
+    /// </summary>
+    [JsonDiscriminator("client_hello")]
+    public class ClientHelloMsg : ClientMsg {
+        /// <summary>
+        /// Version of the judger
+        /// </summary>
+        public string Version { get; set; }
+
+        /// <summary>
+        /// The newest protocol version the judger talks in
+        /// </summary>
+        public int ProtocolVersion { get; set; }
+
+        /// <summary>
+        /// The oldest protocol version the judger talks in
+        /// </summary>
+        public int MinProtocolVersion { get; set; }
+
+        /// <summary>
+        /// Types of server messages the judger handles
+        /// </summary>
+        public List<string> MessageTypes { get; set; }
+
+        /// <summary>
+        /// Features of test suites the judger supports
+        /// </summary>
+        public List<string> Features { get; set; }
+
+        /// <summary>
+        /// Backends the judger runs test cases with
+        /// </summary>
+        public List<string> RunnerBackends { get; set; }
     }
 
     [JsonDiscriminator("revert_job")]
@@ -1,6 +1,7 @@
 using System;
 using System.Collections.Concurrent;
 using System.Collections.Generic;
+using System.Diagnostics;
 using System.Dynamic;
 using System.IO;
 using System.Linq;
@@ -56,6 +57,19 @@ namespace Karenia.Rurikawa.Coordinator.Services {
         /// requires this lock to be acquired.
         /// </summary>
         readonly SemaphoreSlim connectionLock = new SemaphoreSlim(1);
+
+        /// <summary>
+        /// The newest protocol version between judgers and this coordinator.
+        /// <list type="bullet">
+        ///     <item>2: judgers send an <c>event</c> field in <c>JobOutputMsg</c>. See there for why it's not read.</item>
+        /// </list>
+        /// </summary>
+        public const int ProtocolVersion = 2;
+
+        /// <summary>
+        /// The oldest protocol version this coordinator still accepts.
+        /// </summary>
+        public const int MinProtocolVersion = 1;
         private readonly JsonSerializerOptions jsonSerializerOptions;
         private readonly IServiceScopeFactory scopeProvider;
         private readonly FrontendUpdateService frontendService;
@@ -174,6 +188,8 @@ namespace Karenia.Rurikawa.Coordinator.Services {
                             OnJobOutputMessage(clientId, msg1); break;
                         case RevertJobMsg msg1:
                             OnRevertJobMessage(clientId, msg1); break;
+                        case ClientHelloMsg msg1:
+                            OnClientHelloMessage(clientId, msg1); break;
                         default:
                             logger.LogCritical("Unable to handle message type {0}", msg.GetType().Name);
                             break;
@@ -202,12 +218,45 @@ namespace Karenia.Rurikawa.Coordinator.Services {
             }
         }
 
+        /// <summary>
+        /// Replies a <c>ClientHelloMsg</c> with the newest protocol version
+        /// both sides talk in.
+        /// </summary>
+        async void OnClientHelloMessage(string clientId, ClientHelloMsg msg) {
+            Judger? conn;
+            using (await connectionLock.LockAsync()) {
+                if (!connections.TryGetValue(clientId, out conn)) return;
+                conn.Hello = msg;
+            }
+
+            var version = Math.Min(msg.ProtocolVersion, ProtocolVersion);
+            if (version < msg.MinProtocolVersion || version < MinProtocolVersion) {
+                // No common version, so nothing this judger sends can be trusted
+                logger.LogWarning(
+                    "Judger {0} (version {1}) talks in protocol {2} to {3}, but {4} to {5} is accepted. Disconnecting.",
+                    clientId, msg.Version, msg.MinProtocolVersion, msg.ProtocolVersion,
+                    MinProtocolVersion, ProtocolVersion);
+                conn.CanAcceptNewTask = false;
+                await conn.Socket.Close(
+                    System.Net.WebSockets.WebSocketCloseStatus.PolicyViolation,
+                    $"No common protocol version, coordinator accepts {MinProtocolVersion} to {ProtocolVersion}",
+                    CancellationToken.None);
+                return;
+            }
+
+            logger.LogInformation(
+                "Judger {0} (version {1}) talks in protocol {2}", clientId, msg.Version, version);
+            await conn.Socket.SendMessage(new ServerHelloMsg { ProtocolVersion = version });
+        }
+
         async void OnJobRequestMessage(string clientId, JobRequestMsg msg) {
             // should we dispatch a new job for this judger?
             using var reqLock = await connectionLock.LockAsync();
             if (connections.TryGetValue(clientId, out var conn)) {
                 conn.CanAcceptNewTask = msg.ActiveTaskCount > 0;
                 conn.ActiveTaskCount = msg.ActiveTaskCount;
+                msg.CachedImages ??= conn.LastRequest?.CachedImages;
+                conn.LastRequest = msg;
 
                 reqLock.Dispose();
 
@@ -416,6 +465,21 @@ namespace Karenia.Rurikawa.Coordinator.Services {
             return await judgerService.GetJudgerByToken(tokenString);
         }
 
+        /// <summary>
+        /// The trace context of the current activity, which judgers use as
+        /// the parent of their job spans.
+        /// </summary>
+        static Dictionary<string, string>? CurrentTraceContext() {
+            var activity = Activity.Current;
+            if (activity == null || activity.IdFormat != ActivityIdFormat.W3C) return null;
+            var traceContext = new Dictionary<string, string> {
+                ["traceparent"] = activity.Id!
+            };
+            if (!string.IsNullOrEmpty(activity.TraceStateString))
+                traceContext["tracestate"] = activity.TraceStateString;
+            return traceContext;
+        }
+
         /// <summary>
         /// Dispatch a single job to the given judger. 
         /// 
@@ -426,6 +490,7 @@ namespace Karenia.Rurikawa.Coordinator.Services {
         /// </summary>
         protected async ValueTask<bool> DispatchJob(Judger judger, Job job) {
             var redis = await this.redis.GetDatabase();
+            job.TraceContext = CurrentTraceContext();
             await redis.StringSetAsync(FormatJobStdout(job.Id), "", expiry: TimeSpan.FromHours(2), flags: CommandFlags.FireAndForget);
             await redis.StringSetAsync(FormatJobError(job.Id), "", expiry: TimeSpan.FromHours(2), flags: CommandFlags.FireAndForget);
 
@@ -453,6 +518,8 @@ namespace Karenia.Rurikawa.Coordinator.Services {
             List<Job> jobs,
             FlowSnake? replyTo = null) {
             var redis = await this.redis.GetDatabase();
+            var traceContext = CurrentTraceContext();
+            foreach (var job in jobs) job.TraceContext = traceContext;
 
             try {
                 await judger.Socket.SendMessage(new MultipleNewJobServerMsg() {
@@ -493,12 +560,32 @@ namespace Karenia.Rurikawa.Coordinator.Services {
                 .OrderBy(j => j.Id).FirstOrDefaultAsync();
         }
 
-        protected async Task<List<Job>> GetUndispatchedJobsFromDatabase(RurikawaDb db, int count) {
-            var res = await QueuedCriteria(db.Jobs)
+        /// <summary>
+        /// How many of the oldest queued jobs are considered when preferring
+        /// jobs whose test suite a judger has cached, per job requested.
+        /// Keeps old jobs from waiting forever behind cached ones.
+        /// </summary>
+        const int PREFERENCE_WINDOW = 4;
+
+        protected async Task<List<Job>> GetUndispatchedJobsFromDatabase(
+            RurikawaDb db,
+            int count,
+            ICollection<FlowSnake>? cachedTestSuites = null) {
+            if (cachedTestSuites == null || cachedTestSuites.Count == 0) {
+                return await QueuedCriteria(db.Jobs)
+                    .OrderBy(j => j.Id)
+                    .Take(count)
+                    .ToListAsync();
+            }
+            var window = await QueuedCriteria(db.Jobs)
                 .OrderBy(j => j.Id)
-                .Take(count)
+                .Take(count * PREFERENCE_WINDOW)
                 .ToListAsync();
-            return res;
+            // OrderBy is stable, so jobs stay oldest first within each group
+            return window
+                .OrderBy(j => cachedTestSuites.Contains(j.TestSuite) ? 0 : 1)
+                .Take(count)
+                .ToList();
         }
 
         public static IQueryable<Job> QueuedCriteria(IQueryable<Job> jobs) {
@@ -560,7 +647,8 @@ namespace Karenia.Rurikawa.Coordinator.Services {
             using var scope = scopeProvider.CreateScope();
             var db = GetDb(scope);
             using var tx = await db.Database.BeginTransactionAsync(System.Data.IsolationLevel.Serializable);
-            var jobs = await GetUndispatchedJobsFromDatabase(db, count);
+            var jobs = await GetUndispatchedJobsFromDatabase(
+                db, count, judger.LastRequest?.CachedTestSuites);
 
             try {
                 var res = await DispatchJobs(judger, jobs, replyTo);
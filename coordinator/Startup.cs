@@ -119,6 +119,7 @@ namespace Karenia.Rurikawa.Coordinator {
             dis.RegisterType<Models.Judger.ClientStatusMsg>();
             dis.RegisterType<Models.Judger.JobProgressMsg>();
             dis.RegisterType<Models.Judger.ServerHelloMsg>();
+            dis.RegisterType<Models.Judger.ClientHelloMsg>();
             dis.RegisterType<Models.Judger.JobResultMsg>();
             dis.RegisterType<Models.Judger.PartialResultMsg>();
             dis.RegisterType<Models.Judger.AbortJobServerMsg>();
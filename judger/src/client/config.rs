@@ -1,12 +1,24 @@
-use super::model::AbortJob;
-use crate::prelude::{CancellationTokenHandle, FlowSnake};
+use super::{
+    err::TlsConfigErr,
+    metrics::Metrics,
+    model::{AbortJob, JobStage},
+    tls::ClientTls,
+};
+use crate::{
+    fs::net::{ArchiveLimits, GitCredential},
+    prelude::{CancellationTokenHandle, FlowSnake},
+    runner::{build_event::BuildEventLimits, exec::SecurityProfile, image::RegistryCredential},
+    util::tar::SymlinkPolicy,
+};
 use arc_swap::{ArcSwap, ArcSwapOption};
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
     collections::HashMap,
+    net::SocketAddr,
     path::PathBuf,
-    sync::atomic::AtomicBool,
+    sync::atomic::{AtomicBool, AtomicU32},
     sync::{atomic::AtomicUsize, Arc},
 };
 use tokio::{
@@ -17,33 +29,141 @@ use tokio::{
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientConfig {
     pub host: String,
+    /// Standby coordinators, tried in this order when `host` is unavailable.
+    #[serde(default)]
+    pub fallback_hosts: Vec<String>,
     pub max_concurrent_tasks: usize,
     pub ssl: bool,
     pub access_token: Option<String>,
+    /// Access tokens given by standby coordinators this judger registered at,
+    /// keyed by host. Standbys not listed here are sent `access_token`.
+    #[serde(default)]
+    pub fallback_access_tokens: HashMap<String, String>,
     pub register_token: Option<String>,
     pub alternate_name: Option<String>,
     pub tags: Option<Vec<String>>,
     pub cache_folder: PathBuf,
     #[serde(default)]
     pub docker_config: Arc<DockerConfig>,
+    /// Maximum total size of cached repositories, in bytes. Setting this to 0
+    /// disables the cache, and every job clones its repository from scratch.
+    #[serde(default = "default_repo_cache_size")]
+    pub repo_cache_size: u64,
+    /// Credentials used to fetch repositories, keyed by the host name of the
+    /// remote.
+    #[serde(default)]
+    pub git_credentials: HashMap<String, GitCredential>,
+    /// Limits of source archives sent in place of repositories.
+    #[serde(default)]
+    pub archive_limits: ArchiveLimits,
+    /// Credentials used to pull images, keyed by the registry host, e.g.
+    /// `docker.io` or `localhost:5000`.
+    #[serde(default)]
+    pub registry_credentials: HashMap<String, RegistryCredential>,
+    /// Address to serve Prometheus metrics at, e.g. `127.0.0.1:9090`. Metrics
+    /// are not served if absent.
+    #[serde(default)]
+    pub metrics_listen: Option<SocketAddr>,
+    /// Path of the Unix socket that `rurikawa ctl` talks to. Defaults to
+    /// `control.sock` inside the cache folder.
+    #[serde(default)]
+    pub control_socket: Option<PathBuf>,
+    /// Log files of each job.
+    #[serde(default)]
+    pub job_log: JobLogConfig,
+    /// TLS settings of connections to the coordinator.
+    #[serde(default)]
+    pub tls: TlsConfig,
+}
+
+impl ClientConfig {
+    /// Path of the control socket, see [`ClientConfig::control_socket`].
+    pub fn control_socket_path(&self) -> PathBuf {
+        self.control_socket
+            .clone()
+            .unwrap_or_else(|| self.cache_folder.join("control.sock"))
+    }
+
+    /// All coordinators in priority order: `host`, then `fallback_hosts`.
+    pub fn hosts(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.host.as_str()).chain(self.fallback_hosts.iter().map(|h| h.as_str()))
+    }
+}
+
+fn default_repo_cache_size() -> u64 {
+    4 * 1024 * 1024 * 1024
 }
 
 impl Default for ClientConfig {
     fn default() -> Self {
         ClientConfig {
             host: "".into(),
+            fallback_hosts: vec![],
             max_concurrent_tasks: 1,
             ssl: false,
             access_token: None,
+            fallback_access_tokens: HashMap::new(),
             register_token: None,
             alternate_name: None,
             tags: None,
             cache_folder: PathBuf::new(),
             docker_config: Arc::new(Default::default()),
+            repo_cache_size: default_repo_cache_size(),
+            git_credentials: HashMap::new(),
+            archive_limits: Default::default(),
+            registry_credentials: HashMap::new(),
+            metrics_listen: None,
+            control_socket: None,
+            job_log: Default::default(),
+            tls: Default::default(),
         }
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JobLogConfig {
+    /// Write logs of each job into a file of its own, inside the `logs` folder
+    /// of the cache folder.
+    pub enabled: bool,
+
+    /// Upload the log file of each job along with its result.
+    pub upload: bool,
+
+    /// Log files older than this are removed, in seconds.
+    pub retention: u64,
+}
+
+impl Default for JobLogConfig {
+    fn default() -> Self {
+        JobLogConfig {
+            enabled: true,
+            upload: false,
+            retention: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// PEM file of the CA certificates to trust, in place of the built-in
+    /// roots.
+    pub ca_bundle: Option<PathBuf>,
+
+    /// PEM file of the certificate chain presented to the coordinator, for
+    /// mutual TLS. Requires `client_key`.
+    pub client_cert: Option<PathBuf>,
+
+    /// PEM file of the private key of `client_cert`, in PKCS#8 or PKCS#1.
+    pub client_key: Option<PathBuf>,
+
+    /// Base64-encoded SHA-256 hashes of the SubjectPublicKeyInfo of the
+    /// coordinator's certificate. The certificate must match one of them if
+    /// any is set.
+    pub pinned_spki: Vec<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct DockerConfig {
@@ -58,6 +178,44 @@ pub struct DockerConfig {
     /// CPU share available for running use. This field will be the upper limit
     /// of the load factor of all running task in the testing container.
     pub run_cpu_share: Option<f64>,
+
+    /// Upper bound of the stdout and stderr size limits that test suites may
+    /// set, in bytes.
+    pub max_output_size: Option<usize>,
+
+    /// Outputs larger than this size are written into temporary files instead
+    /// of being kept in memory, in bytes.
+    pub output_spill_threshold: usize,
+
+    /// What to do with symbolic links in submitted code when packing it as
+    /// the build context.
+    pub context_symlinks: SymlinkPolicy,
+
+    /// Restrictions of containers running submitted code. Test suites may
+    /// relax some of them.
+    pub security: SecurityProfile,
+
+    /// Upper bound and default of the disk space that containers running
+    /// submitted code may write, in bytes.
+    pub max_disk_limit: Option<u64>,
+
+    /// Size limit of the volume holding test suite data, in bytes.
+    pub data_volume_size_limit: Option<u64>,
+
+    /// Reuse images built from the same sources instead of building them
+    /// again.
+    pub build_cache: bool,
+
+    /// Maximum total size of cached builds, in bytes, counting only layers
+    /// not shared with other images. The least recently used builds are
+    /// removed beyond it.
+    pub build_cache_size: u64,
+
+    /// Memory available for building images from submitted code, in bytes.
+    pub build_memory_limit: Option<u64>,
+
+    /// Limits of the build progress sent to the coordinator.
+    pub build_event_limits: BuildEventLimits,
 }
 
 impl Default for DockerConfig {
@@ -66,6 +224,26 @@ impl Default for DockerConfig {
             docker_user: None,
             build_cpu_share: Some(0.5),
             run_cpu_share: Some(0.3),
+            max_output_size: Some(16 * 1024 * 1024),
+            output_spill_threshold: 1024 * 1024,
+            context_symlinks: SymlinkPolicy::Preserve,
+            security: SecurityProfile::default(),
+            max_disk_limit: Some(1024 * 1024 * 1024),
+            data_volume_size_limit: Some(1024 * 1024 * 1024),
+            build_cache: true,
+            build_cache_size: 8 * 1024 * 1024 * 1024,
+            build_memory_limit: Some(2 * 1024 * 1024 * 1024),
+            build_event_limits: BuildEventLimits::default(),
+        }
+    }
+}
+
+impl DockerConfig {
+    /// The disk limit of a job whose test suite asks for `requested` bytes.
+    pub fn disk_limit(&self, requested: Option<u64>) -> Option<u64> {
+        match (requested, self.max_disk_limit) {
+            (Some(requested), Some(max)) => Some(requested.min(max)),
+            (requested, max) => requested.or(max),
         }
     }
 }
@@ -85,10 +263,20 @@ pub struct SharedClientData {
     pub aborting: AtomicBool,
     /// HTTP client
     pub client: reqwest::Client,
+    /// TLS config of connections to the coordinator, if not the default one
+    pub tls: Option<ClientTls>,
 
     /// All test suites whose folder is being edited. The lock MUST be used internally.
     test_suite_modify: std::sync::Mutex<HashMap<FlowSnake, TestSuiteStatus>>,
 
+    /// Locks of cached repositories, keyed by their folder name. The lock MUST be used internally.
+    repo_cache_modify: std::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+
+    /// Locks of shared base images, keyed by their tag. The lock MUST be used internally.
+    base_image_modify: std::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+
+    /// Stage and start time of all jobs currently running
+    pub running_job_info: DashMap<FlowSnake, RunningJobInfo>,
     /// Handle for all jobs currently running
     pub running_job_handles: Mutex<HashMap<FlowSnake, (JoinHandle<()>, CancellationTokenHandle)>>,
     /// Handle for all jobs currently cancelling
@@ -97,37 +285,95 @@ pub struct SharedClientData {
     pub cancelling_job_info: dashmap::DashMap<FlowSnake, AbortJob>,
     /// Global cancellation token handle
     pub abort_handle: CancellationTokenHandle,
+    /// Cancellation token handle of the current connection to the coordinator
+    pub connection_handle: ArcSwapOption<CancellationTokenHandle>,
+    /// Metrics of handled jobs
+    pub metrics: Metrics,
+    /// Whether this client stops requesting new jobs
+    pub polling_paused: AtomicBool,
+    /// Whether this client exits once running jobs finish. Implies
+    /// `polling_paused`.
+    pub draining: AtomicBool,
+    /// Protocol version accepted by the coordinator of the current
+    /// connection, or 0 if it hasn't negotiated one
+    pub protocol_version: AtomicU32,
+    /// Whether the coordinator of the current connection accepted a protocol
+    /// version this judger can't talk in. No jobs are requested if so.
+    pub protocol_incompatible: AtomicBool,
+    /// Index of the coordinator in use in [`ClientConfig::hosts`]
+    pub active_host: AtomicUsize,
     // /// The docker instance we're connecting
     // pub docker: Docker
 }
 
 impl SharedClientData {
-    pub fn new(cfg: ClientConfig) -> SharedClientData {
-        SharedClientData {
+    pub fn new(cfg: ClientConfig) -> Result<SharedClientData, TlsConfigErr> {
+        let tls = ClientTls::from_config(&cfg.tls)?;
+        // WORKAROUND: Client hang issue in hyper crate.
+        // see: https://github.com/hyperium/hyper/issues/2312
+        let mut client = reqwest::Client::builder()
+            .pool_idle_timeout(std::time::Duration::from_secs(0))
+            .pool_max_idle_per_host(0);
+        if let Some(tls) = &tls {
+            client = client.use_preconfigured_tls((*tls.0).clone());
+        }
+        Ok(SharedClientData {
             cfg: ArcSwap::new(Arc::new(cfg)),
             conn_id: rand::random(),
-            // WORKAROUND: Client hang issue in hyper crate.
-            // see: https://github.com/hyperium/hyper/issues/2312
-            client: reqwest::Client::builder()
-                .pool_idle_timeout(std::time::Duration::from_secs(0))
-                .pool_max_idle_per_host(0)
-                .build()
-                .unwrap(),
+            client: client.build().unwrap(),
+            tls,
             aborting: AtomicBool::new(false),
             waiting_for_jobs: ArcSwapOption::new(None),
             running_tests: AtomicUsize::new(0),
             test_suite_modify: std::sync::Mutex::new(HashMap::new()),
+            repo_cache_modify: std::sync::Mutex::new(HashMap::new()),
+            base_image_modify: std::sync::Mutex::new(HashMap::new()),
+            running_job_info: DashMap::new(),
             running_job_handles: Mutex::new(HashMap::new()),
             cancelling_job_handles: Mutex::new(HashMap::new()),
             cancelling_job_info: DashMap::new(),
             abort_handle: CancellationTokenHandle::new(),
-        }
+            connection_handle: ArcSwapOption::new(None),
+            metrics: Metrics::default(),
+            polling_paused: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
+            protocol_version: AtomicU32::new(0),
+            protocol_incompatible: AtomicBool::new(false),
+            active_host: AtomicUsize::new(0),
+        })
     }
 
     pub fn swap_cfg(&self, cfg: Arc<ClientConfig>) -> Arc<ClientConfig> {
         self.cfg.swap(cfg)
     }
 
+    /// Apply a config read while running. Settings about the connection are
+    /// applied by reconnecting. Running jobs keep the config they started with.
+    pub fn reload_cfg(&self, mut cfg: ClientConfig) {
+        let old = self.cfg_ref();
+        cfg.cache_folder = old.cache_folder.clone();
+        if cfg.metrics_listen != old.metrics_listen
+            || cfg.control_socket != old.control_socket
+            || cfg.tls != old.tls
+        {
+            tracing::warn!(
+                "Changes to `metrics_listen`, `control_socket` and `tls` take effect after restarting"
+            );
+        }
+        let reconnect = cfg.host != old.host
+            || cfg.fallback_hosts != old.fallback_hosts
+            || cfg.ssl != old.ssl
+            || cfg.access_token != old.access_token
+            || cfg.fallback_access_tokens != old.fallback_access_tokens;
+        self.swap_cfg(Arc::new(cfg));
+        if reconnect {
+            if let Some(connection) = self.connection_handle.load_full() {
+                tracing::info!("Reconnecting to apply the new config");
+                connection.cancel();
+            }
+        }
+    }
+
     pub fn cfg(&self) -> arc_swap::Guard<Arc<ClientConfig>> {
         ArcSwap::load(&self.cfg)
     }
@@ -136,6 +382,62 @@ impl SharedClientData {
         ArcSwap::load_full(&self.cfg)
     }
 
+    /// The standby coordinator at `idx` of [`ClientConfig::hosts`], or `None`
+    /// if it's the primary one.
+    fn fallback_host_at(cfg: &ClientConfig, idx: usize) -> Option<&String> {
+        match idx {
+            0 => None,
+            idx => cfg.fallback_hosts.get(idx - 1),
+        }
+    }
+
+    fn active_fallback_host<'a>(&self, cfg: &'a ClientConfig) -> Option<&'a String> {
+        Self::fallback_host_at(
+            cfg,
+            self.active_host.load(std::sync::atomic::Ordering::SeqCst),
+        )
+    }
+
+    /// The coordinator at `idx` of [`ClientConfig::hosts`].
+    pub fn host_at(&self, idx: usize) -> String {
+        let cfg = self.cfg();
+        Self::fallback_host_at(&cfg, idx)
+            .unwrap_or(&cfg.host)
+            .clone()
+    }
+
+    /// The access token for the coordinator at `idx` of
+    /// [`ClientConfig::hosts`].
+    pub fn access_token_at(&self, idx: usize) -> Option<String> {
+        let cfg = self.cfg();
+        Self::fallback_host_at(&cfg, idx)
+            .and_then(|host| cfg.fallback_access_tokens.get(host))
+            .or(cfg.access_token.as_ref())
+            .cloned()
+    }
+
+    /// The coordinator in use, see [`SharedClientData::active_host`].
+    pub fn host(&self) -> String {
+        self.host_at(self.active_host.load(std::sync::atomic::Ordering::SeqCst))
+    }
+
+    /// The access token for the coordinator in use.
+    pub fn access_token(&self) -> Option<String> {
+        self.access_token_at(self.active_host.load(std::sync::atomic::Ordering::SeqCst))
+    }
+
+    /// Keep `token` as the access token for the coordinator in use.
+    pub fn set_access_token(&self, token: String) {
+        let mut cfg = (**self.cfg()).clone();
+        match self.active_fallback_host(&cfg).cloned() {
+            Some(host) => {
+                cfg.fallback_access_tokens.insert(host, token);
+            }
+            None => cfg.access_token = Some(token),
+        }
+        self.swap_cfg(Arc::new(cfg));
+    }
+
     pub fn register_endpoint(&self) -> String {
         let ssl = if self.cfg().ssl {
             format_args!("https")
@@ -143,17 +445,23 @@ impl SharedClientData {
             format_args!("http")
         };
 
-        format!("{}://{}/api/v1/judger/register", ssl, self.cfg().host)
+        format!("{}://{}/api/v1/judger/register", ssl, self.host())
     }
 
     pub fn verify_endpoint(&self) -> String {
+        self.verify_endpoint_at(&self.host())
+    }
+
+    /// The verify endpoint of coordinator `host`, which may not be the one in
+    /// use.
+    pub fn verify_endpoint_at(&self, host: &str) -> String {
         let ssl = if self.cfg().ssl {
             format_args!("https")
         } else {
             format_args!("http")
         };
 
-        format!("{}://{}/api/v1/judger/verify", ssl, self.cfg().host)
+        format!("{}://{}/api/v1/judger/verify", ssl, host)
     }
 
     pub fn websocket_endpoint(&self) -> String {
@@ -163,11 +471,11 @@ impl SharedClientData {
             format_args!("ws")
         };
 
-        if let Some(token) = &self.cfg().access_token {
+        if let Some(token) = self.access_token() {
             format!(
                 "{}://{}/api/v1/judger/ws?token={}&conn={:x}",
                 ssl,
-                self.cfg().host,
+                self.host(),
                 token,
                 self.conn_id
             )
@@ -175,7 +483,7 @@ impl SharedClientData {
             format!(
                 "{}://{}/api/v1/judger/ws?conn={:x}",
                 ssl,
-                self.cfg().host,
+                self.host(),
                 self.conn_id
             )
         }
@@ -190,7 +498,7 @@ impl SharedClientData {
         format!(
             "{}://{}/api/v1/judger/download-suite/{}",
             ssl,
-            self.cfg().host,
+            self.host(),
             suite_id
         )
     }
@@ -201,7 +509,7 @@ impl SharedClientData {
         } else {
             format_args!("http")
         };
-        format!("{}://{}/api/v1/tests/{}", ssl, self.cfg().host, suite_id)
+        format!("{}://{}/api/v1/tests/{}", ssl, self.host(), suite_id)
     }
 
     pub fn result_upload_endpoint(&self) -> String {
@@ -210,7 +518,7 @@ impl SharedClientData {
         } else {
             format_args!("http")
         };
-        format!("{}://{}/api/v1/judger/upload", ssl, self.cfg().host)
+        format!("{}://{}/api/v1/judger/upload", ssl, self.host())
     }
 
     pub fn result_send_endpoint(&self) -> String {
@@ -219,7 +527,17 @@ impl SharedClientData {
         } else {
             format_args!("http")
         };
-        format!("{}://{}/api/v1/judger/result", ssl, self.cfg().host)
+        format!("{}://{}/api/v1/judger/result", ssl, self.host())
+    }
+
+    /// Resolves a path on the coordinator (e.g. `/api/v1/...`) to a full URL.
+    pub fn coordinator_url(&self, path: &str) -> String {
+        let ssl = if self.cfg().ssl {
+            format_args!("https")
+        } else {
+            format_args!("http")
+        };
+        format!("{}://{}{}", ssl, self.host(), path)
     }
 
     pub fn job_folder_root(&self) -> PathBuf {
@@ -243,10 +561,45 @@ impl SharedClientData {
             .join(format!("{}.lock", suite_id))
     }
 
+    /// Hashes of all files inside the test suite, written after it's unpacked.
+    pub fn test_suite_folder_manifest(&self, suite_id: FlowSnake) -> PathBuf {
+        self.test_suite_folder_root()
+            .join(format!("{}.manifest.json", suite_id))
+    }
+
+    pub fn repo_cache_folder_root(&self) -> PathBuf {
+        self.cfg().cache_folder.join("repos")
+    }
+
+    /// The name of the cache folder of the repository at `repo`.
+    pub fn repo_cache_key(repo: &str) -> String {
+        hex::encode(Sha256::digest(repo.as_bytes()))
+    }
+
+    pub fn repo_cache_folder(&self, key: &str) -> PathBuf {
+        self.repo_cache_folder_root().join(key)
+    }
+
     pub fn temp_file_folder_root(&self) -> PathBuf {
         self.cfg().cache_folder.join("files")
     }
 
+    /// The folder to put temporary files of a job in. It is removed after the
+    /// job finishes.
+    pub fn job_temp_file_folder(&self, job_id: FlowSnake) -> PathBuf {
+        self.temp_file_folder_root().join(job_id.to_string())
+    }
+
+    pub fn job_log_folder_root(&self) -> PathBuf {
+        self.cfg().cache_folder.join("logs")
+    }
+
+    /// The log file of a job, see [`JobLogConfig`]. Unlike the job folder, it
+    /// is kept after the job finishes.
+    pub fn job_log_file(&self, job_id: FlowSnake) -> PathBuf {
+        self.job_log_folder_root().join(format!("{}.log", job_id))
+    }
+
     pub fn random_temp_file_path(&self) -> PathBuf {
         self.temp_file_folder_root()
             .join(FlowSnake::generate().to_string())
@@ -304,6 +657,46 @@ impl SharedClientData {
         lock
     }
 
+    fn repo_cache_lock(&self, key: &str) -> Arc<tokio::sync::Mutex<()>> {
+        let mut repos_map = self
+            .repo_cache_modify
+            .lock()
+            .expect("something panicked when locking this lock. Panic!");
+        repos_map.entry(key.to_owned()).or_default().clone()
+    }
+
+    pub async fn before_repo_cache_modify(&self, key: &str) -> OwnedMutexGuard<()> {
+        let arc = self.repo_cache_lock(key);
+
+        tracing::debug!(repo_cache=%key, "TRY_ACQ repo_cache_modify_permit");
+        let lock = arc.lock_owned().await;
+        tracing::debug!(repo_cache=%key, "ACQ repo_cache_modify_permit");
+        lock
+    }
+
+    /// Like [`Self::before_repo_cache_modify`], but returns `None` instead of
+    /// waiting if the cached repository is in use.
+    pub fn try_before_repo_cache_modify(&self, key: &str) -> Option<OwnedMutexGuard<()>> {
+        self.repo_cache_lock(key).try_lock_owned().ok()
+    }
+
+    /// Acquire the lock of the shared base image tagged `tag`, so that only
+    /// one job builds it at a time.
+    pub async fn before_base_image_build(&self, tag: &str) -> OwnedMutexGuard<()> {
+        let arc = {
+            let mut images_map = self
+                .base_image_modify
+                .lock()
+                .expect("something panicked when locking this lock. Panic!");
+            images_map.entry(tag.to_owned()).or_default().clone()
+        };
+
+        tracing::debug!(base_image=%tag, "TRY_ACQ base_image_modify_permit");
+        let lock = arc.lock_owned().await;
+        tracing::debug!(base_image=%tag, "ACQ base_image_modify_permit");
+        lock
+    }
+
     /// Function to call before the job starts. Creates data for the corresponding test suites.
     #[must_use]
     pub fn before_job_start(self: Arc<Self>, id: FlowSnake) -> TestSuiteRunningGuard {
@@ -352,6 +745,40 @@ impl SharedClientData {
             .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
         res - 1
     }
+
+    /// Record that the running job `id` has entered `stage`.
+    pub fn set_job_stage(&self, id: FlowSnake, stage: JobStage) {
+        if let Some(mut info) = self.running_job_info.get_mut(&id) {
+            info.stage = stage;
+        }
+    }
+
+    /// Whether this client should request new jobs from the coordinator.
+    pub fn accepts_new_jobs(&self) -> bool {
+        !self
+            .polling_paused
+            .load(std::sync::atomic::Ordering::SeqCst)
+            && !self.draining.load(std::sync::atomic::Ordering::SeqCst)
+            && !self
+                .protocol_incompatible
+                .load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// Whether the coordinator of the current connection has negotiated
+    /// protocol `version` or a newer one.
+    pub fn negotiated_protocol(&self, version: u32) -> bool {
+        self.protocol_version
+            .load(std::sync::atomic::Ordering::SeqCst)
+            >= version
+    }
+}
+
+/// What a running job is doing.
+#[derive(Debug, Clone)]
+pub struct RunningJobInfo {
+    pub stage: JobStage,
+    /// When the job was received.
+    pub received_at: std::time::Instant,
 }
 
 /// Data structure to ensure that test suites are safe to modify.
@@ -415,3 +842,89 @@ impl Drop for TestSuiteRunningGuard {
         self.client_data.suite_drop(self.suite_id);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reload_cfg() {
+        let data = SharedClientData::new(ClientConfig {
+            host: "judge.example.com".into(),
+            cache_folder: "/var/cache/rurikawa".into(),
+            ..Default::default()
+        })
+        .unwrap();
+        let connection = CancellationTokenHandle::new();
+        data.connection_handle
+            .store(Some(Arc::new(connection.clone())));
+        let snapshot = data.cfg_ref();
+
+        data.reload_cfg(ClientConfig {
+            host: "judge.example.com".into(),
+            max_concurrent_tasks: 4,
+            ..Default::default()
+        });
+        assert_eq!(data.cfg().max_concurrent_tasks, 4);
+        assert_eq!(data.cfg().cache_folder, snapshot.cache_folder);
+        assert_eq!(snapshot.max_concurrent_tasks, 1);
+        assert!(!connection.is_cancelled());
+
+        data.reload_cfg(ClientConfig {
+            host: "judge2.example.com".into(),
+            ..Default::default()
+        });
+        assert!(connection.is_cancelled());
+    }
+
+    #[test]
+    fn test_protocol_negotiation() {
+        use crate::client::model::BUILD_EVENT_PROTOCOL_VERSION;
+
+        let data = SharedClientData::new(ClientConfig::default()).unwrap();
+        assert!(!data.negotiated_protocol(BUILD_EVENT_PROTOCOL_VERSION));
+        data.protocol_version.store(
+            BUILD_EVENT_PROTOCOL_VERSION,
+            std::sync::atomic::Ordering::SeqCst,
+        );
+        assert!(data.negotiated_protocol(BUILD_EVENT_PROTOCOL_VERSION));
+
+        assert!(data.accepts_new_jobs());
+        data.protocol_incompatible
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        assert!(!data.accepts_new_jobs());
+    }
+
+    #[test]
+    fn test_fallback_hosts() {
+        let data = SharedClientData::new(ClientConfig {
+            host: "primary.example.com".into(),
+            fallback_hosts: vec!["standby.example.com".into()],
+            access_token: Some("primary-token".into()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            data.cfg().hosts().collect::<Vec<_>>(),
+            ["primary.example.com", "standby.example.com"]
+        );
+        assert_eq!(data.host(), "primary.example.com");
+
+        data.active_host
+            .store(1, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(
+            data.verify_endpoint(),
+            "http://standby.example.com/api/v1/judger/verify"
+        );
+        // Standbys sharing the database of the primary accept its token
+        assert_eq!(data.access_token().as_deref(), Some("primary-token"));
+        data.set_access_token("standby-token".into());
+        assert_eq!(data.access_token().as_deref(), Some("standby-token"));
+        assert_eq!(data.cfg().access_token.as_deref(), Some("primary-token"));
+
+        data.active_host
+            .store(0, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(data.access_token().as_deref(), Some("primary-token"));
+        assert!(data.websocket_endpoint().contains("token=primary-token"));
+    }
+}
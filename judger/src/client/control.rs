@@ -0,0 +1,221 @@
+//! A Unix socket to control a running judger locally, used by `rurikawa ctl`.
+//!
+//! Every connection carries one request and one response, each as a line of
+//! JSON.
+
+use super::{
+    abort_job,
+    config::SharedClientData,
+    model::{AbortJob, JobStage},
+};
+use crate::prelude::FlowSnake;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+/// A request sent to the control socket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "camelCase")]
+pub enum ControlRequest {
+    /// Report the state of this judger and its running jobs.
+    Status,
+    /// Cancel a running job.
+    #[serde(rename_all = "camelCase")]
+    Cancel { job_id: FlowSnake },
+    /// Stop requesting new jobs, and exit once running jobs finish.
+    Drain,
+    /// Stop requesting new jobs.
+    Pause,
+    /// Request new jobs again after [`ControlRequest::Pause`].
+    Resume,
+    /// Read the config file again.
+    Reload,
+}
+
+/// A response from the control socket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "camelCase")]
+pub enum ControlResponse {
+    Done,
+    Status(JudgerStatus),
+    Error { message: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JudgerStatus {
+    pub polling_paused: bool,
+    pub draining: bool,
+    pub max_concurrent_tasks: usize,
+    pub jobs: Vec<RunningJobStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunningJobStatus {
+    pub id: FlowSnake,
+    pub stage: JobStage,
+    /// Seconds since the job was received.
+    pub elapsed: f64,
+}
+
+/// Reads the config file again and applies it. Provided by the binary, which
+/// knows where the config comes from.
+pub type ReloadConfig = Arc<dyn Fn() -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync>;
+
+/// Serve the control socket at `path`, until the judger aborts.
+pub async fn serve_control(
+    path: PathBuf,
+    cfg: Arc<SharedClientData>,
+    reload: ReloadConfig,
+) -> std::io::Result<()> {
+    // A socket file left by an earlier judger prevents binding
+    match tokio::fs::remove_file(&path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = UnixListener::bind(&path)?;
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).await?;
+    }
+    tracing::info!(?path, "Listening for control requests");
+
+    let abort = cfg.abort_handle.clone();
+    loop {
+        let (stream, _) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = abort.cancelled() => break,
+        };
+        let cfg = cfg.clone();
+        let reload = reload.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, cfg, reload).await {
+                tracing::warn!("Error when handling control request: {}", e);
+            }
+        });
+    }
+    let _ = tokio::fs::remove_file(&path).await;
+    Ok(())
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    cfg: Arc<SharedClientData>,
+    reload: ReloadConfig,
+) -> std::io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(read).read_line(&mut line).await?;
+    let res = match serde_json::from_str::<ControlRequest>(&line) {
+        Ok(req) => {
+            tracing::info!(?req, "Received control request");
+            handle_request(req, cfg, reload).await
+        }
+        Err(e) => ControlResponse::Error {
+            message: format!("Bad request: {}", e),
+        },
+    };
+    let mut res = serde_json::to_vec(&res)?;
+    res.push(b'\n');
+    write.write_all(&res).await?;
+    write.shutdown().await
+}
+
+async fn handle_request(
+    req: ControlRequest,
+    cfg: Arc<SharedClientData>,
+    reload: ReloadConfig,
+) -> ControlResponse {
+    match req {
+        ControlRequest::Status => ControlResponse::Status(status(&cfg)),
+        ControlRequest::Cancel { job_id } => {
+            if !cfg.running_job_handles.lock().await.contains_key(&job_id) {
+                return ControlResponse::Error {
+                    message: format!("Job {} is not running", job_id),
+                };
+            }
+            let job = AbortJob {
+                job_id,
+                as_cancel: true,
+            };
+            abort_job(job, cfg).await;
+            ControlResponse::Done
+        }
+        ControlRequest::Drain => {
+            if !cfg.draining.swap(true, Ordering::SeqCst) {
+                tracing::warn!("Draining. This judger exits once running jobs finish.");
+                tokio::spawn(exit_when_drained(cfg));
+            }
+            ControlResponse::Done
+        }
+        ControlRequest::Pause => {
+            cfg.polling_paused.store(true, Ordering::SeqCst);
+            ControlResponse::Done
+        }
+        ControlRequest::Resume => {
+            cfg.polling_paused.store(false, Ordering::SeqCst);
+            ControlResponse::Done
+        }
+        ControlRequest::Reload => match reload().await {
+            Ok(()) => ControlResponse::Done,
+            Err(e) => ControlResponse::Error {
+                message: format!("{:#}", e),
+            },
+        },
+    }
+}
+
+fn status(cfg: &SharedClientData) -> JudgerStatus {
+    let mut jobs = cfg
+        .running_job_info
+        .iter()
+        .map(|entry| RunningJobStatus {
+            id: *entry.key(),
+            stage: entry.stage,
+            elapsed: entry.received_at.elapsed().as_secs_f64(),
+        })
+        .collect::<Vec<_>>();
+    jobs.sort_by_key(|job| job.id.0);
+    JudgerStatus {
+        polling_paused: cfg.polling_paused.load(Ordering::SeqCst),
+        draining: cfg.draining.load(Ordering::SeqCst),
+        max_concurrent_tasks: cfg.cfg().max_concurrent_tasks,
+        jobs,
+    }
+}
+
+/// Exit once no job is running, and no request for new jobs is waiting for a
+/// reply, since jobs sent in reply would be lost.
+async fn exit_when_drained(cfg: Arc<SharedClientData>) {
+    while !cfg.running_job_handles.lock().await.is_empty() || cfg.waiting_for_jobs.load().is_some()
+    {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    tracing::warn!("All jobs finished. Exiting.");
+    cfg.abort_handle.cancel();
+}
+
+/// Send `req` to the control socket at `path` and wait for its response.
+pub async fn send_control_request(
+    path: &Path,
+    req: &ControlRequest,
+) -> anyhow::Result<ControlResponse> {
+    let stream = UnixStream::connect(path).await?;
+    let (read, mut write) = stream.into_split();
+    let mut req = serde_json::to_vec(req)?;
+    req.push(b'\n');
+    write.write_all(&req).await?;
+
+    let mut line = String::new();
+    BufReader::new(read).read_line(&mut line).await?;
+    Ok(serde_json::from_str(&line)?)
+}
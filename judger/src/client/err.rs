@@ -1,5 +1,5 @@
 use err_derive::Error;
-use std::fmt::Debug;
+use std::{fmt::Debug, path::PathBuf};
 use tokio_tungstenite::tungstenite;
 
 #[derive(Debug, Error)]
@@ -95,3 +95,19 @@ pub enum ClientConnectionErr {
     #[error(display = "Bad register token")]
     BadRegisterToken,
 }
+
+#[derive(Debug, Error)]
+pub enum TlsConfigErr {
+    #[error(display = "Failed to read {:?}: {}", _0, _1)]
+    Io(PathBuf, #[error(source)] std::io::Error),
+    #[error(display = "No valid certificate in {:?}", _0)]
+    NoCertificate(PathBuf),
+    #[error(display = "No private key in {:?}", _0)]
+    NoPrivateKey(PathBuf),
+    #[error(display = "`client_cert` and `client_key` must be set together")]
+    IncompleteClientAuth,
+    #[error(display = "Bad pinned SPKI hash: {}", _0)]
+    BadPin(String),
+    #[error(display = "TLS error: {}", _0)]
+    Tls(#[error(source)] rustls::TLSError),
+}
@@ -0,0 +1,387 @@
+//! Metrics of the judger, served over HTTP in the Prometheus text format.
+
+use super::{
+    config::SharedClientData,
+    err::JobExecErr,
+    model::{JobResultKind, TestResultKind},
+};
+use crate::{fs::dir_size, tester::model::BuildError};
+use hyper::{service::service_fn, Body, Method, Request, Response, StatusCode};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Upper bounds of the buckets of stage durations, in seconds.
+const DURATION_BUCKETS: &[f64] = &[
+    1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0,
+];
+
+/// How often the size of the cache folder is measured.
+const CACHE_FOLDER_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// A stage of a job whose duration is measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum JobPhase {
+    /// Fetching the test suite and the submitted code.
+    Fetch,
+    /// Building images and starting containers.
+    Build,
+    /// Running test cases.
+    Run,
+}
+
+impl JobPhase {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JobPhase::Fetch => "fetch",
+            JobPhase::Build => "build",
+            JobPhase::Run => "run",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Cumulative counts of observations in each of [`DURATION_BUCKETS`].
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Counters and histograms updated while handling jobs. Gauges are read from
+/// [`SharedClientData`] when rendering instead.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    jobs_accepted: AtomicU64,
+    jobs_finished: Mutex<BTreeMap<String, u64>>,
+    jobs_failed: Mutex<BTreeMap<String, u64>>,
+    stage_durations: Mutex<BTreeMap<JobPhase, Histogram>>,
+    test_cases: Mutex<BTreeMap<String, u64>>,
+    job_polls: AtomicU64,
+    job_poll_timeouts: AtomicU64,
+    docker_errors: AtomicU64,
+    cache_folder_bytes: Mutex<Option<u64>>,
+}
+
+fn increment(counters: &Mutex<BTreeMap<String, u64>>, label: String) {
+    *counters.lock().unwrap().entry(label).or_default() += 1;
+}
+
+impl Metrics {
+    /// A job is accepted from the coordinator.
+    pub fn job_accepted(&self) {
+        self.jobs_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A job finishes with a result. Results other than `Accepted` also count
+    /// as failures.
+    pub fn job_finished(&self, result: JobResultKind) {
+        let label = format!("{:?}", result);
+        if !matches!(result, JobResultKind::Accepted) {
+            increment(&self.jobs_failed, label.clone());
+        }
+        increment(&self.jobs_finished, label);
+    }
+
+    /// A stage of a job completes in `duration`.
+    pub fn stage_completed(&self, phase: JobPhase, duration: Duration) {
+        self.stage_durations
+            .lock()
+            .unwrap()
+            .entry(phase)
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+
+    /// A test case is judged.
+    pub fn test_case_judged(&self, result: TestResultKind) {
+        increment(&self.test_cases, format!("{:?}", result));
+    }
+
+    /// Jobs are requested from the coordinator.
+    pub fn job_polled(&self) {
+        self.job_polls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The coordinator doesn't answer a job request in time.
+    pub fn job_poll_timed_out(&self) {
+        self.job_poll_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The cache folder is measured to take `size` bytes.
+    pub fn cache_folder_measured(&self, size: u64) {
+        *self.cache_folder_bytes.lock().unwrap() = Some(size);
+    }
+
+    /// A job fails because of `err`. Counts it if Docker is at fault.
+    pub fn job_errored(&self, err: &JobExecErr) {
+        if is_docker_error(err) {
+            self.docker_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Render the counters and histograms in the Prometheus text format.
+    fn render(&self, out: &mut String) {
+        let counter = |out: &mut String, name: &str, help: &str, value: u64| {
+            header(out, name, help, "counter");
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        let labeled = |out: &mut String,
+                       name: &str,
+                       help: &str,
+                       label: &str,
+                       values: &Mutex<BTreeMap<String, u64>>| {
+            header(out, name, help, "counter");
+            for (value, count) in values.lock().unwrap().iter() {
+                let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, value, count);
+            }
+        };
+
+        counter(
+            out,
+            "rurikawa_jobs_accepted_total",
+            "Jobs accepted from the coordinator.",
+            self.jobs_accepted.load(Ordering::Relaxed),
+        );
+        labeled(
+            out,
+            "rurikawa_jobs_finished_total",
+            "Jobs finished, by result.",
+            "result",
+            &self.jobs_finished,
+        );
+        labeled(
+            out,
+            "rurikawa_jobs_failed_total",
+            "Jobs finished with a result other than Accepted, by result.",
+            "result",
+            &self.jobs_failed,
+        );
+        labeled(
+            out,
+            "rurikawa_test_cases_total",
+            "Test cases judged, by result.",
+            "result",
+            &self.test_cases,
+        );
+        counter(
+            out,
+            "rurikawa_job_polls_total",
+            "Job requests sent to the coordinator.",
+            self.job_polls.load(Ordering::Relaxed),
+        );
+        counter(
+            out,
+            "rurikawa_job_poll_timeouts_total",
+            "Job requests that the coordinator didn't answer in time.",
+            self.job_poll_timeouts.load(Ordering::Relaxed),
+        );
+        counter(
+            out,
+            "rurikawa_docker_errors_total",
+            "Jobs failed because of errors from Docker.",
+            self.docker_errors.load(Ordering::Relaxed),
+        );
+
+        let name = "rurikawa_job_stage_duration_seconds";
+        header(out, name, "Durations of completed job stages.", "histogram");
+        for (phase, histogram) in self.stage_durations.lock().unwrap().iter() {
+            let stage = phase.as_str();
+            for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets) {
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{stage=\"{}\",le=\"{}\"}} {}",
+                    name, stage, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{{stage=\"{}\",le=\"+Inf\"}} {}",
+                name, stage, histogram.count
+            );
+            let _ = writeln!(out, "{}_sum{{stage=\"{}\"}} {}", name, stage, histogram.sum);
+            let _ = writeln!(
+                out,
+                "{}_count{{stage=\"{}\"}} {}",
+                name, stage, histogram.count
+            );
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, ty: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, ty);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Whether Docker is at fault for `err`.
+fn is_docker_error(err: &JobExecErr) -> bool {
+    let is_bollard = |e: &anyhow::Error| {
+        e.chain()
+            .any(|e| e.downcast_ref::<bollard::errors::Error>().is_some())
+    };
+    match err {
+        JobExecErr::Build(BuildError::ImagePullFailure(_)) => true,
+        JobExecErr::Build(BuildError::Internal(e)) | JobExecErr::Any(e) => is_bollard(e),
+        _ => false,
+    }
+}
+
+/// Render all metrics of this judger in the Prometheus text format.
+pub fn render_metrics(cfg: &SharedClientData) -> String {
+    let mut out = String::new();
+    cfg.metrics.render(&mut out);
+
+    gauge(
+        &mut out,
+        "rurikawa_running_jobs",
+        "Jobs being run.",
+        cfg.running_tests.load(Ordering::SeqCst),
+    );
+    gauge(
+        &mut out,
+        "rurikawa_max_concurrent_jobs",
+        "Jobs that may be run at the same time.",
+        cfg.cfg().max_concurrent_tasks,
+    );
+    gauge(
+        &mut out,
+        "rurikawa_waiting_for_jobs",
+        "Whether a job request is waiting for the coordinator's answer.",
+        cfg.waiting_for_jobs.load().is_some() as u8,
+    );
+    if let Some(size) = *cfg.metrics.cache_folder_bytes.lock().unwrap() {
+        gauge(
+            &mut out,
+            "rurikawa_cache_folder_bytes",
+            "Disk space used by the cache folder, measured every few minutes.",
+            size,
+        );
+    }
+    out
+}
+
+/// Measure the cache folder every `interval`, until the judger aborts. Walking
+/// the whole cache is too slow to do on every scrape.
+async fn refresh_cache_folder_size(cfg: Arc<SharedClientData>, interval: Duration) {
+    loop {
+        match dir_size(&cfg.cfg().cache_folder).await {
+            Ok(size) => cfg.metrics.cache_folder_measured(size),
+            Err(e) => tracing::warn!("Failed to measure the cache folder: {}", e),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = cfg.abort_handle.cancelled() => return,
+        }
+    }
+}
+
+/// Serve metrics at `/metrics` of `addr`, until the judger aborts.
+pub async fn serve_metrics(addr: SocketAddr, cfg: Arc<SharedClientData>) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tracing::info!(
+        "Serving metrics at http://{}/metrics",
+        listener.local_addr()?
+    );
+    tokio::spawn(refresh_cache_folder_size(
+        cfg.clone(),
+        CACHE_FOLDER_REFRESH_INTERVAL,
+    ));
+    let abort = cfg.abort_handle.clone();
+    loop {
+        let (stream, _) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = abort.cancelled() => return Ok(()),
+        };
+        let cfg = cfg.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| respond(req, cfg.clone()));
+            if let Err(e) = hyper::server::conn::Http::new()
+                .http1_only(true)
+                .serve_connection(stream, service)
+                .await
+            {
+                tracing::debug!("Metrics connection closed: {}", e);
+            }
+        });
+    }
+}
+
+async fn respond(
+    req: Request<Body>,
+    cfg: Arc<SharedClientData>,
+) -> Result<Response<Body>, std::convert::Infallible> {
+    let res = if req.method() == Method::GET && req.uri().path() == "/metrics" {
+        Response::builder()
+            .header("content-type", "text/plain; version=0.0.4")
+            .body(render_metrics(&cfg).into())
+    } else {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+    };
+    Ok(res.unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_metrics() {
+        let metrics = Metrics::default();
+        metrics.job_accepted();
+        metrics.job_accepted();
+        metrics.job_finished(JobResultKind::Accepted);
+        metrics.job_finished(JobResultKind::CompileError);
+        metrics.test_case_judged(TestResultKind::WrongAnswer);
+        metrics.stage_completed(JobPhase::Build, Duration::from_secs(20));
+        metrics.job_errored(&JobExecErr::Build(BuildError::ImagePullFailure(
+            "no such image".into(),
+        )));
+        metrics.job_errored(&JobExecErr::Cancelled);
+
+        let mut out = String::new();
+        metrics.render(&mut out);
+        for line in [
+            "# TYPE rurikawa_jobs_accepted_total counter",
+            "rurikawa_jobs_accepted_total 2",
+            "rurikawa_jobs_finished_total{result=\"Accepted\"} 1",
+            "rurikawa_jobs_finished_total{result=\"CompileError\"} 1",
+            "rurikawa_jobs_failed_total{result=\"CompileError\"} 1",
+            "rurikawa_test_cases_total{result=\"WrongAnswer\"} 1",
+            "rurikawa_docker_errors_total 1",
+            "rurikawa_job_stage_duration_seconds_bucket{stage=\"build\",le=\"10\"} 0",
+            "rurikawa_job_stage_duration_seconds_bucket{stage=\"build\",le=\"30\"} 1",
+            "rurikawa_job_stage_duration_seconds_bucket{stage=\"build\",le=\"+Inf\"} 1",
+            "rurikawa_job_stage_duration_seconds_sum{stage=\"build\"} 20",
+        ] {
+            assert!(out.lines().any(|l| l == line), "{} not in\n{}", line, out);
+        }
+        assert!(!out.contains("rurikawa_jobs_failed_total{result=\"Accepted\"}"));
+    }
+}
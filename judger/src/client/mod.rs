@@ -1,11 +1,17 @@
 pub mod config;
+#[cfg(unix)]
+pub mod control;
 mod err;
+pub mod metrics;
 pub mod model;
+pub mod resource;
 pub mod sink;
+pub mod tls;
 
 pub use self::err::*;
 use self::{
-    config::{ClientConfig, SharedClientData},
+    config::{RunningJobInfo, SharedClientData},
+    metrics::JobPhase,
     model::*,
     sink::*,
 };
@@ -13,29 +19,37 @@ use crate::{
     config::{JudgeToml, JudgerPublicConfig},
     fs::{self, JUDGE_FILE_NAME},
     prelude::*,
-    runner::{exec::CreateContainerConfigBuilder, volume::Volume},
+    runner::{
+        build_event::BuildEvent, exec::CreateContainerConfigBuilder, image::evict_build_cache,
+        network::Network, volume::Volume,
+    },
     tester::{
-        build_judger_container, build_user_code_container, model::Bind,
+        build_base_image, build_judger_container, build_user_code_container,
+        model::{Bind, NetworkMode},
         runner_plan::RawTestCaseResult,
+        start_service_container,
     },
-    util::AsyncTeardownCollector,
+    util::{logging::JobLogLayer, telemetry::set_remote_parent, AsyncTeardownCollector},
 };
 use anyhow::{Context, Result};
 use futures::prelude::*;
-use http::Method;
+use http::{Method, StatusCode};
 use ignore::gitignore::Gitignore;
 use itertools::Itertools;
 use respector::prelude::*;
+use serde::{Deserialize, Serialize};
 use serde_json::from_slice;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     path::{Path, PathBuf},
     sync::atomic::Ordering,
     sync::Arc,
 };
 use stream::StreamExt;
 use tokio::sync::OwnedMutexGuard;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio_tungstenite::{
+    client_async_tls_with_config, connect_async, tungstenite, tungstenite::Message, Connector,
+};
 use tracing::{info_span, instrument};
 use tracing_futures::Instrument;
 
@@ -43,16 +57,14 @@ use tracing_futures::Instrument;
 ///
 /// Returns `Ok(true)` if register was success, `Ok(false)` if register is not
 /// needed or not applicable.
-pub async fn try_register(
-    client_data: &mut SharedClientData,
-    refresh: bool,
-) -> anyhow::Result<bool> {
+pub async fn try_register(client_data: &SharedClientData, refresh: bool) -> anyhow::Result<bool> {
     tracing::info!(
-        "Registering judger. Access token: {:?}; Register token: {:?}",
-        client_data.cfg().access_token,
+        "Registering judger at {}. Access token: {:?}; Register token: {:?}",
+        client_data.host(),
+        client_data.access_token(),
         client_data.cfg().register_token
     );
-    if (!refresh && client_data.cfg().access_token.is_some())
+    if (!refresh && client_data.access_token().is_some())
         || client_data.cfg().register_token.is_none()
     {
         return Ok(false);
@@ -88,32 +100,82 @@ pub async fn try_register(
 
     tracing::info!("Got new access token: {}", res);
 
-    let new_cfg = ClientConfig {
-        access_token: Some(res),
-        ..(**client_data.cfg()).clone()
-    };
-    client_data.swap_cfg(Arc::new(new_cfg));
+    client_data.set_access_token(res);
 
     Ok(true)
 }
 
 /// Verify if the current registration is active.
+///
+/// Returns `Ok(false)` only if the coordinator rejects the access token, and
+/// an error if it can't tell, e.g. when it's restarting behind a proxy.
 pub async fn verify_self(cfg: &SharedClientData) -> anyhow::Result<bool> {
-    tracing::info!("Verifying access token {:?}", cfg.cfg().access_token);
-    if cfg.cfg().access_token.is_none() {
-        return Ok(false);
-    }
+    verify_at(cfg, cfg.active_host.load(Ordering::SeqCst)).await
+}
 
-    let endpoint = cfg.verify_endpoint();
-    let res = cfg
+/// Verify the registration at the coordinator at `idx` of
+/// [`config::ClientConfig::hosts`], like [`verify_self`].
+async fn verify_at(cfg: &SharedClientData, idx: usize) -> anyhow::Result<bool> {
+    let access_token = cfg.access_token_at(idx);
+    tracing::info!("Verifying access token {:?}", access_token);
+    let access_token = match access_token {
+        Some(token) => token,
+        None => return Ok(false),
+    };
+
+    let endpoint = cfg.verify_endpoint_at(&cfg.host_at(idx));
+    let status = cfg
         .client
         .request(Method::GET, &endpoint)
-        .header("authorization", cfg.cfg().access_token.as_ref().unwrap())
+        .header("authorization", access_token)
         .send()
         .await?
-        .status()
-        .is_success();
-    Ok(res)
+        .status();
+    match status {
+        status if status.is_success() => Ok(true),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Ok(false),
+        status => Err(anyhow::anyhow!(
+            "Verification failed with status {}",
+            status
+        )),
+    }
+}
+
+/// Make the first coordinator in [`config::ClientConfig::hosts`] that accepts
+/// this judger the active one, checking each with [`verify_self`]. The active
+/// coordinator is only changed once one is chosen.
+///
+/// Returns `Ok(false)` if some coordinator rejects this judger and none accepts
+/// it, and an error if none is available.
+pub async fn select_coordinator(cfg: &SharedClientData) -> anyhow::Result<bool> {
+    let previous = cfg.active_host.load(Ordering::SeqCst);
+    let hosts = cfg.cfg().hosts().count();
+    let mut rejected = false;
+    let mut last_err = None;
+    for idx in 0..hosts {
+        let host = cfg.host_at(idx);
+        match verify_at(cfg, idx).await {
+            Ok(true) => {
+                cfg.active_host.store(idx, Ordering::SeqCst);
+                if idx != previous {
+                    tracing::warn!("Switched to coordinator {}", host);
+                }
+                return Ok(true);
+            }
+            Ok(false) => {
+                tracing::warn!("Coordinator {} rejected this judger", host);
+                rejected = true;
+            }
+            Err(e) => {
+                tracing::warn!("Coordinator {} is unavailable: {}", host, e);
+                last_err = Some(e);
+            }
+        }
+    }
+    match last_err {
+        Some(e) if !rejected => Err(e),
+        _ => Ok(false),
+    }
 }
 
 pub async fn connect_to_coordinator(
@@ -122,7 +184,23 @@ pub async fn connect_to_coordinator(
     let endpoint = cfg.websocket_endpoint();
     let req = http::Request::builder().uri(&endpoint);
     tracing::info!("Connecting to {}", endpoint);
-    let (client, _) = connect_async(req.body(()).unwrap()).await?;
+    let req = req.body(()).unwrap();
+    let (client, _) = match &cfg.tls {
+        Some(tls) => {
+            let uri = req.uri();
+            let host = uri.host().unwrap_or_default();
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            let port = uri
+                .port_u16()
+                .unwrap_or(if cfg.cfg().ssl { 443 } else { 80 });
+            let stream = tokio::net::TcpStream::connect((host, port))
+                .await
+                .map_err(tungstenite::Error::Io)?;
+            let connector = Connector::Rustls(tls.0.clone());
+            client_async_tls_with_config(req, stream, None, Some(connector)).await?
+        }
+        None => connect_async(req).await?,
+    };
     let (cli_sink, cli_stream) = client.split();
     tracing::info!("Connection success");
     Ok((cli_sink, cli_stream))
@@ -144,6 +222,64 @@ async fn fetch_test_suite_data(
     Ok(res)
 }
 
+/// Files of a downloaded test suite, saved next to it.
+#[derive(Debug, Serialize, Deserialize)]
+struct SuiteManifest {
+    hashes: BTreeMap<String, String>,
+    stamps: BTreeMap<String, fs::FileStamp>,
+}
+
+impl SuiteManifest {
+    async fn of(suite_folder: &Path) -> std::io::Result<SuiteManifest> {
+        Ok(SuiteManifest {
+            hashes: fs::hash_dir(suite_folder).await?,
+            stamps: fs::stamp_dir(suite_folder).await?,
+        })
+    }
+
+    async fn save(&self, manifest: &Path) -> std::io::Result<()> {
+        tokio::fs::write(manifest, serde_json::to_vec(self)?).await
+    }
+}
+
+/// Check the files inside `suite_folder` against `manifest`. Files are only
+/// hashed if their sizes or modification times have changed. Returns `false`
+/// if the manifest is missing or any file was changed.
+async fn test_suite_intact(suite_folder: &Path, manifest: &Path) -> bool {
+    let expected = match tokio::fs::read(manifest).await {
+        Ok(m) => serde_json::from_slice::<SuiteManifest>(&m).ok(),
+        Err(_) => None,
+    };
+    let expected = match expected {
+        Some(e) => e,
+        None => return false,
+    };
+    match fs::stamp_dir(suite_folder).await {
+        Ok(stamps) if stamps == expected.stamps => return true,
+        Ok(_) => {}
+        Err(_) => return false,
+    }
+
+    tracing::debug!("Files of test suite were touched, checking their hashes");
+    match SuiteManifest::of(suite_folder).await {
+        Ok(actual) if actual.hashes == expected.hashes => {
+            // Spare the hashing next time
+            if let Err(e) = actual.save(manifest).await {
+                tracing::warn!("Failed to update test suite manifest: {}", e);
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+async fn remove_file_if_exists(path: &Path) -> std::io::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
 /// Check for updates on this test suite and update if necessary. Reads the test suite
 /// and returns.
 ///
@@ -207,8 +343,18 @@ pub async fn check_download_read_test_suite(
             .unwrap_or(false)
     };
 
+    // check if files of the test suite are intact
+    let manifest = cfg.test_suite_folder_manifest(suite_id);
+    let suite_intact = dir_exists && lockfile_up_to_date && {
+        let intact = test_suite_intact(&suite_folder, &manifest).await;
+        if !intact {
+            tracing::warn!("Test suite {} is damaged on disk", suite_id);
+        }
+        intact
+    };
+
     // download the test suite
-    if !dir_exists || !lockfile_up_to_date {
+    if !suite_intact {
         tracing::info!("Test suite not up to date. Updating...");
         let _modify_guard = cfg.before_suite_modify(suite_id).await;
         scopeguard::defer! {
@@ -219,6 +365,7 @@ pub async fn check_download_read_test_suite(
         let file_folder_root = cfg.temp_file_folder_root();
 
         fs::ensure_removed_dir(&suite_folder).await?;
+        remove_file_if_exists(&manifest).await?;
         tokio::fs::create_dir_all(file_folder_root).await?;
         tracing::info!(
             "Test suite does not exist. Initiating download of suite {} from {} to {}",
@@ -226,15 +373,25 @@ pub async fn check_download_read_test_suite(
             &endpoint,
             &suite_folder.display()
         );
-        fs::net::download_unzip(
+        let downloaded = fs::net::download_unzip(
             cfg.client.clone(),
             cfg.client
                 .get(&endpoint)
-                .header("authorization", cfg.cfg().access_token.as_ref().unwrap())
+                .header("authorization", cfg.access_token().unwrap())
                 .build()?,
             &suite_folder,
+            suite_data.package_hash.as_deref(),
         )
-        .await?;
+        .await;
+        if let Err(e) = downloaded {
+            let _ = fs::ensure_removed_dir(&suite_folder).await;
+            return Err(e.into());
+        }
+
+        SuiteManifest::of(&suite_folder)
+            .await?
+            .save(&manifest)
+            .await?;
         tracing::info!("Update completed");
     }
 
@@ -333,6 +490,7 @@ fn extract_job_err(job_id: FlowSnake, err: &JobExecErr) -> ClientMsg {
         results: HashMap::new(),
         job_result: err,
         message: Some(msg),
+        log_file_id: None,
     })
 }
 
@@ -347,14 +505,39 @@ pub async fn handle_job_wrapper(
     let teardown_collector = AsyncTeardownCollector::new();
 
     let cancel = cancel.child_token();
-    let timeout_fut = tokio::spawn({
-        let cancel = cancel.clone();
-        async move {
-            // TODO: hardcoded run time limit 30min
-            tokio::time::sleep(std::time::Duration::from_secs(30 * 60)).await;
-            cancel.cancel();
+
+    let job_log = cfg.cfg().job_log.clone();
+    let log_file = cfg.job_log_file(job_id);
+    let span = match tokio::fs::create_dir_all(cfg.job_log_folder_root()).await {
+        Ok(()) if job_log.enabled => {
+            tracing::info_span!("handle_job", %job_id, log_file = %log_file.display())
         }
-    });
+        Ok(()) => tracing::info_span!("handle_job", %job_id),
+        Err(e) => {
+            tracing::error!("Failed to create log folder for job {}: {}", job_id, e);
+            tracing::info_span!("handle_job", %job_id)
+        }
+    };
+    if let Some(e) = JobLogLayer::take_error(&span) {
+        tracing::error!("Failed to open log file for job {}: {}", job_id, e);
+    }
+
+    if let Some(trace_context) = &job.trace_context {
+        set_remote_parent(&span, trace_context);
+    }
+
+    let timeout_fut = tokio::spawn(
+        {
+            let cancel = cancel.clone();
+            async move {
+                // TODO: hardcoded run time limit 30min
+                tokio::time::sleep(std::time::Duration::from_secs(30 * 60)).await;
+                tracing::warn!("Job timed out, cancelling");
+                cancel.cancel();
+            }
+        }
+        .instrument(span.clone()),
+    );
 
     let res_handle = handle_job(
         job,
@@ -363,7 +546,7 @@ pub async fn handle_job_wrapper(
         cfg.clone(),
         &teardown_collector,
     )
-    .instrument(tracing::info_span!("handle_job", %job_id))
+    .instrument(span)
     .await;
 
     teardown_collector.teardown_all().await;
@@ -371,7 +554,10 @@ pub async fn handle_job_wrapper(
     // the loop after this might loop forever, so this call should be placed before it
     flag_finished_job(cfg.clone());
 
-    let msg = match res_handle {
+    if let Err(e) = &res_handle {
+        cfg.metrics.job_errored(e);
+    }
+    let mut msg = match res_handle {
         Ok(_res) => ClientMsg::JobResult(_res),
         // These two types need explicit handling, since they are not finished
         Err(JobExecErr::Aborted) => ClientMsg::JobProgress(JobProgressMsg {
@@ -394,12 +580,22 @@ pub async fn handle_job_wrapper(
         }),
         Err(e) => extract_job_err(job_id, &e),
     };
+    if let ClientMsg::JobResult(result) = &mut msg {
+        cfg.metrics.job_finished(result.job_result);
+        if job_log.enabled && job_log.upload {
+            let upload_info = ResultUploadConfig {
+                cfg: cfg.clone(),
+                job_id,
+            };
+            result.log_file_id = upload_job_log(&log_file, &upload_info).await;
+        }
+    }
 
     loop {
         // Ah yes, do-while pattern
         let mut req = cfg.client.post(&cfg.result_send_endpoint()).json(&msg);
-        if let Some(token) = &cfg.cfg().access_token {
-            req = req.header("authorization", token.as_str());
+        if let Some(token) = cfg.access_token() {
+            req = req.header("authorization", token);
         }
         let res = req
             .send()
@@ -423,11 +619,21 @@ pub async fn handle_job_wrapper(
     let _ = fs::ensure_removed_dir(&cfg.job_folder(job_id))
         .await
         .inspect_err(|e| tracing::error!("Failed to remove directory for job {}: {}", job_id, e));
+    let _ = fs::ensure_removed_dir(&cfg.job_temp_file_folder(job_id))
+        .await
+        .inspect_err(|e| tracing::error!("Failed to remove temp files for job {}: {}", job_id, e));
 
     {
         cfg.running_job_handles.lock().await.remove(&job_id);
+        cfg.running_job_info.remove(&job_id);
         timeout_fut.abort();
     }
+    if job_log.enabled {
+        let retention = std::time::Duration::from_secs(job_log.retention);
+        let _ = fs::remove_files_older_than(&cfg.job_log_folder_root(), retention)
+            .await
+            .inspect_err(|e| tracing::error!("Failed to remove old job logs: {}", e));
+    }
     tracing::info!("{}: cleanup complete", job_id);
 }
 
@@ -442,6 +648,9 @@ pub async fn handle_job(
     let docker =
         bollard::Docker::connect_with_local_defaults().expect("Unable to connect to docker");
 
+    // Config reloaded while running doesn't affect this job
+    let job_cfg = cfg.cfg_ref();
+
     // INFO: See locking pattern in [`crate::client::config::TestSuiteStatus`].
     let _job_guard = cfg.clone().before_job_start(job.test_suite);
 
@@ -461,11 +670,13 @@ pub async fn handle_job(
     tracing::debug!("REL suite_modify_permit");
     drop(might_modify_permit);
 
-    send.send_msg(&ClientMsg::JobProgress(JobProgressMsg {
+    cfg.set_job_stage(job.id, JobStage::Fetching);
+    send.send_job_msg(&ClientMsg::JobProgress(JobProgressMsg {
         job_id: job.id,
         stage: JobStage::Fetching,
     }))
-    .await?;
+    .await;
+    let mut stage_start = std::time::Instant::now();
 
     // Clone the repo specified in job
     let judge_cfg = pull_job(&cfg, &job, cancel.clone()).await?;
@@ -478,7 +689,8 @@ pub async fn handle_job(
         .ok_or_else(|| JobExecErr::NoSuchConfig(public_cfg.name.to_owned()))
         .context("parsing judger public config")?;
 
-    let image = judge_job_cfg.image.clone();
+    let mut image = judge_job_cfg.image.clone();
+    let ignored_build_args = image.retain_build_args(&public_cfg.allowed_build_args);
 
     // Check job paths to be relative & does not navigate into parent
     if let crate::tester::model::Image::Dockerfile { path, .. } = &image {
@@ -495,11 +707,28 @@ pub async fn handle_job(
 
     tracing::info!("Compiling job image & building container");
 
-    send.send_msg(&ClientMsg::JobProgress(JobProgressMsg {
+    cfg.metrics
+        .stage_completed(JobPhase::Fetch, stage_start.elapsed());
+    stage_start = std::time::Instant::now();
+    cfg.set_job_stage(job.id, JobStage::Compiling);
+    send.send_job_msg(&ClientMsg::JobProgress(JobProgressMsg {
         job_id: job.id,
         stage: JobStage::Compiling,
     }))
-    .await?;
+    .await;
+
+    if !ignored_build_args.is_empty() {
+        send.send_job_msg(&ClientMsg::JobOutput(JobOutputMsg {
+            job_id: job.id,
+            stream: Some(format!(
+                "Ignoring build arguments not allowed by the test suite: {}\n",
+                ignored_build_args.join(", ")
+            )),
+            error: None,
+            event: None,
+        }))
+        .await;
+    }
 
     tracing::debug!("Creating data volume");
     let data_volume_name = format!("rurikawa-judge-data-{}", &job.id);
@@ -509,6 +738,7 @@ pub async fn handle_job(
             &public_cfg,
             &cfg.test_suite_folder(job.test_suite),
             data_volume_name,
+            job_cfg.docker_config.data_volume_size_limit,
             Some(std::time::Duration::from_secs(600)), // hardcoded timeout 10min
         )
         .with_cancel(cancel.cancelled())
@@ -526,9 +756,26 @@ pub async fn handle_job(
         .chain([data_volume.as_mount(&public_cfg.mapped_dir.to, false)])
         .collect_vec();
 
+    // The private network of this job, shared by the judger container, the
+    // user container and service containers.
+    let job_network = if public_cfg.services.is_empty() && !public_cfg.network.use_job_network() {
+        None
+    } else {
+        tracing::debug!("Creating job network");
+        let network = Arc::new(
+            Network::create_internal(docker.clone(), format!("rurikawa-job-{}", job.id))
+                .await
+                .context("Error when creating job network")?,
+        );
+        teardown_collector.add(network.clone());
+        Some(network)
+    };
+    let job_network_name = job_network.as_ref().map(|n| n.name().to_owned());
+
     let test_suite_container_cfg = CreateContainerConfigBuilder::default()
         .cancellation(cancel.clone())
         .network_enabled(true)
+        .network(job_network_name.clone())
         .tag_name(format!("judger_container_{}", job.id))
         .mounts(mounts.clone())
         .build()
@@ -540,69 +787,133 @@ pub async fn handle_job(
         &public_cfg,
         &cfg.test_suite_folder(job.test_suite),
         &suite_unique_name,
+        &job_cfg.registry_credentials,
         test_suite_container_cfg,
     )
+    .instrument(info_span!("build", image = "judger"))
     .await?
     .map(Arc::new);
     if let Some(c) = judger_container.clone() {
         teardown_collector.add(c)
     }
 
+    if let Some(network) = &job_network {
+        for service in &public_cfg.services {
+            let container = start_service_container(
+                docker.clone(),
+                service,
+                &cfg.test_suite_folder(job.test_suite),
+                &suite_unique_name,
+                network.name(),
+                &job_cfg.registry_credentials,
+                cancel.clone(),
+            )
+            .await
+            .with_context(|| format!("Error when starting service {}", service.name))?;
+            teardown_collector.add(Arc::new(container));
+        }
+    }
+
+    let base_image_id = match &public_cfg.base_image {
+        Some(base_image) => {
+            let _permit = cfg.before_base_image_build(&base_image.tag).await;
+            let id = build_base_image(
+                docker.clone(),
+                base_image,
+                &cfg.test_suite_folder(job.test_suite),
+                &job_cfg.registry_credentials,
+                cancel.clone(),
+            )
+            .instrument(info_span!("build", image = "base"))
+            .await?;
+            Some(id)
+        }
+        None => None,
+    };
+
     tracing::info!("Building container");
 
-    let (build_ch_send, build_ch_recv) =
-        tokio::sync::mpsc::unbounded_channel::<bollard::models::BuildInfo>();
+    let (build_ch_send, build_ch_recv) = tokio::sync::mpsc::unbounded_channel::<BuildEvent>();
 
     let build_recv_handle = tokio::spawn({
         let mut recv = build_ch_recv;
         let ws_send = send.clone();
         let job_id = job.id;
+        let cfg = cfg.clone();
         async move {
-            while let Some(res) = recv.recv().await {
-                let _ = ws_send
-                    .send_msg(&ClientMsg::JobOutput(JobOutputMsg {
+            while let Some(event) = recv.recv().await {
+                let (stream, error) = event.legacy_output();
+                let event = cfg
+                    .negotiated_protocol(BUILD_EVENT_PROTOCOL_VERSION)
+                    .then_some(event);
+                ws_send
+                    .send_job_msg(&ClientMsg::JobOutput(JobOutputMsg {
                         job_id,
-                        stream: res.stream,
-                        error: res.error,
+                        stream,
+                        error,
+                        event,
                     }))
                     .await;
             }
         }
+        .in_current_span()
     });
 
+    let build_network = job_network_name
+        .clone()
+        .filter(|_| public_cfg.network.build == NetworkMode::Internal);
     let user_container = build_user_code_container(
-        docker,
+        docker.clone(),
         &job.id.to_string(),
         &image,
         |opt| {
             opt.base_path(cfg.job_folder(job.id))
                 .cancellation(cancel.clone())
                 .build_result_channel(build_ch_send)
-                .network_enabled(public_cfg.network.enable_build)
+                .event_limits(job_cfg.docker_config.build_event_limits)
+                .symlinks(job_cfg.docker_config.context_symlinks)
+                .network_enabled(public_cfg.network.build == NetworkMode::Full)
+                .network(build_network)
+                .memory_limit(job_cfg.docker_config.build_memory_limit)
+                .cache(job_cfg.docker_config.build_cache)
+                .cache_salt(base_image_id)
+                .registry_credentials(job_cfg.registry_credentials.clone())
         },
         |opt| {
             opt.mounts(mounts)
                 .cancellation(cancel.clone())
-                .network_enabled(public_cfg.network.enable_running)
+                .network_enabled(public_cfg.network.run == NetworkMode::Full)
+                .network(job_network_name)
+                .security(public_cfg.security.relax(&job_cfg.docker_config.security))
+                .disk_limit(job_cfg.docker_config.disk_limit(public_cfg.disk_limit))
                 .tag_name(format!("user_code_container_{}", job.id))
         },
     )
+    .instrument(info_span!("build", image = "user_code"))
     .await?;
     let user_container = Arc::new(user_container);
     teardown_collector.add(user_container.clone());
 
-    send.send_msg(&ClientMsg::JobProgress(JobProgressMsg {
+    if job_cfg.docker_config.build_cache {
+        let _ = evict_build_cache(&docker, job_cfg.docker_config.build_cache_size)
+            .await
+            .inspect_err(|e| tracing::warn!("Failed to evict build cache: {}", e));
+    }
+
+    cfg.metrics
+        .stage_completed(JobPhase::Build, stage_start.elapsed());
+    stage_start = std::time::Instant::now();
+    cfg.set_job_stage(job.id, JobStage::Running);
+    send.send_job_msg(&ClientMsg::JobProgress(JobProgressMsg {
         job_id: job.id,
         stage: JobStage::Running,
     }))
-    .await?;
+    .await;
 
     tracing::info!("started");
 
     let upload_info = Arc::new(ResultUploadConfig {
-        client: cfg.client.clone(),
-        endpoint: cfg.result_upload_endpoint(),
-        access_token: cfg.cfg().access_token.clone(),
+        cfg: cfg.clone(),
         job_id: job.id,
     });
 
@@ -614,6 +925,7 @@ pub async fn handle_job(
         let ws_send = send.clone();
         let job_id = job.id;
         let upload_info = upload_info.clone();
+        let cfg = cfg.clone();
         async move {
             let mut final_result = HashMap::new();
             while let Some(RawTestCaseResult(test_case, failure, output)) = recv.recv().await {
@@ -626,10 +938,11 @@ pub async fn handle_job(
                     &test_case,
                 )
                 .await;
+                cfg.metrics.test_case_judged(test_result.kind);
 
-                // Omit error; it doesn't matter
-                let _ = ws_send
-                    .send_msg(&ClientMsg::PartialResult(PartialResultMsg {
+                // Queued if disconnected, so the coordinator gets every result
+                ws_send
+                    .send_job_msg(&ClientMsg::PartialResult(PartialResultMsg {
                         job_id,
                         test_id: test_case.clone(),
                         test_result: test_result.clone(),
@@ -640,11 +953,19 @@ pub async fn handle_job(
             }
             final_result
         }
+        .in_current_span()
     });
 
     let sink = tokio_util::sync::PollSender::new(ch_send).sink_map_err(|_e| ());
     let sink = Box::pin(sink);
 
+    let run_option = crate::tester::runner_plan::make_run_options(
+        &public_cfg,
+        &job_cfg.docker_config,
+        cfg.job_temp_file_folder(job.id),
+        cancel.clone(),
+    );
+
     crate::tester::runner_plan::run_job_test_cases(
         &job,
         &public_cfg,
@@ -653,7 +974,7 @@ pub async fn handle_job(
         judger_container.map(|container| container as _),
         sink,
         &cfg.test_suite_folder(job.test_suite),
-        cancel.clone(),
+        &run_option,
     )
     .await?;
 
@@ -672,12 +993,15 @@ pub async fn handle_job(
         .map_err(|e| anyhow::anyhow!("The result handling task encountered an error").context(e))?;
 
     tracing::info!("finished");
+    cfg.metrics
+        .stage_completed(JobPhase::Run, stage_start.elapsed());
 
     let job_result = JobResultMsg {
         job_id: job.id,
         results: result,
         job_result: JobResultKind::Accepted,
         message: None,
+        log_file_id: None,
     };
     Ok(job_result)
 }
@@ -687,9 +1011,18 @@ async fn populate_data_volume(
     public_cfg: &JudgerPublicConfig,
     base_dir: &Path,
     data_volume_name: String,
+    size_limit: Option<u64>,
     timeout: Option<std::time::Duration>,
 ) -> Result<Volume, JobExecErr> {
-    let mut data_volume = Volume::create(docker.clone(), data_volume_name.clone())
+    let data_dir = base_dir.join(&public_cfg.mapped_dir.from);
+    let ignore = match &public_cfg.test_ignore {
+        Some(file) => crate::util::path_security::assert_child_path(file)
+            .and_then(|_| crate::util::tar::ignore_from_file(&data_dir, &base_dir.join(file)))
+            .context("When reading `testIgnore`")?,
+        None => Gitignore::empty(),
+    };
+
+    let mut data_volume = Volume::create(docker.clone(), data_volume_name.clone(), size_limit)
         .await
         .map_err(|e| {
             JobExecErr::Build(crate::tester::model::BuildError::Internal(
@@ -700,10 +1033,7 @@ async fn populate_data_volume(
     let timeout = timeout.unwrap_or(std::time::Duration::MAX);
     if let Err(e) = tokio::time::timeout(
         timeout,
-        data_volume.copy_local_files_into(
-            &base_dir.join(&public_cfg.mapped_dir.from),
-            Gitignore::empty(),
-        ),
+        data_volume.copy_local_files_into(&data_dir, ignore),
     )
     .await
     .context(format!(
@@ -727,19 +1057,20 @@ async fn pull_job(
 ) -> Result<JudgeToml, JobExecErr> {
     let job_path = cfg.job_folder(job.id);
     let _ = fs::ensure_removed_dir(&job_path).await;
-    fs::net::git_clone(
-        &job_path,
-        fs::net::GitCloneOptions {
-            repo: job.repo.clone(),
-            revision: job.revision.clone(),
-            depth: 3,
-        },
-    )
-    .with_cancel(cancel.cancelled())
-    .await
-    .ok_or(JobExecErr::Aborted)?
-    .map_err(JobExecErr::Git)
-    .context("cloning repo")?;
+    if let Some(archive) = &job.archive {
+        fetch_job_archive(cfg, job, archive, &job_path)
+            .with_cancel(cancel.cancelled())
+            .await
+            .ok_or(JobExecErr::Aborted)?
+            .context("fetching source archive")?;
+    } else {
+        clone_job_repo(cfg, job, &job_path)
+            .with_cancel(cancel.cancelled())
+            .await
+            .ok_or(JobExecErr::Aborted)?
+            .map_err(JobExecErr::Git)
+            .context("cloning repo")?;
+    }
 
     tracing::info!("fetched");
 
@@ -762,6 +1093,131 @@ async fn pull_job(
     Ok(judge_cfg)
 }
 
+/// Download and unpack the source archive of `job` into `job_path`.
+async fn fetch_job_archive(
+    cfg: &SharedClientData,
+    job: &Job,
+    archive: &SourceArchive,
+    job_path: &Path,
+) -> anyhow::Result<()> {
+    let client_cfg = cfg.cfg();
+    // Only send our access token to the coordinator itself
+    let req = if url::Url::parse(&archive.url).is_ok() {
+        cfg.client.get(&archive.url)
+    } else {
+        let req = cfg.client.get(cfg.coordinator_url(&archive.url));
+        match cfg.access_token() {
+            Some(token) => req.header("authorization", token),
+            None => req,
+        }
+    };
+    fs::net::download_source_archive(
+        cfg.client.clone(),
+        req.build()?,
+        archive.format,
+        job_path,
+        &cfg.job_temp_file_folder(job.id).join("source-archive"),
+        &client_cfg.archive_limits,
+    )
+    .await
+}
+
+/// Clone the repository of `job` into `job_path`, through the repository cache
+/// if it's enabled.
+async fn clone_job_repo(cfg: &SharedClientData, job: &Job, job_path: &Path) -> std::io::Result<()> {
+    let client_cfg = cfg.cfg();
+    let credential = job.credential.as_ref().or_else(|| {
+        fs::net::git_remote_host(&job.repo).and_then(|host| client_cfg.git_credentials.get(&host))
+    });
+    let auth = fs::net::GitAuth::prepare(credential, &cfg.job_temp_file_folder(job.id)).await?;
+    let res = clone_job_repo_with_auth(cfg, job, job_path, &auth).await;
+    auth.cleanup().await;
+    res
+}
+
+async fn clone_job_repo_with_auth(
+    cfg: &SharedClientData,
+    job: &Job,
+    job_path: &Path,
+    auth: &fs::net::GitAuth,
+) -> std::io::Result<()> {
+    let options = || fs::net::GitCloneOptions {
+        repo: job.repo.clone(),
+        revision: job.revision.clone(),
+        depth: 3,
+    };
+
+    let cache_size = cfg.cfg().repo_cache_size;
+    if cache_size == 0 {
+        return fs::net::git_clone(job_path, options(), auth).await;
+    }
+
+    let cache_key = SharedClientData::repo_cache_key(&job.repo);
+    let cache_path = cfg.repo_cache_folder(&cache_key);
+    {
+        let _cache_guard = cfg.before_repo_cache_modify(&cache_key).await;
+        scopeguard::defer! {
+            tracing::debug!(repo_cache=%cache_key, "REL repo_cache_modify_permit");
+        }
+
+        let res = fs::net::git_clone_cached(&cache_path, job_path, options(), auth).await;
+        if let Err(e) = res {
+            // Errors like a missing revision or a failed fetch are not the
+            // fault of the cache, and are returned as is.
+            if !fs::net::git_cache_is_corrupt(&cache_path).await {
+                return Err(e);
+            }
+            tracing::warn!(
+                ?cache_path,
+                "Cached repository is corrupt, starting over: {}",
+                e
+            );
+            fs::ensure_removed_dir(&cache_path).await?;
+            fs::ensure_removed_dir(job_path).await?;
+            fs::net::git_clone_cached(&cache_path, job_path, options(), auth).await?;
+        }
+    }
+
+    let _ = evict_repo_cache(cfg, cache_size)
+        .await
+        .inspect_err(|e| tracing::warn!("Failed to evict repository cache: {}", e));
+    Ok(())
+}
+
+/// Remove the least recently used repositories from the cache until its size
+/// is within `limit`. Repositories in use are skipped.
+async fn evict_repo_cache(cfg: &SharedClientData, limit: u64) -> std::io::Result<()> {
+    let mut entries = vec![];
+    let mut dir = tokio::fs::read_dir(cfg.repo_cache_folder_root()).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        if !entry.file_type().await?.is_dir() {
+            continue;
+        }
+        let path = entry.path();
+        let size = fs::dir_size(&path).await?;
+        let last_used = tokio::fs::metadata(path.join(fs::net::CACHE_LAST_USED_FILE))
+            .await
+            .and_then(|m| m.modified())
+            .unwrap_or(std::time::SystemTime::UNIX_EPOCH);
+        let key = entry.file_name().to_string_lossy().into_owned();
+        entries.push((last_used, size, key, path));
+    }
+
+    let mut total_size: u64 = entries.iter().map(|(_, size, _, _)| size).sum();
+    entries.sort_by_key(|(last_used, _, _, _)| *last_used);
+    for (_, size, key, path) in entries {
+        if total_size <= limit {
+            break;
+        }
+        if let Some(_guard) = cfg.try_before_repo_cache_modify(&key) {
+            tracing::info!(?path, %size, "Evicting cached repository");
+            fs::ensure_removed_dir(&path).await?;
+            total_size -= size;
+        }
+    }
+    Ok(())
+}
+
 async fn pull_public_cfg(
     job: &Job,
     cfg: &Arc<SharedClientData>,
@@ -788,6 +1244,14 @@ pub fn flag_finished_job(client_config: Arc<SharedClientData>) {
 pub async fn accept_job(job: Job, send: Arc<WsSink>, client_config: Arc<SharedClientData>) {
     tracing::info!("Received job {}", job.id);
     let job_id = job.id;
+    client_config.metrics.job_accepted();
+    client_config.running_job_info.insert(
+        job_id,
+        RunningJobInfo {
+            stage: JobStage::Dispatched,
+            received_at: std::time::Instant::now(),
+        },
+    );
     let cancel_handle = client_config.abort_handle.child_token();
     let cancel_token = cancel_handle.child_token();
 
@@ -800,6 +1264,7 @@ pub async fn accept_job(job: Job, send: Arc<WsSink>, client_config: Arc<SharedCl
             tokio::time::sleep(std::time::Duration::from_secs(30 * 60)).await;
             cancel_token.cancel();
         }
+        .instrument(tracing::info_span!("job_timeout", %job_id))
     });
 
     let handle = tokio::spawn(handle_job_wrapper(
@@ -849,14 +1314,29 @@ async fn cancel_job(
     client_config.cancelling_job_info.remove(&job_id);
 }
 
+/// Cancel or abort a running job in the background, as described in `job`.
+pub async fn abort_job(job: AbortJob, client_config: Arc<SharedClientData>) {
+    let job_id = job.job_id;
+    let (inserted_send, inserted_recv) = futures::channel::oneshot::channel();
+    let abort = tokio::spawn(
+        cancel_job(job, client_config.clone(), inserted_recv)
+            .instrument(tracing::info_span!("cancel_job", %job_id)),
+    );
+    client_config
+        .cancelling_job_handles
+        .lock()
+        .await
+        .insert(job_id, abort);
+    let _ = inserted_send.send(());
+}
+
 async fn keepalive(
-    client_config: Arc<SharedClientData>,
     keepalive_token: CancellationTokenHandle,
     ws: Arc<WsSink>,
     interval: std::time::Duration,
 ) {
     while tokio::time::sleep(interval)
-        .with_cancel(client_config.abort_handle.cancelled())
+        .with_cancel(keepalive_token.cancelled())
         .await
         .is_some()
     {
@@ -879,6 +1359,11 @@ async fn poll_jobs(
     retry_interval: std::time::Duration,
     poll_timeout: std::time::Duration,
 ) {
+    // Used to list local images in job requests
+    let docker = bollard::Docker::connect_with_local_defaults()
+        .inspect_err(|e| tracing::warn!("Unable to connect to docker: {}", e))
+        .ok();
+    let mut sent_images = None;
     'outer: loop {
         while client_config.waiting_for_jobs.load().is_some() {
             tracing::debug!("Loading current poll but it's Some(_)...");
@@ -891,6 +1376,18 @@ async fn poll_jobs(
             }
         }
 
+        if !client_config.accepts_new_jobs() {
+            tracing::debug!("Polling is paused");
+            if tokio::time::sleep(poll_interval)
+                .with_cancel(keepalive_token.cancelled())
+                .await
+                .is_none()
+            {
+                break 'outer;
+            }
+            continue;
+        }
+
         let message_id = FlowSnake::generate();
 
         let curr_poll = Arc::new(message_id);
@@ -908,17 +1405,29 @@ async fn poll_jobs(
                 request_for_new_task
             );
 
-            let msg = ClientMsg::JobRequest(JobRequestMsg {
+            let mut msg = JobRequestMsg {
                 active_task_count,
                 request_for_new_task,
                 message_id: Some(message_id),
-            });
+                resources: None,
+                tags: vec![],
+                cached_test_suites: vec![],
+                cached_images: None,
+            };
+            resource::probe_into(&mut msg, &client_config, docker.as_ref()).await;
+            // Images rarely change, so the coordinator keeps the last ones sent
+            if msg.cached_images == sent_images {
+                msg.cached_images = None;
+            } else {
+                sent_images = msg.cached_images.clone();
+            }
+            let msg = ClientMsg::JobRequest(msg);
             if let Some(Ok(_)) = ws
                 .send_msg(&msg)
                 .with_cancel(keepalive_token.cancelled())
                 .await
             {
-                // wtf here
+                client_config.metrics.job_polled();
             } else {
                 break 'outer;
             }
@@ -932,6 +1441,7 @@ async fn poll_jobs(
                     let old_val = client_config.waiting_for_jobs.compare_and_swap(guard, None);
 
                     if old_val.as_ref().map_or(false, |x| **x == message_id) {
+                        client_config.metrics.job_poll_timed_out();
                         tracing::warn!(
                         "Job polling timed out at {}s for poll message {}. Please check server!",
                         poll_timeout.as_secs_f32(),
@@ -958,6 +1468,23 @@ async fn poll_jobs(
     tracing::info!("Stopping current polling session");
 }
 
+/// Apply the protocol version that the coordinator accepts. Polling stops if
+/// this judger can't talk in it.
+fn accept_protocol_version(version: u32, cfg: &SharedClientData) {
+    if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        tracing::info!(version, "Coordinator accepted protocol version");
+        cfg.protocol_version.store(version, Ordering::SeqCst);
+    } else {
+        cfg.protocol_incompatible.store(true, Ordering::SeqCst);
+        tracing::error!(
+            "Coordinator accepted protocol version {}, but this judger supports {} to {}. Stopped polling for jobs; please update either of them.",
+            version,
+            MIN_PROTOCOL_VERSION,
+            PROTOCOL_VERSION
+        );
+    }
+}
+
 #[allow(clippy::if_same_then_else)]
 pub async fn client_loop(
     mut ws_recv: WsStream,
@@ -968,14 +1495,35 @@ pub async fn client_loop(
     let keepalive_cancel = keepalive_token.child_token();
 
     client_config.waiting_for_jobs.store(None);
+    client_config
+        .connection_handle
+        .store(Some(Arc::new(keepalive_token.clone())));
 
     let keepalive_handle = tokio::spawn(keepalive(
-        client_config.clone(),
         keepalive_token,
         ws_send.clone(),
         std::time::Duration::from_secs(20),
     ));
 
+    client_config.protocol_version.store(0, Ordering::SeqCst);
+    client_config
+        .protocol_incompatible
+        .store(false, Ordering::SeqCst);
+    if let Err(e) = ws_send
+        .send_msg(&ClientMsg::ClientHello(ClientHelloMsg::current()))
+        .await
+    {
+        tracing::error!("Failed to send hello message: {}", e);
+    }
+    // Messages of running jobs queued while disconnected
+    let queued = ws_send.queued_len();
+    if queued > 0 {
+        tracing::info!("Sending {} queued messages", queued);
+        if let Err(e) = ws_send.flush().await {
+            tracing::warn!("Failed to send queued messages: {}", e);
+        }
+    }
+
     let poll_jobs_handle = tokio::spawn(poll_jobs(
         client_config.clone(),
         keepalive_cancel.child_token(),
@@ -1019,6 +1567,10 @@ pub async fn client_loop(
                                     );
                                 }
                             };
+                            if proceed && client_config.draining.load(Ordering::SeqCst) {
+                                tracing::warn!("Received jobs while draining. Not proceeding.");
+                                proceed = false;
+                            }
 
                             if proceed {
                                 for job in msg.jobs {
@@ -1040,22 +1592,21 @@ pub async fn client_loop(
                                 });
                             }
                         }
-                        ServerMsg::AbortJob(job) => {
-                            let job_id = job.job_id;
-                            let (inserted_send, inserted_recv) =
-                                futures::channel::oneshot::channel();
-                            let abort =
-                                tokio::spawn(cancel_job(job, client_config.clone(), inserted_recv));
-                            client_config
-                                .cancelling_job_handles
-                                .lock()
-                                .await
-                                .insert(job_id, abort);
-                            let _ = inserted_send.send(());
-                        }
-                        ServerMsg::ServerHello => {
+                        ServerMsg::AbortJob(job) => abort_job(job, client_config.clone()).await,
+                        ServerMsg::ServerHello(ServerHelloMsg {
+                            protocol_version: None,
+                        }) => {
                             tracing::info!("Hi, server o/");
                         }
+                        ServerMsg::ServerHello(ServerHelloMsg {
+                            protocol_version: Some(version),
+                        }) => accept_protocol_version(version, &client_config),
+                        ServerMsg::Unknown => {
+                            let ty = serde_json::from_str::<serde_json::Value>(&payload)
+                                .ok()
+                                .and_then(|v| v["_t"].as_str().map(str::to_owned));
+                            tracing::info!(?ty, "Ignoring message of unknown type");
+                        }
                     }
                 }
             }
@@ -1068,6 +1619,7 @@ pub async fn client_loop(
     let _ = poll_jobs_handle.await;
 
     client_config.waiting_for_jobs.store(None);
+    client_config.connection_handle.store(None);
 
     tracing::warn!("Disconnected!");
     ws_send
@@ -1,11 +1,25 @@
+use super::config::SharedClientData;
 use crate::{
+    fs::net::{ArchiveFormat, GitCredential},
     prelude::FlowSnake,
-    runner::model::ProcessOutput,
+    runner::{build_event::BuildEvent, model::ProcessOutput},
     tester::model::{ExecErrorKind, JobFailure, SpjFailure},
 };
 use respector::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+/// Version of the protocol between judgers and the coordinator, sent in
+/// [`ClientHelloMsg`]. Increase it when messages change incompatibly.
+///
+/// - 2: [`JobOutputMsg::event`]
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// The protocol version since which [`JobOutputMsg::event`] is sent.
+pub const BUILD_EVENT_PROTOCOL_VERSION: u32 = 2;
+
+/// The oldest protocol version this judger can still talk in.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
 
 /// Message sent from server. See documentation on the server side.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -18,7 +32,27 @@ pub enum ServerMsg {
     #[serde(rename = "abort_job")]
     AbortJob(AbortJob),
     #[serde(rename = "server_hello")]
-    ServerHello,
+    ServerHello(ServerHelloMsg),
+    /// A message of a type this judger doesn't know, e.g. one added in a newer
+    /// coordinator.
+    #[serde(other)]
+    Unknown,
+}
+
+impl ServerMsg {
+    /// Types of all messages this judger handles, as in `_t`.
+    pub const TYPES: &'static [&'static str] = &["new_job_multi", "abort_job", "server_hello"];
+}
+
+/// Sent by the coordinator once connected, and again in reply to
+/// [`ClientHelloMsg`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerHelloMsg {
+    /// The protocol version the coordinator accepts for this connection.
+    /// Absent in the greeting, and from coordinators that don't negotiate.
+    #[serde(default)]
+    pub protocol_version: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -39,12 +73,36 @@ pub struct AbortJob {
 #[serde(rename_all = "camelCase")]
 pub struct Job {
     pub id: FlowSnake,
+    #[serde(default)]
     pub repo: String,
+    #[serde(default)]
     pub revision: String,
     pub test_suite: FlowSnake,
     pub tests: Vec<String>,
     pub stage: JobStage,
     pub results: HashMap<String, TestResult>,
+    /// Credential used to fetch `repo`. Overrides the ones configured in the
+    /// judger.
+    #[serde(default)]
+    pub credential: Option<GitCredential>,
+    /// An uploaded source archive to use instead of cloning `repo`.
+    #[serde(default)]
+    pub archive: Option<SourceArchive>,
+    /// The span of the coordinator that dispatched this job, in the W3C Trace
+    /// Context format (`traceparent` and `tracestate`).
+    #[serde(default)]
+    pub trace_context: Option<HashMap<String, String>>,
+}
+
+/// A source archive containing the code of a job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceArchive {
+    /// URL of the archive. A URL without scheme and host (e.g. `/api/v1/...`)
+    /// is resolved against the coordinator and fetched with the judger's
+    /// access token.
+    pub url: String,
+    pub format: ArchiveFormat,
 }
 
 /// Specification of a test suite, returned by the server.
@@ -61,6 +119,9 @@ pub struct TestSuite {
     pub description: String,
     pub tags: Option<Vec<String>>,
     pub package_file_id: String,
+    /// SHA-256 hash of the package file in hex, checked while downloading.
+    #[serde(default)]
+    pub package_hash: Option<String>,
 }
 
 /// Message sent from client
@@ -92,6 +153,10 @@ pub enum ClientMsg {
 
     #[serde(rename = "revert_job")]
     RevertJob(RevertJobMsg),
+
+    /// Tells the coordinator what this judger supports, right after connecting
+    #[serde(rename = "client_hello")]
+    ClientHello(ClientHelloMsg),
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -132,11 +197,11 @@ pub enum JobResultKind {
     OtherError,
 }
 
-#[derive(Debug)]
+/// Where to upload the result files of a job. The endpoint and the access
+/// token are resolved on each upload, since they change when the judger fails
+/// over to another coordinator or refreshes its token.
 pub struct ResultUploadConfig {
-    pub client: reqwest::Client,
-    pub endpoint: String,
-    pub access_token: Option<String>,
+    pub cfg: Arc<SharedClientData>,
     pub job_id: FlowSnake,
 }
 
@@ -199,6 +264,11 @@ pub async fn transform_and_upload_test_result(
                     Some("The user's program has exceeded its maximum execution time.".into()),
                     None,
                 ),
+                ExecErrorKind::DiskLimitExceeded => (
+                    TestResultKind::RuntimeError,
+                    Some("The user's program has run out of its disk space.".into()),
+                    None,
+                ),
             },
             JobFailure::InternalError(e) => (TestResultKind::OtherError, Some(e.to_string()), None),
             JobFailure::ShouldFail(_) => (
@@ -230,8 +300,30 @@ pub async fn upload_test_result(
     upload_info: Arc<ResultUploadConfig>,
     test_id: &str,
 ) -> Option<String> {
-    let mut post = upload_info.client.post(&upload_info.endpoint);
-    if let Some(hdr) = upload_info.access_token.as_ref() {
+    upload_file(&f, &upload_info, test_id).await
+}
+
+/// The test id that the log file of a job is uploaded as.
+pub const JOB_LOG_TEST_ID: &str = "_judger_log";
+
+/// Upload the log file of a job at `path`, see
+/// [`JobLogConfig`](super::config::JobLogConfig).
+pub async fn upload_job_log(path: &Path, upload_info: &ResultUploadConfig) -> Option<String> {
+    let log = tokio::fs::read_to_string(path)
+        .await
+        .inspect_err(|e| log::warn!("Failed to read log file {:?}:\n{:?}", path, e))
+        .ok()?;
+    upload_file(&JobLogFile { log }, upload_info, JOB_LOG_TEST_ID).await
+}
+
+async fn upload_file<T: Serialize>(
+    f: &T,
+    upload_info: &ResultUploadConfig,
+    test_id: &str,
+) -> Option<String> {
+    let cfg = &upload_info.cfg;
+    let mut post = cfg.client.post(cfg.result_upload_endpoint());
+    if let Some(hdr) = cfg.access_token() {
         post = post.header("authorization", hdr);
     }
     let post = post
@@ -239,7 +331,7 @@ pub async fn upload_test_result(
             ("jobId", upload_info.job_id.to_string().as_str()),
             ("testId", test_id),
         ])
-        .json(&f)
+        .json(f)
         .send()
         .await;
     let resp = post
@@ -279,6 +371,12 @@ pub struct JobOutputMsg {
     pub job_id: FlowSnake,
     pub stream: Option<String>,
     pub error: Option<String>,
+    /// The structured form of this output, if it comes from building an
+    /// image. `stream` and `error` still hold its plain-text form, which is
+    /// all the coordinator keeps for now. Only sent since
+    /// [`BUILD_EVENT_PROTOCOL_VERSION`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event: Option<BuildEvent>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -288,6 +386,43 @@ pub struct JobResultMsg {
     pub job_result: JobResultKind,
     pub results: HashMap<String, TestResult>,
     pub message: Option<String>,
+    /// Id of the uploaded log file of this job, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_file_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientHelloMsg {
+    /// Version of this judger.
+    pub version: String,
+    /// The newest protocol version this judger talks in.
+    pub protocol_version: u32,
+    /// The oldest protocol version this judger talks in.
+    pub min_protocol_version: u32,
+    /// Types of server messages this judger handles, see [`ServerMsg::TYPES`].
+    pub message_types: Vec<String>,
+    /// Features of test suites this judger supports.
+    pub features: Vec<String>,
+    /// Backends this judger runs test cases with.
+    pub runner_backends: Vec<String>,
+}
+
+impl ClientHelloMsg {
+    /// The hello message of this judger.
+    pub fn current() -> ClientHelloMsg {
+        ClientHelloMsg {
+            version: env!("CARGO_PKG_VERSION").into(),
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            message_types: ServerMsg::TYPES.iter().map(|&t| t.into()).collect(),
+            features: ["spj", "isolated_exec", "services"]
+                .iter()
+                .map(|&f| f.into())
+                .collect(),
+            runner_backends: vec!["docker".into()],
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -304,6 +439,32 @@ pub struct JobRequestMsg {
     pub active_task_count: u32,
     pub request_for_new_task: u32,
     pub message_id: Option<FlowSnake>,
+    /// Resources available for new jobs, if probed.
+    #[serde(default)]
+    pub resources: Option<FreeResources>,
+    /// Tags of this judger.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Test suites already downloaded by this judger.
+    #[serde(default)]
+    pub cached_test_suites: Vec<FlowSnake>,
+    /// Tags of images present on this judger, absent if they haven't changed
+    /// since the last request of this connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cached_images: Option<Vec<String>>,
+}
+
+/// Resources of a judger available for new jobs. Fields are absent if they
+/// can't be probed on its platform.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FreeResources {
+    /// Idle CPU cores, from the load average.
+    pub cpu: Option<f64>,
+    /// Available memory, in bytes.
+    pub memory: Option<u64>,
+    /// Free disk space of the cache folder, in bytes.
+    pub disk: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -320,6 +481,12 @@ pub struct JobOutputFile {
     pub message: Option<String>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobLogFile {
+    pub log: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct JudgerRegisterMessage {
@@ -327,3 +494,40 @@ pub struct JudgerRegisterMessage {
     pub alternate_name: Option<String>,
     pub tags: Option<Vec<String>>,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_server_msg_types() {
+        let msg = serde_json::from_str::<ServerMsg>(r#"{"_t":"server_hello"}"#).unwrap();
+        assert!(matches!(
+            msg,
+            ServerMsg::ServerHello(ServerHelloMsg {
+                protocol_version: None
+            })
+        ));
+        let msg = serde_json::from_str::<ServerMsg>(r#"{"_t":"server_hello","protocolVersion":1}"#)
+            .unwrap();
+        assert!(matches!(
+            msg,
+            ServerMsg::ServerHello(ServerHelloMsg {
+                protocol_version: Some(1)
+            })
+        ));
+        let msg = serde_json::from_str::<ServerMsg>(r#"{"_t":"new_thing","foo":[1,2]}"#).unwrap();
+        assert!(matches!(msg, ServerMsg::Unknown));
+    }
+
+    #[test]
+    fn test_client_hello() {
+        let msg = serde_json::to_value(ClientMsg::ClientHello(ClientHelloMsg::current())).unwrap();
+        assert_eq!(msg["_t"], "client_hello");
+        assert_eq!(msg["protocolVersion"], PROTOCOL_VERSION);
+        assert_eq!(
+            msg["messageTypes"],
+            serde_json::json!(["new_job_multi", "abort_job", "server_hello"])
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//! Probes of the resources and caches of this judger, sent in job requests so
+//! the coordinator can pick jobs that suit it.
+
+use super::{
+    config::SharedClientData,
+    model::{FreeResources, JobRequestMsg},
+};
+use crate::{prelude::FlowSnake, runner::image::BUILD_CACHE_REPO};
+use bollard::{image::ListImagesOptions, Docker};
+use std::path::Path;
+
+/// Suffix of the manifest written after a test suite is unpacked, see
+/// [`SharedClientData::test_suite_folder_manifest`].
+const MANIFEST_SUFFIX: &str = ".manifest.json";
+
+/// Fill the resources, tags and caches of this judger into `msg`. Local
+/// images are only listed if `docker` is present.
+pub async fn probe_into(msg: &mut JobRequestMsg, cfg: &SharedClientData, docker: Option<&Docker>) {
+    let client_cfg = cfg.cfg();
+    msg.resources = Some(probe_free_resources(&client_cfg.cache_folder).await);
+    msg.tags = client_cfg.tags.clone().unwrap_or_default();
+    msg.cached_test_suites = cached_test_suites(&cfg.test_suite_folder_root())
+        .await
+        .unwrap_or_else(|e| {
+            tracing::debug!("Failed to list cached test suites: {}", e);
+            vec![]
+        });
+    if let Some(docker) = docker {
+        msg.cached_images = Some(local_images(docker).await.unwrap_or_else(|e| {
+            tracing::debug!("Failed to list local images: {}", e);
+            vec![]
+        }));
+    }
+}
+
+/// Probe the CPU, memory and disk available to new jobs. Disk space is that
+/// of the file system holding `cache_folder`. Values that can't be probed on
+/// this platform are `None`.
+pub async fn probe_free_resources(cache_folder: &Path) -> FreeResources {
+    let cpu = match tokio::fs::read_to_string("/proc/loadavg").await {
+        Ok(loadavg) => std::thread::available_parallelism()
+            .ok()
+            .zip(parse_loadavg(&loadavg))
+            .map(|(cpus, load)| (cpus.get() as f64 - load).max(0.0)),
+        Err(_) => None,
+    };
+    let memory = match tokio::fs::read_to_string("/proc/meminfo").await {
+        Ok(meminfo) => parse_meminfo(&meminfo),
+        Err(_) => None,
+    };
+    let disk = free_disk_space(cache_folder.to_owned()).await;
+    FreeResources { cpu, memory, disk }
+}
+
+/// The load average of the last minute in the contents of `/proc/loadavg`.
+fn parse_loadavg(loadavg: &str) -> Option<f64> {
+    loadavg.split_whitespace().next()?.parse().ok()
+}
+
+/// Available memory in bytes in the contents of `/proc/meminfo`.
+fn parse_meminfo(meminfo: &str) -> Option<u64> {
+    let line = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(unix)]
+async fn free_disk_space(path: std::path::PathBuf) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    tokio::task::spawn_blocking(move || {
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+        let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+        // SAFETY: `path` is a valid C string, and `stat` is only read if the
+        // call succeeds and has filled it.
+        let stat = unsafe {
+            if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+                return None;
+            }
+            stat.assume_init()
+        };
+        Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+#[cfg(not(unix))]
+async fn free_disk_space(_path: std::path::PathBuf) -> Option<u64> {
+    None
+}
+
+/// Ids of test suites that are downloaded and unpacked inside `suite_root`.
+pub async fn cached_test_suites(suite_root: &Path) -> std::io::Result<Vec<FlowSnake>> {
+    let mut suites = vec![];
+    let mut dir = match tokio::fs::read_dir(suite_root).await {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(suites),
+        Err(e) => return Err(e),
+    };
+    while let Some(entry) = dir.next_entry().await? {
+        let name = entry.file_name();
+        let id = name
+            .to_str()
+            .and_then(|name| name.strip_suffix(MANIFEST_SUFFIX))
+            .and_then(|id| FlowSnake::parse(id).ok());
+        if let Some(id) = id {
+            suites.push(id);
+        }
+    }
+    suites.sort_unstable_by_key(|id| id.0);
+    Ok(suites)
+}
+
+/// Tags of images present locally, except build caches which jobs never refer
+/// to.
+pub async fn local_images(docker: &Docker) -> Result<Vec<String>, bollard::errors::Error> {
+    let images = docker
+        .list_images(Some(ListImagesOptions::<String> {
+            ..Default::default()
+        }))
+        .await?;
+    let mut tags = images
+        .into_iter()
+        .flat_map(|image| image.repo_tags)
+        .filter(|tag| is_reusable_image(tag))
+        .collect::<Vec<_>>();
+    tags.sort_unstable();
+    tags.dedup();
+    Ok(tags)
+}
+
+/// Whether the image tagged `tag` may be used by jobs.
+fn is_reusable_image(tag: &str) -> bool {
+    tag != "<none>:<none>"
+        && !tag
+            .strip_prefix(BUILD_CACHE_REPO)
+            .is_some_and(|rest| rest.starts_with(':'))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::util::TempDir;
+
+    #[test]
+    fn test_parse_proc() {
+        assert_eq!(parse_loadavg("0.52 0.58 0.59 2/1234 5678\n"), Some(0.52));
+        assert_eq!(parse_loadavg(""), None);
+        let meminfo = "MemTotal:       16318480 kB\nMemFree:         1201648 kB\nMemAvailable:    8754236 kB\n";
+        assert_eq!(parse_meminfo(meminfo), Some(8754236 * 1024));
+        assert_eq!(parse_meminfo("MemTotal: 1 kB\n"), None);
+    }
+
+    #[test]
+    fn test_is_reusable_image() {
+        assert!(is_reusable_image("python:3.9"));
+        assert!(is_reusable_image("rurikawa-build-cache-like:latest"));
+        assert!(!is_reusable_image("<none>:<none>"));
+        assert!(!is_reusable_image(&format!(
+            "{}:0123abcd",
+            BUILD_CACHE_REPO
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_cached_test_suites() {
+        let root = TempDir::new();
+        let folder = root.join("suites");
+        assert_eq!(cached_test_suites(&folder).await.unwrap(), vec![]);
+
+        tokio::fs::create_dir_all(&folder).await.unwrap();
+        let (a, b) = (FlowSnake::generate(), FlowSnake::generate());
+        for name in [
+            format!("{}{}", a, MANIFEST_SUFFIX),
+            format!("{}.lock", b),
+            format!("{}{}", b, MANIFEST_SUFFIX),
+            "junk.manifest.json".into(),
+        ] {
+            tokio::fs::write(folder.join(name), b"{}").await.unwrap();
+        }
+        tokio::fs::create_dir(folder.join(a.to_string()))
+            .await
+            .unwrap();
+        let mut expected = vec![a, b];
+        expected.sort_unstable_by_key(|id| id.0);
+        assert_eq!(cached_test_suites(&folder).await.unwrap(), expected);
+
+        let resources = probe_free_resources(&std::env::temp_dir()).await;
+        if cfg!(target_os = "linux") {
+            assert!(resources.memory.is_some());
+            assert!(resources.disk.is_some());
+        }
+    }
+}
@@ -1,6 +1,7 @@
 //! Data structures for handling websocket message sinks that preserve message when connection is not available.
 
-use crate::prelude::CancellationTokenHandle;
+use super::model::ClientMsg;
+use crate::prelude::{CancellationTokenHandle, FlowSnake};
 use anyhow::Result;
 use arc_swap::{ArcSwapAny, ArcSwapOption};
 use async_trait::async_trait;
@@ -9,7 +10,7 @@ use futures::{
     stream::{SplitSink, SplitStream},
 };
 use serde::Serialize;
-use std::{fmt::Debug, sync::Arc};
+use std::{collections::VecDeque, fmt::Debug, sync::Arc};
 use tokio::{net::TcpStream, sync::Mutex};
 use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
 use tungstenite::Message;
@@ -19,19 +20,152 @@ pub type WsSink = WebsocketSink;
 pub type RawWsSink = SplitSink<WsDuplex, Message>;
 pub type WsStream = SplitStream<WsDuplex>;
 
+/// Default size limit of the messages queued while disconnected, in bytes.
+pub const DEFAULT_QUEUE_LIMIT: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QueuedKind {
+    Progress,
+    Output,
+    Other,
+}
+
+#[derive(Debug)]
+struct QueuedMessage {
+    kind: QueuedKind,
+    job_id: Option<FlowSnake>,
+    payload: String,
+}
+
+/// Job messages waiting to be sent, oldest first.
+///
+/// A progress update replaces the queued one of the same job, since only the
+/// latest stage matters. Once the queue is larger than its limit, the oldest
+/// job outputs are dropped. Partial results and other messages are never
+/// dropped, so the queue may stay above its limit if it only holds those.
+#[derive(Debug)]
+struct MessageQueue {
+    messages: VecDeque<QueuedMessage>,
+    size: usize,
+    limit: usize,
+}
+
+impl MessageQueue {
+    fn new(limit: usize) -> MessageQueue {
+        MessageQueue {
+            messages: VecDeque::new(),
+            size: 0,
+            limit,
+        }
+    }
+
+    fn push(&mut self, msg: &ClientMsg) {
+        let (kind, job_id) = match msg {
+            ClientMsg::JobProgress(m) => (QueuedKind::Progress, Some(m.job_id)),
+            ClientMsg::JobOutput(m) => (QueuedKind::Output, Some(m.job_id)),
+            ClientMsg::PartialResult(m) => (QueuedKind::Other, Some(m.job_id)),
+            _ => (QueuedKind::Other, None),
+        };
+        if kind == QueuedKind::Progress {
+            self.remove_where(|m| m.kind == QueuedKind::Progress && m.job_id == job_id);
+        }
+        let payload = serde_json::to_string(msg).unwrap();
+        self.size += payload.len();
+        self.messages.push_back(QueuedMessage {
+            kind,
+            job_id,
+            payload,
+        });
+
+        let mut dropped = 0;
+        while self.size > self.limit
+            && self
+                .remove_where(|m| m.kind == QueuedKind::Output)
+                .is_some()
+        {
+            dropped += 1;
+        }
+        if dropped > 0 {
+            tracing::warn!("Message queue is full. Dropped {} job outputs", dropped);
+        }
+    }
+
+    /// Remove the oldest message matching `pred`.
+    fn remove_where(&mut self, pred: impl Fn(&QueuedMessage) -> bool) -> Option<QueuedMessage> {
+        let idx = self.messages.iter().position(pred)?;
+        self.take(idx)
+    }
+
+    fn take(&mut self, idx: usize) -> Option<QueuedMessage> {
+        let msg = self.messages.remove(idx)?;
+        self.size -= msg.payload.len();
+        Some(msg)
+    }
+
+    fn push_front(&mut self, msg: QueuedMessage) {
+        self.size += msg.payload.len();
+        self.messages.push_front(msg);
+    }
+}
+
 pub struct WebsocketSink {
     sink: ArcSwapOption<Mutex<RawWsSink>>,
     handle: ArcSwapAny<Arc<CancellationTokenHandle>>,
+    /// Job messages not yet sent, see [`WebsocketSink::send_job_msg`]
+    queue: std::sync::Mutex<MessageQueue>,
 }
 
 impl WebsocketSink {
     pub fn new() -> WebsocketSink {
+        Self::with_queue_limit(DEFAULT_QUEUE_LIMIT)
+    }
+
+    /// Create a sink queueing at most about `limit` bytes of job messages
+    /// while disconnected.
+    pub fn with_queue_limit(limit: usize) -> WebsocketSink {
         WebsocketSink {
             sink: arc_swap::ArcSwapOption::new(None),
             handle: ArcSwapAny::new(Arc::new(CancellationTokenHandle::new())),
+            queue: std::sync::Mutex::new(MessageQueue::new(limit)),
+        }
+    }
+
+    /// Send a message about a job without waiting for the connection. The
+    /// message is queued if it can't be sent now, and sent by a later call
+    /// to [`WebsocketSink::flush`].
+    pub async fn send_job_msg(&self, msg: &ClientMsg) {
+        self.queue.lock().unwrap().push(msg);
+        if let Err(e) = self.flush().await {
+            tracing::debug!("Queued message until reconnected: {}", e);
         }
     }
 
+    /// Send all queued job messages in order, if connected. Messages that
+    /// couldn't be sent stay in the queue.
+    pub async fn flush(&self) -> Result<(), tungstenite::Error> {
+        let sink = match self.sink.load_full() {
+            Some(sink) => sink,
+            None => return Ok(()),
+        };
+        // Holding the sink keeps other flushes from reordering messages
+        let mut sink = sink.lock().await;
+        loop {
+            let msg = match self.queue.lock().unwrap().take(0) {
+                Some(msg) => msg,
+                None => return Ok(()),
+            };
+            if let Err(e) = sink.send(Message::text(msg.payload.clone())).await {
+                self.queue.lock().unwrap().push_front(msg);
+                return Err(e);
+            }
+        }
+    }
+
+    /// Number of job messages waiting to be sent.
+    pub fn queued_len(&self) -> usize {
+        self.queue.lock().unwrap().messages.len()
+    }
+
     pub async fn send(&self, msg: Message) -> Result<(), tungstenite::Error> {
         self.send_conf(msg, false).await
     }
@@ -146,3 +280,124 @@ where
 //     type Error = tungstenite::Error;
 
 // }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::model::{
+        JobOutputMsg, JobProgressMsg, JobStage, PartialResultMsg, TestResult, TestResultKind,
+    };
+
+    fn progress(job_id: FlowSnake, stage: JobStage) -> ClientMsg {
+        ClientMsg::JobProgress(JobProgressMsg { job_id, stage })
+    }
+
+    fn output(job_id: FlowSnake, line: &str) -> ClientMsg {
+        ClientMsg::JobOutput(JobOutputMsg {
+            job_id,
+            stream: Some(line.into()),
+            error: None,
+            event: None,
+        })
+    }
+
+    fn partial_result(job_id: FlowSnake, test_id: &str) -> ClientMsg {
+        ClientMsg::PartialResult(PartialResultMsg {
+            job_id,
+            test_id: test_id.into(),
+            test_result: TestResult {
+                kind: TestResultKind::Accepted,
+                score: None,
+                result_file_id: None,
+            },
+        })
+    }
+
+    fn payloads(queue: &MessageQueue) -> Vec<serde_json::Value> {
+        queue
+            .messages
+            .iter()
+            .map(|m| serde_json::from_str(&m.payload).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_queue_merges_and_drops() {
+        let (a, b) = (FlowSnake::generate(), FlowSnake::generate());
+        let mut queue = MessageQueue::new(usize::MAX);
+        queue.push(&progress(a, JobStage::Fetching));
+        queue.push(&progress(b, JobStage::Fetching));
+        queue.push(&output(a, "building"));
+        queue.push(&progress(a, JobStage::Running));
+        queue.push(&partial_result(a, "1"));
+        let msgs = payloads(&queue);
+        assert_eq!(msgs.len(), 4);
+        assert_eq!(
+            msgs[0],
+            serde_json::to_value(progress(b, JobStage::Fetching)).unwrap()
+        );
+        assert_eq!(msgs[1]["stream"], "building");
+        assert_eq!(msgs[2]["stage"], "Running");
+        assert_eq!(msgs[3]["testId"], "1");
+
+        // Only outputs are dropped to fit in the limit
+        queue.limit = queue.size;
+        queue.push(&output(b, "more"));
+        queue.push(&partial_result(b, "2"));
+        let msgs = payloads(&queue);
+        assert_eq!(msgs.len(), 4);
+        assert!(msgs.iter().all(|m| m["stream"].is_null()));
+        assert_eq!(msgs[3]["testId"], "2");
+        assert!(queue.size > queue.limit);
+        assert_eq!(
+            queue.size,
+            queue
+                .messages
+                .iter()
+                .map(|m| m.payload.len())
+                .sum::<usize>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flush_after_reconnect() {
+        let job = FlowSnake::generate();
+        let sink = WebsocketSink::new();
+        sink.send_job_msg(&progress(job, JobStage::Running)).await;
+        sink.send_job_msg(&partial_result(job, "1")).await;
+        sink.send_job_msg(&partial_result(job, "2")).await;
+        assert_eq!(sink.queued_len(), 3);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let mut received = vec![];
+            while let Some(Ok(Message::Text(msg))) = ws.next().await {
+                received.push(serde_json::from_str::<serde_json::Value>(&msg).unwrap());
+                if received.len() == 4 {
+                    break;
+                }
+            }
+            received
+        });
+
+        let (client, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+        let (raw_sink, _stream) = client.split();
+        sink.load_socket(raw_sink);
+        sink.flush().await.unwrap();
+        assert_eq!(sink.queued_len(), 0);
+        sink.send_job_msg(&partial_result(job, "3")).await;
+
+        let received = server.await.unwrap();
+        assert_eq!(received[0]["stage"], "Running");
+        let tests = received[1..]
+            .iter()
+            .map(|m| m["testId"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(tests, ["1", "2", "3"]);
+    }
+}
@@ -0,0 +1,187 @@
+//! TLS of connections to the coordinator, shared by the HTTP and websocket
+//! clients: custom CA bundles, client certificates and SPKI pinning.
+
+use super::{config::TlsConfig, err::TlsConfigErr};
+use rustls::{
+    internal::pemfile, Certificate, PrivateKey, RootCertStore, ServerCertVerified,
+    ServerCertVerifier, TLSError, WebPKIVerifier,
+};
+use sha2::{Digest, Sha256};
+use std::{io::BufReader, path::Path, sync::Arc};
+
+/// A TLS client config for connecting to the coordinator.
+#[derive(Clone)]
+pub struct ClientTls(pub Arc<rustls::ClientConfig>);
+
+impl std::fmt::Debug for ClientTls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientTls").finish_non_exhaustive()
+    }
+}
+
+impl ClientTls {
+    /// Build the TLS config described by `cfg`. Returns `None` if `cfg` is the
+    /// default, so that the clients keep their own defaults.
+    pub fn from_config(cfg: &TlsConfig) -> Result<Option<ClientTls>, TlsConfigErr> {
+        if *cfg == TlsConfig::default() {
+            return Ok(None);
+        }
+
+        let mut tls = rustls::ClientConfig::new();
+        match &cfg.ca_bundle {
+            Some(path) => {
+                let (valid, _) = tls
+                    .root_store
+                    .add_pem_file(&mut open(path)?)
+                    .map_err(|_| TlsConfigErr::NoCertificate(path.clone()))?;
+                if valid == 0 {
+                    return Err(TlsConfigErr::NoCertificate(path.clone()));
+                }
+            }
+            None => tls
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+        }
+
+        match (&cfg.client_cert, &cfg.client_key) {
+            (Some(cert), Some(key)) => tls
+                .set_single_client_cert(read_certs(cert)?, read_private_key(key)?)
+                .map_err(TlsConfigErr::Tls)?,
+            (None, None) => {}
+            _ => return Err(TlsConfigErr::IncompleteClientAuth),
+        }
+
+        if !cfg.pinned_spki.is_empty() {
+            let pins = cfg
+                .pinned_spki
+                .iter()
+                .map(|pin| parse_pin(pin))
+                .collect::<Result<_, _>>()?;
+            tls.dangerous()
+                .set_certificate_verifier(Arc::new(PinnedSpkiVerifier { pins }));
+        }
+
+        Ok(Some(ClientTls(Arc::new(tls))))
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<std::fs::File>, TlsConfigErr> {
+    std::fs::File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| TlsConfigErr::Io(path.to_owned(), e))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, TlsConfigErr> {
+    match pemfile::certs(&mut open(path)?) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(TlsConfigErr::NoCertificate(path.to_owned())),
+    }
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKey, TlsConfigErr> {
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut open(path)?).unwrap_or_default();
+    let rsa = pemfile::rsa_private_keys(&mut open(path)?).unwrap_or_default();
+    pkcs8
+        .into_iter()
+        .chain(rsa)
+        .next()
+        .ok_or_else(|| TlsConfigErr::NoPrivateKey(path.to_owned()))
+}
+
+fn parse_pin(pin: &str) -> Result<[u8; 32], TlsConfigErr> {
+    base64::decode(pin)
+        .ok()
+        .and_then(|hash| hash.try_into().ok())
+        .ok_or_else(|| TlsConfigErr::BadPin(pin.to_owned()))
+}
+
+/// Verifies the certificate chain as usual, then checks the SPKI of the
+/// server's certificate against the pinned hashes.
+struct PinnedSpkiVerifier {
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedSpkiVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: webpki::DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let verified = WebPKIVerifier::new().verify_server_cert(
+            roots,
+            presented_certs,
+            dns_name,
+            ocsp_response,
+        )?;
+        match presented_certs
+            .first()
+            .and_then(|cert| spki_sha256(&cert.0))
+        {
+            Some(hash) if self.pins.contains(&hash) => Ok(verified),
+            _ => Err(TLSError::General(
+                "Certificate of the coordinator matches no pinned SPKI hash".into(),
+            )),
+        }
+    }
+}
+
+/// SHA-256 hash of the DER-encoded SubjectPublicKeyInfo of a certificate,
+/// the same as `openssl x509 -pubkey | openssl pkey -pubin -outform der |
+/// openssl dgst -sha256`.
+pub fn spki_sha256(cert: &[u8]) -> Option<[u8; 32]> {
+    let spki = find_spki(cert)?;
+    Some(Sha256::digest(spki).into())
+}
+
+/// A DER element split off the front of some bytes.
+struct DerElement<'a> {
+    tag: u8,
+    /// The element with its tag and length
+    whole: &'a [u8],
+    contents: &'a [u8],
+    /// The bytes after this element
+    rest: &'a [u8],
+}
+
+fn der_split(der: &[u8]) -> Option<DerElement<'_>> {
+    let (&tag, rest) = der.split_first()?;
+    let (&len, rest) = rest.split_first()?;
+    let (len, rest) = if len < 0x80 {
+        (len as usize, rest)
+    } else {
+        let n = (len & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n].iter().fold(0, |acc, &b| acc << 8 | b as usize);
+        (len, &rest[n..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let header = der.len() - rest.len();
+    Some(DerElement {
+        tag,
+        whole: &der[..header + len],
+        contents: &rest[..len],
+        rest: &rest[len..],
+    })
+}
+
+/// The SubjectPublicKeyInfo element inside a DER-encoded X.509 certificate.
+fn find_spki(cert: &[u8]) -> Option<&[u8]> {
+    let cert = der_split(cert)?.contents;
+    let mut tbs = der_split(cert)?.contents;
+    // The version is optional, tagged [0]
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_split(tbs)?.rest;
+    }
+    // serialNumber, signature, issuer, validity and subject
+    for _ in 0..5 {
+        tbs = der_split(tbs)?.rest;
+    }
+    let spki = der_split(tbs)?;
+    (spki.tag == 0x30).then_some(spki.whole)
+}
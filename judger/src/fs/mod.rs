@@ -1,9 +1,16 @@
 //! File-system-related stuff. Including manipulating test folders, performing git operations and so on.
 
 use futures::{future::BoxFuture, prelude::*};
+use path_slash::PathExt;
 use respector::prelude::*;
-use std::path::{Path, PathBuf};
-use tokio::fs::read_dir;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::{fs::read_dir, io::AsyncReadExt};
 
 pub mod net;
 
@@ -39,6 +46,134 @@ pub fn ensure_removed_dir(path: &Path) -> BoxFuture<std::io::Result<()>> {
     .boxed()
 }
 
+/// Calculate the total size of all files inside a directory recursively.
+/// Symbolic links are not followed.
+pub fn dir_size(path: &Path) -> BoxFuture<'_, std::io::Result<u64>> {
+    async move {
+        let mut size = 0;
+        let mut dir = read_dir(path).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let metadata = tokio::fs::symlink_metadata(entry.path()).await?;
+            if metadata.is_dir() {
+                size += dir_size(&entry.path()).await?;
+            } else {
+                size += metadata.len();
+            }
+        }
+        Ok(size)
+    }
+    .boxed()
+}
+
+/// Remove files directly inside a directory that were last modified longer
+/// than `age` ago.
+pub async fn remove_files_older_than(path: &Path, age: std::time::Duration) -> std::io::Result<()> {
+    let mut dir = read_dir(path).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        let expired = metadata
+            .modified()?
+            .elapsed()
+            .is_ok_and(|elapsed| elapsed > age);
+        if metadata.is_file() && expired {
+            tokio::fs::remove_file(entry.path()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Size and modification time of a file, which tell cheaply whether it may
+/// have changed since last seen.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// Stamp all files inside a directory recursively, without reading them.
+/// Returns a map keyed like [`hash_dir`]. Symbolic links are not followed.
+pub async fn stamp_dir(path: &Path) -> std::io::Result<BTreeMap<String, FileStamp>> {
+    let mut stamps = BTreeMap::new();
+    stamp_dir_into(path, path, &mut stamps).await?;
+    Ok(stamps)
+}
+
+fn stamp_dir_into<'a>(
+    root: &'a Path,
+    path: &'a Path,
+    stamps: &'a mut BTreeMap<String, FileStamp>,
+) -> BoxFuture<'a, std::io::Result<()>> {
+    async move {
+        let mut dir = read_dir(path).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let entry_path = entry.path();
+            let metadata = tokio::fs::symlink_metadata(&entry_path).await?;
+            if metadata.is_dir() {
+                stamp_dir_into(root, &entry_path, stamps).await?;
+                continue;
+            }
+            let relative = entry_path.strip_prefix(root).unwrap_or(&entry_path);
+            stamps.insert(
+                relative.to_slash_lossy(),
+                FileStamp {
+                    size: metadata.len(),
+                    modified: metadata.modified().ok(),
+                },
+            );
+        }
+        Ok(())
+    }
+    .boxed()
+}
+
+/// Hash all files inside a directory recursively with SHA-256. Returns a map
+/// from slash-separated relative paths to hashes in lowercase hex. Symbolic
+/// links are not followed; their targets are hashed instead.
+pub async fn hash_dir(path: &Path) -> std::io::Result<BTreeMap<String, String>> {
+    let mut hashes = BTreeMap::new();
+    hash_dir_into(path, path, &mut hashes).await?;
+    Ok(hashes)
+}
+
+fn hash_dir_into<'a>(
+    root: &'a Path,
+    path: &'a Path,
+    hashes: &'a mut BTreeMap<String, String>,
+) -> BoxFuture<'a, std::io::Result<()>> {
+    async move {
+        let mut dir = read_dir(path).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let entry_path = entry.path();
+            let metadata = tokio::fs::symlink_metadata(&entry_path).await?;
+            if metadata.is_dir() {
+                hash_dir_into(root, &entry_path, hashes).await?;
+                continue;
+            }
+
+            let mut hasher = Sha256::new();
+            if metadata.file_type().is_symlink() {
+                let target = tokio::fs::read_link(&entry_path).await?;
+                hasher.update(b"symlink:");
+                hasher.update(target.to_string_lossy().as_bytes());
+            } else {
+                let mut file = tokio::fs::File::open(&entry_path).await?;
+                let mut buf = vec![0u8; 64 * 1024];
+                loop {
+                    let n = file.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    hasher.update(&buf[..n]);
+                }
+            }
+            let relative = entry_path.strip_prefix(root).unwrap_or(&entry_path);
+            hashes.insert(relative.to_slash_lossy(), hex::encode(hasher.finalize()));
+        }
+        Ok(())
+    }
+    .boxed()
+}
+
 pub fn find_judge_root(path: &Path) -> BoxFuture<std::io::Result<PathBuf>> {
     async move {
         let mut dir = tokio_stream::wrappers::ReadDirStream::new(read_dir(path).await?);
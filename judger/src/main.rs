@@ -1,14 +1,28 @@
 use clap::Parser;
 use dirs::home_dir;
+#[cfg(unix)]
+use futures::FutureExt;
 use once_cell::sync::OnceCell;
+#[cfg(unix)]
+use rurikawa_judger::{
+    client::control::{
+        send_control_request, serve_control, ControlRequest, ControlResponse, ReloadConfig,
+    },
+    prelude::FlowSnake,
+};
 use rurikawa_judger::{
     client::{
-        client_loop, config::*, connect_to_coordinator, sink::WsSink, try_register, verify_self,
+        client_loop, config::*, connect_to_coordinator, metrics::serve_metrics, select_coordinator,
+        sink::WsSink, try_register, verify_self,
     },
     prelude::CancellationTokenHandle,
+    util::{
+        logging::JobLogLayer,
+        telemetry::{otlp_tracer, shutdown_tracer},
+    },
 };
 use std::{
-    path::Path,
+    path::{Path, PathBuf},
     process::exit,
     sync::{
         atomic::{AtomicBool, Ordering},
@@ -16,7 +30,7 @@ use std::{
     },
     time::Duration,
 };
-use tracing_subscriber::{EnvFilter, FmtSubscriber};
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
 mod opt;
 
@@ -27,49 +41,83 @@ static ABORT_HANDLE: OnceCell<CancellationTokenHandle> = OnceCell::new();
 fn main() {
     let opt = opt::Opts::parse();
 
-    configure_logger(&opt);
-
-    ctrlc::set_handler(handle_ctrl_c).expect("Failed to set termination handler!");
-
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .expect("Failed to initialize runtime");
+    // The OTLP exporter spawns its task into the runtime
+    let _guard = rt.enter();
+
+    configure_logger(&opt);
+
+    ctrlc::set_handler(handle_ctrl_c).expect("Failed to set termination handler!");
+
+    let otlp_enabled = opt.opt.otlp_endpoint.is_some();
     rt.block_on(async_main(opt));
+
+    if otlp_enabled {
+        shutdown_tracer();
+    }
 }
 
 fn configure_logger(opt: &opt::Opts) {
-    let subscriber_builder = FmtSubscriber::builder();
-    let subscriber;
-    if let Some(level) = opt.opt.log_level {
-        subscriber = subscriber_builder
-            .with_env_filter(EnvFilter::new(level.to_string()))
-            .finish();
+    let filter = if let Some(level) = opt.opt.log_level {
+        EnvFilter::new(level.to_string())
     } else {
-        let filter = EnvFilter::try_from_default_env();
-        match filter {
-            Ok(filter) => subscriber = subscriber_builder.with_env_filter(filter).finish(),
+        match EnvFilter::try_from_default_env() {
+            Ok(filter) => filter,
             Err(e) => {
                 eprintln!(
                     "No valid loglevel specified. Resorting to RUST_LOG=info.\nError: {}",
                     e,
                 );
-                subscriber = subscriber_builder
-                    .with_env_filter(EnvFilter::new("info"))
-                    .finish();
+                EnvFilter::new("info")
             }
         }
-    }
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+    };
+    // Logs of each job are also written into its own file, see `JobLogConfig`
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(JobLogLayer);
+    let otlp = opt.opt.otlp_endpoint.as_ref().map(|endpoint| {
+        let tracer = otlp_tracer(endpoint).expect("Failed to set up the OTLP exporter");
+        tracing_opentelemetry::layer().with_tracer(tracer)
+    });
+    let registry = registry.with(otlp);
+    let res = match opt.opt.log_format {
+        opt::LogFormat::Text => {
+            tracing::subscriber::set_global_default(registry.with(fmt::layer()))
+        }
+        opt::LogFormat::Json => tracing::subscriber::set_global_default(
+            registry.with(
+                fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(true),
+            ),
+        ),
+    };
+    res.expect("setting default subscriber failed");
 }
 
 async fn async_main(opt: opt::Opts) {
     match opt.cmd {
         opt::SubCmd::Connect(cmd) => client(cmd).await,
         opt::SubCmd::Run(_) => {}
+        #[cfg(unix)]
+        opt::SubCmd::Ctl(cmd) => ctl(cmd).await,
     }
 }
 
+/// The folder holding the config and cache, `~/.rurikawa` if not specified.
+fn cache_folder(path: Option<PathBuf>) -> PathBuf {
+    path.unwrap_or_else(|| {
+        let mut dir = home_dir().expect("Failed to get home directory. Please provide a storage folder manually via `--temp-folder-path <path>`");
+        dir.push(".rurikawa");
+        dir
+    })
+}
+
 async fn read_client_config(source_path: &Path) -> std::io::Result<Option<ClientConfig>> {
     let mut config_path = source_path.to_owned();
     config_path.push("config.toml");
@@ -114,18 +162,19 @@ fn override_config_using_cmd(cmd: &opt::ConnectSubCmd, cfg: &mut ClientConfig) {
     if let Some(host) = cmd.host.clone() {
         cfg.host = host;
     }
+    if let Some(hosts) = cmd.fallback_host.clone() {
+        cfg.fallback_hosts = hosts;
+    }
     if let Some(tags) = cmd.tag.clone() {
         cfg.tags = Some(tags);
     }
+    if let Some(addr) = cmd.metrics_listen {
+        cfg.metrics_listen = Some(addr);
+    }
 }
 
 async fn client(cmd: opt::ConnectSubCmd) {
-    let cache_folder = cmd.temp_folder_path.clone().unwrap_or_else(|| {
-            let mut dir =
-                home_dir().expect("Failed to get home directory. Please provide a storage folder manually via `--temp-folder-path <path>`");
-            dir.push(".rurikawa");
-            dir
-        });
+    let cache_folder = cache_folder(cmd.temp_folder_path.clone());
 
     let mut cfg = read_client_config(&cache_folder)
         .await
@@ -135,9 +184,9 @@ async fn client(cmd: opt::ConnectSubCmd) {
     override_config_using_cmd(&cmd, &mut cfg);
     cfg.cache_folder = cache_folder.clone();
 
-    let mut cfg = SharedClientData::new(cfg);
+    let cfg = SharedClientData::new(cfg).expect("Error when loading TLS config");
 
-    let verify_res = verify_self(&cfg)
+    let verify_res = select_coordinator(&cfg)
         .await
         .expect("Error when verifying judger status");
 
@@ -145,7 +194,7 @@ async fn client(cmd: opt::ConnectSubCmd) {
     let refresh = !verify_res || cmd.refresh;
     if refresh {
         log::warn!("Verification failed. Registering.");
-        let register_res = try_register(&mut cfg, refresh)
+        let register_res = try_register(&cfg, refresh)
             .await
             .expect("Error when registering judger");
         if !register_res {
@@ -170,6 +219,35 @@ async fn client(cmd: opt::ConnectSubCmd) {
     let handle = client_config.abort_handle.clone();
     ABORT_HANDLE.set(handle).unwrap();
 
+    #[cfg(unix)]
+    {
+        let reload = config_reloader(cmd.clone(), cache_folder.clone(), client_config.clone());
+        let path = client_config.cfg().control_socket_path();
+        let client_config = client_config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_control(path.clone(), client_config, reload).await {
+                tracing::error!("Failed to serve control socket at {:?}: {}", path, e);
+            }
+        });
+    }
+
+    tokio::spawn(watch_config(
+        cmd.clone(),
+        cache_folder.clone(),
+        client_config.clone(),
+    ));
+
+    if let Some(addr) = client_config.cfg().metrics_listen {
+        tokio::spawn({
+            let client_config = client_config.clone();
+            async move {
+                if let Err(e) = serve_metrics(addr, client_config).await {
+                    tracing::error!("Failed to serve metrics at {}: {}", addr, e);
+                }
+            }
+        });
+    }
+
     const START_WAIT_TIME: Duration = Duration::from_millis(250);
     const MAX_WAIT_TIME: Duration = Duration::from_secs(256);
     let mut wait_time = START_WAIT_TIME;
@@ -178,7 +256,18 @@ async fn client(cmd: opt::ConnectSubCmd) {
 
     loop {
         client_sink.clear_socket();
-        let (sink, stream) = match connect_to_coordinator(&client_config).await {
+        // Running jobs keep sending into `client_sink`, and their messages
+        // go to whichever coordinator is connected next
+        // Registering again here would give this judger a new identity, so a
+        // rejected judger waits for a working token, e.g. from a config reload
+        let connected = match select_coordinator(&client_config).await {
+            Ok(true) => connect_to_coordinator(&client_config)
+                .await
+                .map_err(|e| e.to_string()),
+            Ok(false) => Err("every available coordinator rejected the access token".into()),
+            Err(e) => Err(e.to_string()),
+        };
+        let (sink, stream) = match connected {
             Ok(e) => e,
             Err(e) => {
                 // Exponential wait time
@@ -251,6 +340,153 @@ async fn client(cmd: opt::ConnectSubCmd) {
     tracing::warn!("All things cancelled");
 }
 
+/// Read the config file again, applying the same command line overrides.
+async fn reload_config(
+    cmd: &opt::ConnectSubCmd,
+    cache_folder: &Path,
+    client_config: &SharedClientData,
+) -> anyhow::Result<()> {
+    let mut cfg = read_client_config(cache_folder)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("No config file in {:?}", cache_folder))?;
+    override_config_using_cmd(cmd, &mut cfg);
+    // Access tokens aren't in the file if they were got from registering with
+    // `--no-save`
+    if cfg.access_token.is_none() {
+        cfg.access_token = client_config.cfg().access_token.clone();
+    }
+    for (host, token) in &client_config.cfg().fallback_access_tokens {
+        cfg.fallback_access_tokens
+            .entry(host.clone())
+            .or_insert_with(|| token.clone());
+    }
+    client_config.reload_cfg(cfg);
+    tracing::info!("Config reloaded");
+    Ok(())
+}
+
+/// Reload the config when the config file changes, or on SIGHUP.
+async fn watch_config(
+    cmd: opt::ConnectSubCmd,
+    cache_folder: PathBuf,
+    client_config: Arc<SharedClientData>,
+) {
+    const POLL_INTERVAL: Duration = Duration::from_secs(5);
+    let path = cache_folder.join("config.toml");
+    let modified = |path: PathBuf| async move {
+        tokio::fs::metadata(path)
+            .await
+            .and_then(|m| m.modified())
+            .ok()
+    };
+
+    #[cfg(unix)]
+    let mut hangup =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).unwrap();
+    let mut last_modified = modified(path.clone()).await;
+    loop {
+        #[cfg(unix)]
+        let hangup = hangup.recv();
+        #[cfg(not(unix))]
+        let hangup = futures::future::pending::<Option<()>>();
+        let signalled = tokio::select! {
+            _ = tokio::time::sleep(POLL_INTERVAL) => false,
+            _ = hangup => true,
+            _ = client_config.abort_handle.cancelled() => break,
+        };
+        let current = modified(path.clone()).await;
+        if !signalled && current == last_modified {
+            continue;
+        }
+        last_modified = current;
+        if let Err(e) = reload_config(&cmd, &cache_folder, &client_config).await {
+            tracing::error!("Failed to reload config: {:#}", e);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn config_reloader(
+    cmd: opt::ConnectSubCmd,
+    cache_folder: PathBuf,
+    client_config: Arc<SharedClientData>,
+) -> ReloadConfig {
+    Arc::new(move || {
+        let cmd = cmd.clone();
+        let cache_folder = cache_folder.clone();
+        let client_config = client_config.clone();
+        async move { reload_config(&cmd, &cache_folder, &client_config).await }.boxed()
+    })
+}
+
+#[cfg(unix)]
+async fn ctl(cmd: opt::CtlSubCmd) {
+    let socket = match cmd.socket {
+        Some(socket) => socket,
+        None => {
+            let mut cfg = read_client_config(&cache_folder(cmd.temp_folder_path.clone()))
+                .await
+                .expect("Failed to read config file")
+                .unwrap_or_default();
+            cfg.cache_folder = cache_folder(cmd.temp_folder_path);
+            cfg.control_socket_path()
+        }
+    };
+
+    let req = match cmd.cmd {
+        opt::CtlCmd::Status => ControlRequest::Status,
+        opt::CtlCmd::Cancel { job_id } => match FlowSnake::parse(&job_id) {
+            Ok(job_id) => ControlRequest::Cancel { job_id },
+            Err(e) => {
+                eprintln!("Invalid job id {}: {:?}", job_id, e);
+                exit(2);
+            }
+        },
+        opt::CtlCmd::Drain => ControlRequest::Drain,
+        opt::CtlCmd::Pause => ControlRequest::Pause,
+        opt::CtlCmd::Resume => ControlRequest::Resume,
+        opt::CtlCmd::Reload => ControlRequest::Reload,
+    };
+
+    match send_control_request(&socket, &req).await {
+        Ok(ControlResponse::Done) => println!("Done."),
+        Ok(ControlResponse::Status(status)) => {
+            let polling = if status.draining {
+                "draining"
+            } else if status.polling_paused {
+                "paused"
+            } else {
+                "active"
+            };
+            println!("Polling: {}", polling);
+            println!(
+                "Running jobs: {}/{}",
+                status.jobs.len(),
+                status.max_concurrent_tasks
+            );
+            if !status.jobs.is_empty() {
+                println!("{:<20} {:<12} {:>10}", "JOB", "STAGE", "ELAPSED");
+                for job in status.jobs {
+                    println!(
+                        "{:<20} {:<12} {:>9.0}s",
+                        job.id.to_string(),
+                        format!("{:?}", job.stage),
+                        job.elapsed
+                    );
+                }
+            }
+        }
+        Ok(ControlResponse::Error { message }) => {
+            eprintln!("Error: {}", message);
+            exit(1);
+        }
+        Err(e) => {
+            eprintln!("Failed to talk to the judger at {:?}: {}", socket, e);
+            exit(1);
+        }
+    }
+}
+
 fn handle_ctrl_c() {
     if !CTRL_C.load(Ordering::SeqCst) {
         log::warn!("Waiting for existing jobs to complete... Press Ctrl-C again to force quit.");
@@ -22,10 +22,40 @@ pub struct Opts {
 pub struct GlobalOpts {
     #[clap(long, short = 'l')]
     pub log_level: Option<tracing::level_filters::LevelFilter>,
+
+    /// Format of logs written to stdout, `text` or `json`
+    #[clap(long, default_value = "text", env = "RURIKAWA_LOG_FORMAT")]
+    pub log_format: LogFormat,
+
+    /// Send traces to the OTLP/HTTP collector at this URL, e.g.
+    /// `http://localhost:4318/v1/traces`
+    #[clap(long, env = "RURIKAWA_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
     // #[clap(long = "docker")]
     // pub docker_path: String,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "unknown log format `{}`, expected `text` or `json`",
+                s
+            )),
+        }
+    }
+}
+
 #[derive(Parser, Debug, Clone)]
 pub enum SubCmd {
     /// Run as a long-running runner instance (which is the only available way to run)
@@ -35,6 +65,11 @@ pub enum SubCmd {
     /// Run a single test job in local environment
     #[clap(name = "run")]
     Run(RunSubCmd),
+
+    /// Control a judger running on this machine
+    #[cfg(unix)]
+    #[clap(name = "ctl")]
+    Ctl(CtlSubCmd),
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -73,6 +108,11 @@ pub struct ConnectSubCmd {
     #[clap(long, env = "RURIKAWA_ALTERNATE_NAME")]
     pub name: Option<String>,
 
+    /// Supply or override standby coordinators, tried in order when the
+    /// primary one is unavailable
+    #[clap(long, env = "RURIKAWA_FALLBACK_HOST", use_delimiter = true)]
+    pub fallback_host: Option<Vec<String>>,
+
     /// Supply or override tags
     #[clap(long, short, env = "RURIKAWA_TAG", use_delimiter = true)]
     pub tag: Option<Vec<String>>,
@@ -85,6 +125,60 @@ pub struct ConnectSubCmd {
     /// Do not save updated data into config file.
     #[clap(long, env = "RURIKAWA_NO_SAVE")]
     pub no_save: bool,
+
+    /// Supply or override the address to serve Prometheus metrics at.
+    #[clap(long, env = "RURIKAWA_METRICS_LISTEN")]
+    pub metrics_listen: Option<std::net::SocketAddr>,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct CtlSubCmd {
+    /// Path of the control socket. Defaults to the one configured in the temp
+    /// folder.
+    #[clap(long, env = "RURIKAWA_CONTROL_SOCKET")]
+    pub socket: Option<PathBuf>,
+
+    /// Path of temp folder, defaults to ~/.rurikawa/
+    #[clap(
+        long = "temp-folder",
+        long = "path",
+        name = "path",
+        env = "RURIKAWA_TEMP_FOLDER_PATH"
+    )]
+    pub temp_folder_path: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    pub cmd: CtlCmd,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub enum CtlCmd {
+    /// Show running jobs, and whether new jobs are requested
+    #[clap(name = "status")]
+    Status,
+
+    /// Cancel a running job
+    #[clap(name = "cancel")]
+    Cancel {
+        /// The id of the job
+        job_id: String,
+    },
+
+    /// Stop requesting new jobs, and exit once running jobs finish
+    #[clap(name = "drain")]
+    Drain,
+
+    /// Stop requesting new jobs
+    #[clap(name = "pause")]
+    Pause,
+
+    /// Request new jobs again after pausing
+    #[clap(name = "resume")]
+    Resume,
+
+    /// Read the config file again
+    #[clap(name = "reload")]
+    Reload,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -0,0 +1,539 @@
+//! Structured progress of building images.
+//!
+//! Docker reports build progress as loosely structured text. This module turns
+//! it into [`BuildEvent`]s, so that the coordinator can tell which step of a
+//! Dockerfile is running or has failed.
+
+use std::{collections::VecDeque, time::Instant};
+
+use bollard::models::{BuildInfo, CreateImageInfo};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// An event that happens while building or pulling an image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum BuildEvent {
+    /// Progress of pulling an image, or one of its layers.
+    #[serde(rename_all = "camelCase")]
+    Pull {
+        /// The image being pulled, if known.
+        image: Option<String>,
+        /// The layer being pulled, if any.
+        layer: Option<String>,
+        status: String,
+        /// Bytes processed, if the status has a progress.
+        current: Option<i64>,
+        /// Total bytes, if the status has a progress.
+        total: Option<i64>,
+    },
+
+    /// A Dockerfile step starts, e.g. `Step 2/5 : RUN make`.
+    #[serde(rename_all = "camelCase")]
+    StepStart {
+        /// The number of this step, starting from 1.
+        step: u32,
+        total: u32,
+        instruction: String,
+    },
+
+    /// A line of output, from the current step if there's one.
+    #[serde(rename_all = "camelCase")]
+    Log { step: Option<u32>, line: String },
+
+    /// A Dockerfile step finishes.
+    #[serde(rename_all = "camelCase")]
+    StepDone {
+        step: u32,
+        /// The id of the image that the step results in.
+        image_id: String,
+    },
+
+    /// An earlier result is reused. `step` is the Dockerfile step that hits
+    /// Docker's layer cache, or `None` if the whole build is reused.
+    #[serde(rename_all = "camelCase")]
+    CacheHit { step: Option<u32> },
+
+    /// The build fails, during `step` if it's known.
+    #[serde(rename_all = "camelCase")]
+    Error { step: Option<u32>, message: String },
+
+    /// The build finishes.
+    #[serde(rename_all = "camelCase")]
+    Done { image_id: Option<String> },
+}
+
+impl BuildEvent {
+    /// The plain-text output of this event, as `(stream, error)` of a
+    /// [`JobOutputMsg`](crate::client::model::JobOutputMsg). This is what
+    /// coordinators that don't understand build events show.
+    pub fn legacy_output(&self) -> (Option<String>, Option<String>) {
+        match self {
+            BuildEvent::Pull { .. } | BuildEvent::Done { .. } => (None, None),
+            BuildEvent::StepStart {
+                step,
+                total,
+                instruction,
+            } => (
+                Some(format!("Step {}/{} : {}\n", step, total, instruction)),
+                None,
+            ),
+            BuildEvent::Log { line, .. } => (Some(format!("{}\n", line)), None),
+            BuildEvent::StepDone { image_id, .. } => (Some(format!(" ---> {}\n", image_id)), None),
+            BuildEvent::CacheHit { step: Some(_) } => (Some(" ---> Using cache\n".into()), None),
+            BuildEvent::CacheHit { step: None } => (
+                Some("Using an image built earlier from the same sources\n".into()),
+                None,
+            ),
+            BuildEvent::Error { message, .. } => (None, Some(message.clone())),
+        }
+    }
+
+    /// Whether this event may be dropped when events come too fast.
+    fn is_droppable(&self) -> bool {
+        matches!(self, BuildEvent::Pull { .. } | BuildEvent::Log { .. })
+    }
+
+    /// Cut the text of this event down to `max_size` bytes.
+    fn truncate(&mut self, max_size: usize) {
+        let text = match self {
+            BuildEvent::Pull { status: s, .. }
+            | BuildEvent::StepStart { instruction: s, .. }
+            | BuildEvent::Log { line: s, .. }
+            | BuildEvent::Error { message: s, .. } => s,
+            _ => return,
+        };
+        if text.len() > max_size {
+            let mut end = max_size;
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            text.truncate(end);
+            text.push_str("...");
+        }
+    }
+}
+
+/// Turns the progress of a pull into an event.
+pub fn pull_event(image: &str, info: CreateImageInfo) -> BuildEvent {
+    if let Some(message) = info.error {
+        return BuildEvent::Error {
+            step: None,
+            message,
+        };
+    }
+    let progress = info.progress_detail.unwrap_or_default();
+    BuildEvent::Pull {
+        image: Some(image.to_owned()),
+        layer: info.id,
+        status: info.status.unwrap_or_default(),
+        current: progress.current,
+        total: progress.total,
+    }
+}
+
+/// Turns the output of Docker's builder into events. Output may come in
+/// pieces that don't end at line breaks, so lines are only parsed after they
+/// are complete.
+#[derive(Debug, Default)]
+pub struct BuildOutputParser {
+    step: Option<u32>,
+    pending: String,
+    image_id: Option<String>,
+}
+
+impl BuildOutputParser {
+    /// Parse the next piece of output.
+    pub fn parse(&mut self, info: BuildInfo) -> Vec<BuildEvent> {
+        let mut events = vec![];
+        if let Some(stream) = info.stream {
+            self.pending.push_str(&stream);
+            while let Some(idx) = self.pending.find('\n') {
+                let line = self.pending[..idx].trim_end_matches('\r').to_owned();
+                self.pending.drain(..=idx);
+                events.extend(self.parse_line(line));
+            }
+        }
+        if let Some(status) = info.status {
+            let progress = info.progress_detail.unwrap_or_default();
+            events.push(BuildEvent::Pull {
+                image: None,
+                layer: info.id,
+                status,
+                current: progress.current,
+                total: progress.total,
+            });
+        }
+        if let Some(id) = info.aux.and_then(|aux| aux.id) {
+            self.image_id = Some(id);
+        }
+        if let Some(message) = info.error {
+            events.extend(self.finish());
+            events.push(BuildEvent::Error {
+                step: self.step,
+                message,
+            });
+        }
+        events
+    }
+
+    /// Parse the remaining incomplete line, if there's one.
+    pub fn finish(&mut self) -> Option<BuildEvent> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let line = std::mem::take(&mut self.pending);
+        self.parse_line(line)
+    }
+
+    /// The Dockerfile step being run, if any.
+    pub fn step(&self) -> Option<u32> {
+        self.step
+    }
+
+    /// The id of the built image, once Docker has reported it.
+    pub fn image_id(&self) -> Option<&str> {
+        self.image_id.as_deref()
+    }
+
+    fn parse_line(&mut self, line: String) -> Option<BuildEvent> {
+        if line.trim().is_empty() {
+            return None;
+        }
+        if let Some((step, total, instruction)) = parse_step_line(&line) {
+            self.step = Some(step);
+            return Some(BuildEvent::StepStart {
+                step,
+                total,
+                instruction: instruction.to_owned(),
+            });
+        }
+        if let (Some(step), Some(result)) = (self.step, line.strip_prefix(" ---> ")) {
+            let result = result.trim();
+            if result == "Using cache" {
+                return Some(BuildEvent::CacheHit { step: Some(step) });
+            }
+            if !result.is_empty() && result.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Some(BuildEvent::StepDone {
+                    step,
+                    image_id: result.to_owned(),
+                });
+            }
+        }
+        Some(BuildEvent::Log {
+            step: self.step,
+            line,
+        })
+    }
+}
+
+/// Parse a line like `Step 2/5 : RUN make` into `(2, 5, "RUN make")`.
+fn parse_step_line(line: &str) -> Option<(u32, u32, &str)> {
+    let (progress, instruction) = line.strip_prefix("Step ")?.split_once(" : ")?;
+    let (step, total) = progress.split_once('/')?;
+    Some((step.parse().ok()?, total.parse().ok()?, instruction))
+}
+
+/// Limits of build events sent to the coordinator.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct BuildEventLimits {
+    /// Maximum size of the text in a single event, in bytes. Longer text is
+    /// truncated.
+    pub max_event_size: usize,
+    /// Maximum number of log and pull events sent per second, averaged over
+    /// a second. Events above it are dropped, and the number of dropped events
+    /// is reported later. 0 means no limit.
+    pub max_events_per_second: u32,
+    /// Number of the last dropped log lines that are still sent before the
+    /// next step or error, since they usually tell why a step fails.
+    pub kept_lines: usize,
+}
+
+impl Default for BuildEventLimits {
+    fn default() -> Self {
+        BuildEventLimits {
+            max_event_size: 4096,
+            max_events_per_second: 50,
+            kept_lines: 20,
+        }
+    }
+}
+
+/// Applies [`BuildEventLimits`] to a stream of events. Events other than logs
+/// and pulls are never dropped, since they tell where the build is. The last
+/// dropped log lines are sent along with them.
+#[derive(Debug)]
+pub struct BuildEventLimiter {
+    limits: BuildEventLimits,
+    tokens: f64,
+    last_refill: Instant,
+    omitted: u64,
+    /// The last dropped log lines, which are not counted in `omitted`.
+    kept: VecDeque<BuildEvent>,
+}
+
+impl BuildEventLimiter {
+    pub fn new(limits: BuildEventLimits) -> Self {
+        BuildEventLimiter {
+            limits,
+            tokens: limits.max_events_per_second as f64,
+            last_refill: Instant::now(),
+            omitted: 0,
+            kept: VecDeque::new(),
+        }
+    }
+
+    /// Returns the events to send in place of `event`.
+    pub fn admit(&mut self, event: BuildEvent) -> Vec<BuildEvent> {
+        self.admit_at(event, Instant::now())
+    }
+
+    fn admit_at(&mut self, mut event: BuildEvent, now: Instant) -> Vec<BuildEvent> {
+        let rate = self.limits.max_events_per_second as f64;
+        if rate > 0.0 {
+            let elapsed = now.saturating_duration_since(self.last_refill);
+            self.tokens = (self.tokens + elapsed.as_secs_f64() * rate).min(rate);
+            self.last_refill = now;
+            if event.is_droppable() {
+                if self.tokens < 1.0 {
+                    self.drop_event(event);
+                    return vec![];
+                }
+                self.tokens -= 1.0;
+            }
+        }
+
+        event.truncate(self.limits.max_event_size);
+        let mut kept = std::mem::take(&mut self.kept);
+        if event.is_droppable() {
+            // Lines after the dropped ones are sent, so those are no longer
+            // the last lines
+            self.omitted += kept.len() as u64;
+            kept.clear();
+        }
+        let mut events = vec![];
+        if self.omitted > 0 {
+            let step = match &event {
+                BuildEvent::Log { step, .. } | BuildEvent::Error { step, .. } => *step,
+                BuildEvent::StepStart { step, .. } | BuildEvent::StepDone { step, .. } => {
+                    Some(*step)
+                }
+                _ => None,
+            };
+            events.push(BuildEvent::Log {
+                step,
+                line: format!("... {} messages omitted", std::mem::take(&mut self.omitted)),
+            });
+        }
+        events.extend(kept);
+        events.push(event);
+        events
+    }
+
+    fn drop_event(&mut self, mut event: BuildEvent) {
+        if !matches!(event, BuildEvent::Log { .. }) || self.limits.kept_lines == 0 {
+            self.omitted += 1;
+            return;
+        }
+        if self.kept.len() >= self.limits.kept_lines {
+            self.kept.pop_front();
+            self.omitted += 1;
+        }
+        event.truncate(self.limits.max_event_size);
+        self.kept.push_back(event);
+    }
+}
+
+/// Sends build events through a channel, if there's one, under some limits.
+#[derive(Debug)]
+pub(crate) struct BuildEventSink {
+    channel: Option<UnboundedSender<BuildEvent>>,
+    limiter: BuildEventLimiter,
+}
+
+impl BuildEventSink {
+    pub fn new(channel: Option<UnboundedSender<BuildEvent>>, limits: BuildEventLimits) -> Self {
+        BuildEventSink {
+            channel,
+            limiter: BuildEventLimiter::new(limits),
+        }
+    }
+
+    pub fn send(&mut self, event: BuildEvent) {
+        if let Some(channel) = &self.channel {
+            for event in self.limiter.admit(event) {
+                let _ = channel.send(event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bollard::models::ImageId;
+    use std::time::Duration;
+
+    fn stream(s: &str) -> BuildInfo {
+        BuildInfo {
+            stream: Some(s.into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_build_output() {
+        let mut parser = BuildOutputParser::default();
+        let mut events = vec![];
+        for info in [
+            stream("Step 1/3 : FROM gcc:11\n"),
+            stream(" ---> 0123456789ab\n"),
+            stream("Step 2/3 : COPY . /src\n ---> Using cache\n ---> ba9876543210\n"),
+            stream("Step 3/3 : RUN make\n"),
+            stream(" ---> Running in fedcba987654\n"),
+            stream("gcc -o main"),
+            stream(" main.c\r\n"),
+            stream("main.c:1: error"),
+        ] {
+            events.extend(parser.parse(info));
+        }
+        events.extend(parser.parse(BuildInfo {
+            error: Some("The command '/bin/sh -c make' returned a non-zero code: 2".into()),
+            ..Default::default()
+        }));
+
+        let log = |line: &str| BuildEvent::Log {
+            step: Some(3),
+            line: line.into(),
+        };
+        assert_eq!(
+            events,
+            vec![
+                BuildEvent::StepStart {
+                    step: 1,
+                    total: 3,
+                    instruction: "FROM gcc:11".into()
+                },
+                BuildEvent::StepDone {
+                    step: 1,
+                    image_id: "0123456789ab".into()
+                },
+                BuildEvent::StepStart {
+                    step: 2,
+                    total: 3,
+                    instruction: "COPY . /src".into()
+                },
+                BuildEvent::CacheHit { step: Some(2) },
+                BuildEvent::StepDone {
+                    step: 2,
+                    image_id: "ba9876543210".into()
+                },
+                BuildEvent::StepStart {
+                    step: 3,
+                    total: 3,
+                    instruction: "RUN make".into()
+                },
+                log(" ---> Running in fedcba987654"),
+                log("gcc -o main main.c"),
+                log("main.c:1: error"),
+                BuildEvent::Error {
+                    step: Some(3),
+                    message: "The command '/bin/sh -c make' returned a non-zero code: 2".into()
+                },
+            ]
+        );
+        assert_eq!(
+            events[0].legacy_output(),
+            (Some("Step 1/3 : FROM gcc:11\n".into()), None)
+        );
+
+        assert!(parser
+            .parse(BuildInfo {
+                aux: Some(ImageId {
+                    id: Some("sha256:abc".into())
+                }),
+                ..Default::default()
+            })
+            .is_empty());
+        assert_eq!(parser.image_id(), Some("sha256:abc"));
+    }
+
+    #[test]
+    fn test_build_event_limiter() {
+        let mut limiter = BuildEventLimiter::new(BuildEventLimits {
+            max_event_size: 8,
+            max_events_per_second: 2,
+            kept_lines: 0,
+        });
+        let start = limiter.last_refill;
+        let log = |line: &str| BuildEvent::Log {
+            step: Some(1),
+            line: line.into(),
+        };
+
+        assert_eq!(
+            limiter.admit_at(log("0123456789"), start),
+            vec![log("01234567...")]
+        );
+        assert_eq!(limiter.admit_at(log("a"), start), vec![log("a")]);
+        assert!(limiter.admit_at(log("b"), start).is_empty());
+        assert!(limiter.admit_at(log("c"), start).is_empty());
+
+        // Steps are never dropped, and they report the dropped messages
+        let done = BuildEvent::StepDone {
+            step: 1,
+            image_id: "0123456789ab".into(),
+        };
+        assert_eq!(
+            limiter.admit_at(done.clone(), start),
+            vec![log("... 2 messages omitted"), done]
+        );
+
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.admit_at(log("d"), later), vec![log("d")]);
+        assert!(limiter.admit_at(log("e"), later).is_empty());
+    }
+
+    #[test]
+    fn test_build_event_limiter_kept_lines() {
+        let mut limiter = BuildEventLimiter::new(BuildEventLimits {
+            max_event_size: 8,
+            max_events_per_second: 1,
+            kept_lines: 2,
+        });
+        let start = limiter.last_refill;
+        let log = |line: &str| BuildEvent::Log {
+            step: Some(1),
+            line: line.into(),
+        };
+
+        assert_eq!(limiter.admit_at(log("a"), start), vec![log("a")]);
+        for line in ["b", "c", "d", "error: 0123456789"] {
+            assert!(limiter.admit_at(log(line), start).is_empty());
+        }
+
+        // The last lines before an error are sent along with it
+        let error = BuildEvent::Error {
+            step: Some(1),
+            message: "failed".into(),
+        };
+        assert_eq!(
+            limiter.admit_at(error.clone(), start),
+            vec![
+                log("... 2 messages omitted"),
+                log("d"),
+                log("error: 0..."),
+                error
+            ]
+        );
+
+        // Lines followed by sent lines are only counted
+        assert!(limiter.admit_at(log("e"), start).is_empty());
+        let later = start + Duration::from_secs(1);
+        assert_eq!(
+            limiter.admit_at(log("f"), later),
+            vec![log("... 1 messages omitted"), log("f")]
+        );
+    }
+}
@@ -1,26 +1,46 @@
-use std::{fmt::Write, path::Path};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
 
 use async_trait::async_trait;
 use bollard::{
-    container::{Config, RemoveContainerOptions, StopContainerOptions, UploadToContainerOptions},
+    container::{
+        Config, InspectContainerOptions, NetworkingConfig, RemoveContainerOptions,
+        StopContainerOptions, UploadToContainerOptions,
+    },
     exec::{CreateExecOptions, StartExecOptions},
-    models::Mount,
+    models::{
+        EndpointSettings, HostConfig, Mount, MountTypeEnum, MountVolumeOptions,
+        MountVolumeOptionsDriverConfig, ResourcesUlimits,
+    },
+    network::ConnectNetworkOptions,
     Docker,
 };
 use bytes::BytesMut;
 use derive_builder::Builder;
 use futures::FutureExt;
 use ignore::gitignore::Gitignore;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+};
 use tokio_stream::StreamExt;
 
 use crate::{
-    prelude::CancellationTokenHandle,
+    prelude::{CancellationTokenHandle, FlowSnake},
     runner::model::ProcessOutput,
-    runner::{model::ExitStatus, util::is_recoverable_error},
-    util::tar::pack_as_tar,
+    runner::{
+        model::ExitStatus,
+        util::{is_recoverable_error, is_storage_quota_unsupported, tmpfs_volume_options},
+    },
+    util::tar::{pack_as_tar, SymlinkPolicy},
 };
 
-use super::model::{CommandRunOptions, CommandRunner};
+use super::model::{CommandRunOptions, CommandRunOptionsBuilder, CommandRunner};
 
 #[derive(Debug, Builder)]
 #[builder(setter(into), pattern = "owned")]
@@ -49,15 +69,354 @@ pub struct CreateContainerConfig {
     #[builder(default)]
     pub cpu_quota: Option<f64>,
 
-    /// Whether network is allowed in this container
+    /// Whether this container may access the outside world. See
+    /// [`CreateContainerConfig::apply_network`].
     #[builder(default = "false")]
     pub network_enabled: bool,
+
+    /// A network to join, e.g. the private network of a job. The container
+    /// joins it even if `network_enabled` is false.
+    #[builder(default)]
+    pub network: Option<String>,
+
+    /// Host names of this container inside `network`.
+    #[builder(default)]
+    pub network_aliases: Vec<String>,
+
+    /// Environment variables of this container, in addition to the image's.
+    #[builder(default)]
+    pub env: HashMap<String, String>,
+
+    /// Run the entrypoint of the image instead of an idle shell, e.g. for
+    /// service containers. Commands can still be executed inside.
+    #[builder(default)]
+    pub keep_entrypoint: bool,
+
+    /// Restrictions for untrusted code. Docker's defaults are used if absent.
+    #[builder(default)]
+    pub security: Option<SecurityProfile>,
+
+    /// Maximum disk space this container may write, in bytes. See
+    /// [`limit_disk_with_tmpfs`] for what happens if the storage driver can't
+    /// enforce it.
+    #[builder(default)]
+    pub disk_limit: Option<u64>,
+}
+
+/// Limit the writable space of a container to `limit` bytes without help from
+/// the storage driver. The root filesystem is made read-only, since writes into
+/// it can't be capped, and the container can only write into its tmpfs
+/// directories (`/tmp` if there are none) and its working directory `work_dir`,
+/// each capped to `limit`.
+pub fn limit_disk_with_tmpfs(host_config: &mut HostConfig, limit: u64, work_dir: Option<&str>) {
+    host_config.storage_opt = None;
+    host_config.readonly_rootfs = Some(true);
+    let tmpfs = host_config.tmpfs.get_or_insert_with(Default::default);
+    if tmpfs.is_empty() {
+        tmpfs.insert("/tmp".into(), "mode=1777".into());
+    }
+    for options in tmpfs.values_mut() {
+        let mut size = limit;
+        let mut rest = vec![];
+        for opt in options.split(',').filter(|opt| !opt.is_empty()) {
+            match parse_size_option(opt) {
+                Some(s) => size = size.min(s),
+                None => rest.push(opt),
+            }
+        }
+        *options = std::iter::once(format!("size={}", size))
+            .chain(rest.into_iter().map(|x| x.to_owned()))
+            .join(",");
+    }
+    if let Some(work_dir) = work_dir {
+        mount_work_dir(host_config, work_dir, limit);
+    }
+}
+
+/// Keep `work_dir` writable under a read-only root filesystem, by mounting a
+/// tmpfs-backed volume of at most `size` bytes over it. Unlike a tmpfs mount,
+/// the volume starts with the files the image has in `work_dir`. A volume
+/// already mounted this way is capped to `size`; other mounts on `work_dir`
+/// are left alone.
+pub fn mount_work_dir(host_config: &mut HostConfig, work_dir: &str, size: u64) {
+    let mounts = host_config.mounts.get_or_insert_with(Default::default);
+    let existing = mounts
+        .iter_mut()
+        .find(|mount| mount.target.as_deref().map(|x| x.trim_end_matches('/')) == Some(work_dir));
+    let existing = match existing {
+        Some(mount) => mount,
+        None => {
+            mounts.push(Mount {
+                target: Some(work_dir.into()),
+                typ: Some(MountTypeEnum::VOLUME),
+                volume_options: Some(MountVolumeOptions {
+                    driver_config: Some(MountVolumeOptionsDriverConfig {
+                        name: Some("local".into()),
+                        options: Some(tmpfs_volume_options(size)),
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+            return;
+        }
+    };
+    let options = existing
+        .volume_options
+        .as_mut()
+        .and_then(|x| x.driver_config.as_mut())
+        .and_then(|x| x.options.as_mut())
+        .filter(|x| x.get("type").map(|x| x.as_str()) == Some("tmpfs"));
+    if let Some(options) = options {
+        let current = options.get("o").and_then(|o| parse_size_option(o));
+        let size = current.map_or(size, |current| current.min(size));
+        options.insert("o".into(), format!("size={}", size));
+    }
+}
+
+fn parse_size_option(opt: &str) -> Option<u64> {
+    opt.strip_prefix("size=").and_then(|x| x.parse().ok())
+}
+
+/// Directories of `host_config` that are mounted from tmpfs, including
+/// tmpfs-backed volumes.
+fn tmpfs_backed_dirs(host_config: &HostConfig) -> Vec<String> {
+    let volumes = host_config.mounts.iter().flatten().filter(|mount| {
+        mount
+            .volume_options
+            .as_ref()
+            .and_then(|x| x.driver_config.as_ref())
+            .and_then(|x| x.options.as_ref())
+            .is_some_and(|x| x.get("type").map(|x| x.as_str()) == Some("tmpfs"))
+    });
+    host_config
+        .tmpfs
+        .iter()
+        .flat_map(|tmpfs| tmpfs.keys().cloned())
+        .chain(volumes.filter_map(|mount| mount.target.clone()))
+        .collect()
+}
+
+/// Restrictions applied to containers that run untrusted code.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SecurityProfile {
+    /// Drop all Linux capabilities except those in `cap_add`.
+    pub drop_all_capabilities: bool,
+    /// Capabilities to keep, e.g. `CHOWN`.
+    pub cap_add: Vec<String>,
+    /// Stop processes from gaining privileges, e.g. through setuid binaries.
+    pub no_new_privileges: bool,
+    /// Maximum number of processes and threads in the container.
+    pub pids_limit: Option<i64>,
+    /// Mount the root filesystem as read-only. Programs can then only write
+    /// into `tmpfs_dirs` and the working directory of the image, which is
+    /// mounted as a tmpfs-backed volume holding the files the image has
+    /// there. Images working in `/` have no writable working directory.
+    pub read_only_rootfs: bool,
+    /// Size limit of the working directory under a read-only root
+    /// filesystem, in bytes.
+    pub work_dir_size: u64,
+    /// Directories mounted as tmpfs, which are writable even if the root
+    /// filesystem is read-only. Files of the image inside them are hidden.
+    pub tmpfs_dirs: Vec<String>,
+    /// Size limit of each tmpfs directory, in bytes.
+    pub tmpfs_size: u64,
+    /// Limit of open files of each process.
+    pub nofile_limit: Option<i64>,
+    /// Limit of processes of the container user. Note that the kernel counts
+    /// processes of the same user ID across all containers and the host.
+    pub nproc_limit: Option<i64>,
+    /// Path to a seccomp profile in JSON. Docker's default profile is used if
+    /// absent.
+    pub seccomp_profile: Option<PathBuf>,
+    /// How far test suites may relax this profile.
+    pub relaxation_limits: RelaxationLimits,
+}
+
+impl Default for SecurityProfile {
+    fn default() -> Self {
+        SecurityProfile {
+            drop_all_capabilities: true,
+            cap_add: vec![],
+            no_new_privileges: true,
+            pids_limit: Some(256),
+            read_only_rootfs: true,
+            work_dir_size: 256 * 1024 * 1024,
+            tmpfs_dirs: vec!["/tmp".into()],
+            tmpfs_size: 64 * 1024 * 1024,
+            nofile_limit: Some(1024),
+            nproc_limit: None,
+            seccomp_profile: None,
+            relaxation_limits: RelaxationLimits::default(),
+        }
+    }
+}
+
+/// Bounds of the relaxations of a [`SecurityProfile`] requested by test
+/// suites. Requests beyond them are clamped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RelaxationLimits {
+    /// Capabilities that test suites may keep. Others are ignored.
+    pub allowed_capabilities: Vec<String>,
+    /// Maximum limit of processes and threads.
+    pub max_pids_limit: i64,
+    /// Maximum size of each tmpfs directory, in bytes.
+    pub max_tmpfs_size: u64,
+    /// Maximum limit of open files of each process.
+    pub max_nofile_limit: i64,
+    /// Whether test suites may make the root filesystem writable.
+    pub allow_writable_rootfs: bool,
+    /// Whether test suites may let processes gain privileges.
+    pub allow_new_privileges: bool,
+}
+
+impl Default for RelaxationLimits {
+    fn default() -> Self {
+        RelaxationLimits {
+            allowed_capabilities: vec!["SYS_PTRACE".into()],
+            max_pids_limit: 1024,
+            max_tmpfs_size: 1024 * 1024 * 1024,
+            max_nofile_limit: 65536,
+            allow_writable_rootfs: false,
+            allow_new_privileges: false,
+        }
+    }
+}
+
+impl RelaxationLimits {
+    /// Whether test suites may keep capability `cap`, with or without the
+    /// `CAP_` prefix.
+    pub fn allows_capability(&self, cap: &str) -> bool {
+        let normalize = |cap: &str| {
+            let cap = cap.to_ascii_uppercase();
+            cap.strip_prefix("CAP_")
+                .map(|x| x.to_owned())
+                .unwrap_or(cap)
+        };
+        let cap = normalize(cap);
+        self.allowed_capabilities
+            .iter()
+            .any(|allowed| normalize(allowed) == cap)
+    }
+
+    /// Clamp a requested limit to `max`. Docker takes values not above 0 as
+    /// unlimited, so those are clamped too.
+    pub fn clamp_limit(requested: i64, max: i64) -> i64 {
+        if requested <= 0 {
+            max
+        } else {
+            requested.min(max)
+        }
+    }
+}
+
+impl SecurityProfile {
+    /// Set the security-related fields of `host_config` from this profile.
+    /// Fails if the seccomp profile cannot be read.
+    pub fn apply(&self, host_config: &mut HostConfig) -> std::io::Result<()> {
+        if self.drop_all_capabilities {
+            host_config.cap_drop = Some(vec!["ALL".into()]);
+        }
+        if !self.cap_add.is_empty() {
+            host_config.cap_add = Some(self.cap_add.clone());
+        }
+
+        let mut security_opt = vec![];
+        if self.no_new_privileges {
+            security_opt.push("no-new-privileges".to_owned());
+        }
+        if let Some(path) = &self.seccomp_profile {
+            // Docker takes the content of the profile instead of its path
+            let profile = std::fs::read_to_string(path)?;
+            security_opt.push(format!("seccomp={}", profile));
+        }
+        if !security_opt.is_empty() {
+            host_config.security_opt = Some(security_opt);
+        }
+
+        host_config.pids_limit = self.pids_limit;
+        host_config.readonly_rootfs = Some(self.read_only_rootfs);
+        if !self.tmpfs_dirs.is_empty() {
+            host_config.tmpfs = Some(
+                self.tmpfs_dirs
+                    .iter()
+                    .map(|dir| (dir.clone(), format!("size={},mode=1777", self.tmpfs_size)))
+                    .collect(),
+            );
+        }
+
+        let ulimit = |name: &str, limit: Option<i64>| {
+            limit.map(|limit| ResourcesUlimits {
+                name: Some(name.into()),
+                soft: Some(limit),
+                hard: Some(limit),
+            })
+        };
+        let ulimits = [
+            ulimit("nofile", self.nofile_limit),
+            ulimit("nproc", self.nproc_limit),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+        if !ulimits.is_empty() {
+            host_config.ulimits = Some(ulimits);
+        }
+        Ok(())
+    }
 }
 
 impl CreateContainerConfig {
     pub fn builder() -> CreateContainerConfigBuilder {
         CreateContainerConfigBuilder::default()
     }
+
+    /// Set up the network of a container created with this config. Returns
+    /// the network to join when creating the container, if any.
+    ///
+    /// A container without network access lives only in `network`, or in no
+    /// network at all. A container with network access uses the default
+    /// bridge, and should be connected to `network` after it's created.
+    pub fn apply_network(&self, host_config: &mut HostConfig) -> Option<NetworkingConfig<String>> {
+        if self.network_enabled {
+            return None;
+        }
+        match &self.network {
+            Some(network) => {
+                host_config.network_mode = Some(network.clone());
+                Some(NetworkingConfig {
+                    endpoints_config: [(network.clone(), self.endpoint_settings())].into(),
+                })
+            }
+            None => {
+                host_config.network_mode = Some("none".into());
+                None
+            }
+        }
+    }
+
+    fn endpoint_settings(&self) -> EndpointSettings {
+        EndpointSettings {
+            aliases: Some(self.network_aliases.clone()),
+            ..Default::default()
+        }
+    }
+}
+
+/// The working directory of `image`, unless it's the root directory, which
+/// can't be mounted over.
+async fn image_work_dir(
+    docker: &Docker,
+    image: &str,
+) -> Result<Option<String>, bollard::errors::Error> {
+    let image = docker.inspect_image(image).await?;
+    Ok(image
+        .config
+        .and_then(|config| config.working_dir)
+        .map(|dir| dir.trim_end_matches('/').to_owned())
+        .filter(|dir| !dir.is_empty()))
 }
 
 #[derive(Debug)]
@@ -69,6 +428,9 @@ pub struct Container {
     id: String,
     tag: Option<String>,
     state: ContainerState,
+    disk_limit: Option<u64>,
+    /// Directories capped by [`limit_disk_with_tmpfs`].
+    limited_dirs: Vec<String>,
 
     /// Make sure this container is fully stopped and teared down before losing
     /// all reference of it.
@@ -82,44 +444,104 @@ impl Container {
         cfg: CreateContainerConfig,
     ) -> Result<Self, bollard::errors::Error> {
         tracing::debug!(%image, "Creating container from image");
-        let res = docker
-            .create_container::<String, _>(
-                None,
-                Config {
-                    image: Some(image),
-                    attach_stdin: Some(true),
-                    attach_stdout: Some(true),
-                    attach_stderr: Some(true),
-                    tty: Some(true),
-                    // set docker user
-                    user: cfg.docker_user,
-                    host_config: Some(bollard::service::HostConfig {
-                        mounts: Some(cfg.mounts),
-                        // set memory limits
-                        memory_swap: cfg.mem_limit,
-                        // set cpu limits
-                        nano_cpus: cfg.cpu_quota.map(|x| (x * 1e9) as i64),
-                        ..Default::default()
-                    }),
-                    entrypoint: Some(vec!["sh".into()]),
-                    // Set network availability
-                    network_disabled: Some(!cfg.network_enabled),
-                    ..Default::default()
-                },
-            )
-            .await?;
+        let mut host_config = HostConfig {
+            mounts: Some(cfg.mounts.clone()),
+            // set memory limits
+            memory_swap: cfg.mem_limit,
+            // set cpu limits
+            nano_cpus: cfg.cpu_quota.map(|x| (x * 1e9) as i64),
+            ..Default::default()
+        };
+        let read_only_rootfs = cfg.security.as_ref().is_some_and(|x| x.read_only_rootfs);
+        let work_dir = if read_only_rootfs || cfg.disk_limit.is_some() {
+            image_work_dir(&docker, &image).await?
+        } else {
+            None
+        };
+        if let Some(security) = &cfg.security {
+            security.apply(&mut host_config)?;
+            if let (true, Some(work_dir)) = (read_only_rootfs, &work_dir) {
+                mount_work_dir(&mut host_config, work_dir, security.work_dir_size);
+            }
+        }
+        if let Some(limit) = cfg.disk_limit {
+            host_config.storage_opt = Some([("size".to_owned(), limit.to_string())].into());
+        }
+        let networking_config = cfg.apply_network(&mut host_config);
+        let network_disabled = host_config.network_mode.as_deref() == Some("none");
+        let env = cfg
+            .env
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>();
+        let config = |host_config| Config {
+            image: Some(image.clone()),
+            attach_stdin: Some(true),
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            tty: Some(true),
+            // set docker user
+            user: cfg.docker_user.clone(),
+            env: (!env.is_empty()).then(|| env.clone()),
+            host_config: Some(host_config),
+            entrypoint: (!cfg.keep_entrypoint).then(|| vec!["sh".into()]),
+            // Set network availability
+            network_disabled: Some(network_disabled),
+            networking_config: networking_config.clone(),
+            ..Default::default()
+        };
+        let mut limited_dirs = vec![];
+        let res = match docker
+            .create_container::<String, _>(None, config(host_config.clone()))
+            .await
+        {
+            Err(e) if cfg.disk_limit.is_some() && is_storage_quota_unsupported(&e) => {
+                tracing::warn!(
+                    "Storage driver cannot limit container size, using tmpfs instead: {}",
+                    e
+                );
+                limit_disk_with_tmpfs(
+                    &mut host_config,
+                    cfg.disk_limit.unwrap(),
+                    work_dir.as_deref(),
+                );
+                limited_dirs = tmpfs_backed_dirs(&host_config);
+                docker
+                    .create_container::<String, _>(None, config(host_config))
+                    .await?
+            }
+            res => res?,
+        };
 
         let mut container = Container {
             docker: docker.clone(),
             id: res.id,
-            tag: cfg.tag_name,
+            tag: cfg.tag_name.clone(),
             state: ContainerState::Running,
+            disk_limit: cfg.disk_limit,
+            limited_dirs,
 
             _teardown_bomb: drop_bomb::DropBomb::new(
                 "`Container::teardown()` must be called before dropping!",
             ),
         };
 
+        if let (Some(network), true) = (&cfg.network, cfg.network_enabled) {
+            let connected = docker
+                .connect_network(
+                    network,
+                    ConnectNetworkOptions {
+                        container: container.id.as_str(),
+                        endpoint_config: cfg.endpoint_settings(),
+                    },
+                )
+                .await;
+            if let Err(e) = connected {
+                let _ = container.remove().await;
+                return Err(e);
+            }
+        }
+
         match docker.start_container::<String>(&container.id, None).await {
             Ok(_) => {}
             Err(e) => {
@@ -133,7 +555,7 @@ impl Container {
 
     pub async fn copy_local_files(&self, file_path: &Path, into_path: &str) -> anyhow::Result<()> {
         tracing::debug!(name = %self.name(), ?file_path, ?into_path, "Copying local files into container");
-        let (tar, join) = pack_as_tar(file_path, Gitignore::empty())?;
+        let (tar, join) = pack_as_tar(file_path, Gitignore::empty(), SymlinkPolicy::Preserve)?;
         self.docker
             .upload_to_container(
                 &self.id,
@@ -183,8 +605,10 @@ impl Container {
             bollard::exec::StartExecResults::Detached => unreachable!("All exec are attached"),
         };
 
-        let mut stdout = SizeConstraintBytesMut::new(opt.stdout_size_limit);
-        let mut stderr = SizeConstraintBytesMut::new(opt.stderr_size_limit);
+        let mut stdout = SizeConstraintBytesMut::new(opt.stdout_size_limit)
+            .with_spill(opt.in_memory_size_limit, opt.spill_folder.as_deref());
+        let mut stderr = SizeConstraintBytesMut::new(opt.stderr_size_limit)
+            .with_spill(opt.in_memory_size_limit, opt.spill_folder.as_deref());
 
         let timeout_timer = opt.timeout.map_or_else(
             || futures::future::pending().left_future(),
@@ -214,8 +638,12 @@ impl Container {
             };
 
             match out {
-                bollard::container::LogOutput::StdErr { message } => stderr.append(&message),
-                bollard::container::LogOutput::StdOut { message } => stdout.append(&message),
+                bollard::container::LogOutput::StdErr { message } => {
+                    stderr.append(&message).await?
+                }
+                bollard::container::LogOutput::StdOut { message } => {
+                    stdout.append(&message).await?
+                }
                 bollard::container::LogOutput::StdIn { .. } => {}
                 bollard::container::LogOutput::Console { .. } => {}
             }
@@ -224,6 +652,9 @@ impl Container {
         let results = self.docker.inspect_exec(exec_id).await?;
         let ret_code = results.exit_code;
 
+        let (stdout, stdout_file) = stdout.into_output().await?;
+        let (stderr, stderr_file) = stderr.into_output().await?;
+
         Ok(ProcessOutput {
             ret_code: if timed_out {
                 ExitStatus::Timeout
@@ -237,13 +668,43 @@ impl Container {
                 ExitStatus::Unknown
             },
             command: command.to_string(),
-            stdout: stdout.into_string(),
-            stderr: stderr.into_string(),
+            stdout,
+            stderr,
 
             runned_inside: self.name().into(),
+            stdout_file,
+            stderr_file,
         })
     }
 
+    /// Whether this container has used up the disk space it may write, either
+    /// in its writable layer or in any tmpfs directory capped in place of it.
+    pub async fn is_disk_full(&self) -> anyhow::Result<bool> {
+        let limit = match self.disk_limit {
+            Some(limit) => limit,
+            None => return Ok(false),
+        };
+        let info = self
+            .docker
+            .inspect_container(&self.id, Some(InspectContainerOptions { size: true }))
+            .await?;
+        let used = info.size_rw.unwrap_or(0).max(0) as u64;
+        if used + DISK_FULL_MARGIN >= limit {
+            return Ok(true);
+        }
+        if self.limited_dirs.is_empty() {
+            return Ok(false);
+        }
+
+        let command = format!("df -Pk {}", self.limited_dirs.join(" "));
+        let opt = CommandRunOptionsBuilder::default()
+            .timeout(Some(std::time::Duration::from_secs(10)))
+            .build()
+            .unwrap();
+        let output = self.exec(&command, &mut std::iter::empty(), &opt).await?;
+        Ok(df_shows_full(&output.stdout))
+    }
+
     pub async fn remove(&mut self) -> anyhow::Result<()> {
         tracing::debug!(%self.id, "Removing container");
         // Defuse the teardown drop bomb.
@@ -287,6 +748,10 @@ impl CommandRunner for Container {
         self.exec(command, env, opt).await
     }
 
+    async fn is_disk_full(&self) -> anyhow::Result<bool> {
+        Container::is_disk_full(self).await
+    }
+
     fn name(&self) -> std::borrow::Cow<'static, str> {
         if let Some(tag) = &self.tag {
             format!("Container {} ({})", tag, self.id).into()
@@ -303,6 +768,21 @@ impl crate::util::AsyncTeardown for Container {
     }
 }
 
+/// Free space below which a disk counts as full, since the write that failed
+/// may have been larger than what was left.
+const DISK_FULL_MARGIN: u64 = 1024 * 1024;
+
+/// Whether any filesystem listed in the output of `df -Pk` has less than
+/// [`DISK_FULL_MARGIN`] of free space.
+fn df_shows_full(output: &str) -> bool {
+    output.lines().skip(1).any(|line| {
+        line.split_whitespace()
+            .nth(3)
+            .and_then(|available| available.parse::<u64>().ok())
+            .is_some_and(|available| available * 1024 < DISK_FULL_MARGIN)
+    })
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum ContainerState {
     Empty,
@@ -310,37 +790,93 @@ pub enum ContainerState {
     Running,
 }
 
+/// A byte buffer that stops accepting data after `size_limit` bytes. Data
+/// beyond `memory_limit` bytes is written into a file at `spill_path`, if one
+/// is given.
 struct SizeConstraintBytesMut {
     size_limit: usize,
+    memory_limit: usize,
+    written: usize,
     bytes: BytesMut,
+    spill_path: Option<PathBuf>,
+    spill_file: Option<BufWriter<File>>,
 }
 
 impl SizeConstraintBytesMut {
     pub fn new(size_limit: usize) -> Self {
         SizeConstraintBytesMut {
             size_limit,
+            memory_limit: size_limit,
+            written: 0,
             bytes: BytesMut::new(),
+            spill_path: None,
+            spill_file: None,
         }
     }
 
-    pub fn append(&mut self, bytes: &[u8]) {
-        if self.bytes.len() > self.size_limit {
-            // do nothing
-        } else if self.bytes.len() + bytes.len() > self.size_limit {
-            let cut_at = self.size_limit - self.bytes.len();
-            self.bytes.extend_from_slice(&bytes[0..cut_at]);
-        } else {
-            self.bytes.extend_from_slice(bytes);
+    /// Spill data larger than `memory_limit` into a new file inside `folder`.
+    pub fn with_spill(mut self, memory_limit: usize, folder: Option<&Path>) -> Self {
+        if let Some(folder) = folder {
+            self.memory_limit = memory_limit.min(self.size_limit);
+            self.spill_path = Some(folder.join(FlowSnake::generate().to_string()));
+        }
+        self
+    }
+
+    pub async fn append(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        let cut_at = bytes
+            .len()
+            .min(self.size_limit.saturating_sub(self.written));
+        let bytes = &bytes[0..cut_at];
+        self.written += bytes.len();
+
+        if self.spill_file.is_none() && self.bytes.len() + bytes.len() > self.memory_limit {
+            if let Some(path) = &self.spill_path {
+                tracing::debug!(?path, "Output too large, spilling into file");
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                let mut file = BufWriter::new(File::create(path).await?);
+                file.write_all(&self.bytes).await?;
+                self.spill_file = Some(file);
+            }
         }
+        if let Some(file) = &mut self.spill_file {
+            file.write_all(bytes).await?;
+        }
+
+        let in_memory = bytes.len().min(self.memory_limit - self.bytes.len());
+        self.bytes.extend_from_slice(&bytes[0..in_memory]);
+        Ok(())
     }
 
     pub fn is_oversized(&self) -> bool {
-        self.bytes.len() >= self.size_limit
+        self.written >= self.size_limit
     }
 
-    pub fn into_string(self) -> String {
+    /// Returns the in-memory part of the output, and the path of the file
+    /// holding the full output if it has been spilled.
+    pub async fn into_output(mut self) -> std::io::Result<(String, Option<PathBuf>)> {
         let oversized = self.is_oversized();
+        let spilled = match self.spill_file.take() {
+            Some(mut file) => {
+                file.flush().await?;
+                self.spill_path.take()
+            }
+            None => None,
+        };
+
         let mut s = String::from_utf8_lossy(&self.bytes).into_owned();
+        if spilled.is_some() {
+            writeln!(s).unwrap();
+            writeln!(
+                s,
+                "--- {} bytes of output in total, only the first {} bytes are shown ---",
+                self.written,
+                self.bytes.len()
+            )
+            .unwrap();
+        }
         if oversized {
             writeln!(s).unwrap();
             writeln!(
@@ -350,6 +886,224 @@ impl SizeConstraintBytesMut {
             )
             .unwrap();
         }
-        s
+        Ok((s, spilled))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::util::TempDir;
+
+    #[tokio::test]
+    async fn test_output_buffer_cap() {
+        let mut buf = SizeConstraintBytesMut::new(8);
+        buf.append(b"12345").await.unwrap();
+        buf.append(b"67890").await.unwrap();
+        let (s, file) = buf.into_output().await.unwrap();
+        assert!(s.starts_with("12345678\n"));
+        assert!(s.contains("capped out at 8 bytes"));
+        assert_eq!(file, None);
+    }
+
+    #[tokio::test]
+    async fn test_output_buffer_spill() {
+        let folder = TempDir::new();
+        let mut buf = SizeConstraintBytesMut::new(1024).with_spill(4, Some(&folder));
+        buf.append(b"123").await.unwrap();
+        buf.append(b"4567").await.unwrap();
+        buf.append(b"89").await.unwrap();
+        let (s, file) = buf.into_output().await.unwrap();
+        assert!(s.starts_with("1234\n"));
+        assert!(!s.contains("capped out"));
+
+        let file = file.expect("output should be spilled");
+        assert_eq!(tokio::fs::read(&file).await.unwrap(), b"123456789");
+    }
+
+    #[test]
+    fn test_security_profile_host_config() {
+        let mut host_config = HostConfig::default();
+        SecurityProfile::default().apply(&mut host_config).unwrap();
+        assert_eq!(host_config.cap_drop, Some(vec!["ALL".to_owned()]));
+        assert_eq!(host_config.cap_add, None);
+        assert_eq!(
+            host_config.security_opt,
+            Some(vec!["no-new-privileges".to_owned()])
+        );
+        assert_eq!(host_config.pids_limit, Some(256));
+        assert_eq!(host_config.readonly_rootfs, Some(true));
+        assert_eq!(
+            host_config.tmpfs.unwrap()["/tmp"],
+            format!("size={},mode=1777", 64 * 1024 * 1024)
+        );
+        assert_eq!(
+            host_config.ulimits,
+            Some(vec![ResourcesUlimits {
+                name: Some("nofile".into()),
+                soft: Some(1024),
+                hard: Some(1024),
+            }])
+        );
+    }
+
+    #[test]
+    fn test_security_profile_relax() {
+        let folder = TempDir::new();
+        let seccomp = folder.join("seccomp.json");
+        std::fs::write(&seccomp, r#"{"defaultAction":"SCMP_ACT_ALLOW"}"#).unwrap();
+        let profile = SecurityProfile {
+            seccomp_profile: Some(seccomp.clone()),
+            relaxation_limits: RelaxationLimits {
+                allow_writable_rootfs: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let relaxation: crate::tester::model::SecurityRelaxation = serde_json::from_str(
+            r#"{"capAdd": ["SYS_PTRACE"], "writableRootfs": true, "pidsLimit": 1024}"#,
+        )
+        .unwrap();
+
+        let mut host_config = HostConfig::default();
+        relaxation.relax(&profile).apply(&mut host_config).unwrap();
+        assert_eq!(host_config.cap_drop, Some(vec!["ALL".to_owned()]));
+        assert_eq!(host_config.cap_add, Some(vec!["SYS_PTRACE".to_owned()]));
+        assert_eq!(host_config.readonly_rootfs, Some(false));
+        assert_eq!(host_config.pids_limit, Some(1024));
+        assert_eq!(
+            host_config.security_opt,
+            Some(vec![
+                "no-new-privileges".to_owned(),
+                r#"seccomp={"defaultAction":"SCMP_ACT_ALLOW"}"#.to_owned()
+            ])
+        );
+
+        std::fs::remove_file(&seccomp).unwrap();
+        let mut host_config = HostConfig::default();
+        profile.apply(&mut host_config).unwrap_err();
+    }
+
+    #[test]
+    fn test_security_relaxation_limits() {
+        let profile = SecurityProfile::default();
+        let relaxation: crate::tester::model::SecurityRelaxation = serde_json::from_str(
+            r#"{
+                "capAdd": ["SYS_ADMIN", "cap_sys_ptrace"],
+                "pidsLimit": -1,
+                "tmpfsSize": 1099511627776,
+                "nofileLimit": 2048,
+                "writableRootfs": true,
+                "allowNewPrivileges": true
+            }"#,
+        )
+        .unwrap();
+        let relaxed = relaxation.relax(&profile);
+        assert_eq!(relaxed.cap_add, vec!["cap_sys_ptrace".to_owned()]);
+        assert!(relaxed.read_only_rootfs);
+        assert!(relaxed.no_new_privileges);
+        assert_eq!(relaxed.pids_limit, Some(1024));
+        assert_eq!(relaxed.tmpfs_size, 1024 * 1024 * 1024);
+        assert_eq!(relaxed.nofile_limit, Some(2048));
+    }
+
+    #[test]
+    fn test_network_host_config() {
+        let isolated = CreateContainerConfig::builder().build().unwrap();
+        let mut host_config = HostConfig::default();
+        assert!(isolated.apply_network(&mut host_config).is_none());
+        assert_eq!(host_config.network_mode.as_deref(), Some("none"));
+
+        let internal = CreateContainerConfig::builder()
+            .network(Some("rurikawa-job-1".into()))
+            .network_aliases(vec!["user".into()])
+            .build()
+            .unwrap();
+        let mut host_config = HostConfig::default();
+        let networking_config = internal.apply_network(&mut host_config).unwrap();
+        assert_eq!(host_config.network_mode.as_deref(), Some("rurikawa-job-1"));
+        assert_eq!(
+            networking_config.endpoints_config["rurikawa-job-1"].aliases,
+            Some(vec!["user".to_owned()])
+        );
+
+        let full = CreateContainerConfig::builder()
+            .network_enabled(true)
+            .network(Some("rurikawa-job-1".into()))
+            .build()
+            .unwrap();
+        let mut host_config = HostConfig::default();
+        assert!(full.apply_network(&mut host_config).is_none());
+        assert_eq!(host_config.network_mode, None);
+    }
+
+    #[test]
+    fn test_limit_disk_with_tmpfs() {
+        let mut host_config = HostConfig::default();
+        let profile = SecurityProfile {
+            read_only_rootfs: false,
+            tmpfs_dirs: vec!["/tmp".into(), "/var/tmp".into()],
+            tmpfs_size: 1024,
+            ..Default::default()
+        };
+        profile.apply(&mut host_config).unwrap();
+        host_config.storage_opt = Some([("size".to_owned(), "4096".to_owned())].into());
+
+        limit_disk_with_tmpfs(&mut host_config, 2048, Some("/work"));
+        assert_eq!(host_config.storage_opt, None);
+        assert_eq!(host_config.readonly_rootfs, Some(true));
+        let tmpfs = host_config.tmpfs.as_ref().unwrap();
+        assert_eq!(tmpfs["/tmp"], "size=1024,mode=1777");
+        assert_eq!(tmpfs["/var/tmp"], "size=1024,mode=1777");
+        let mount = &host_config.mounts.as_ref().unwrap()[0];
+        assert_eq!(mount.target.as_deref(), Some("/work"));
+        assert_eq!(mount.typ, Some(MountTypeEnum::VOLUME));
+        let options = mount.volume_options.as_ref().unwrap();
+        let options = options.driver_config.as_ref().unwrap().options.as_ref();
+        assert_eq!(options, Some(&tmpfs_volume_options(2048)));
+        let mut dirs = tmpfs_backed_dirs(&host_config);
+        dirs.sort();
+        assert_eq!(dirs, vec!["/tmp", "/var/tmp", "/work"]);
+
+        let mut host_config = HostConfig::default();
+        limit_disk_with_tmpfs(&mut host_config, 2048, None);
+        assert_eq!(host_config.tmpfs.unwrap()["/tmp"], "size=2048,mode=1777");
+        assert_eq!(host_config.mounts, None);
+    }
+
+    #[test]
+    fn test_mount_work_dir() {
+        let data = Mount {
+            target: Some("/data/".into()),
+            source: Some("rurikawa-data".into()),
+            typ: Some(MountTypeEnum::VOLUME),
+            ..Default::default()
+        };
+        let mut host_config = HostConfig {
+            mounts: Some(vec![data.clone()]),
+            ..Default::default()
+        };
+        mount_work_dir(&mut host_config, "/data", 1024);
+        assert_eq!(host_config.mounts, Some(vec![data]));
+
+        let mut host_config = HostConfig::default();
+        mount_work_dir(&mut host_config, "/work", 4096);
+        mount_work_dir(&mut host_config, "/work", 1024);
+        mount_work_dir(&mut host_config, "/work", 2048);
+        let mounts = host_config.mounts.unwrap();
+        assert_eq!(mounts.len(), 1);
+        let options = mounts[0].volume_options.as_ref().unwrap();
+        let options = options.driver_config.as_ref().unwrap().options.as_ref();
+        assert_eq!(options, Some(&tmpfs_volume_options(1024)));
+    }
+
+    #[test]
+    fn test_df_shows_full() {
+        let header = "Filesystem     1024-blocks  Used Available Capacity Mounted on\n";
+        let free = "tmpfs                65536     4     65532       1% /tmp\n";
+        let full = "tmpfs                 2048  2048         0     100% /work\n";
+        assert!(!df_shows_full(&format!("{}{}", header, free)));
+        assert!(df_shows_full(&format!("{}{}{}", header, free, full)));
+        assert!(!df_shows_full("df: /work: No such file or directory\n"));
     }
 }
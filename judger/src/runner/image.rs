@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     path::{Path, PathBuf},
     time::Duration,
 };
@@ -6,18 +7,35 @@ use std::{
 use crate::{
     config::Image,
     prelude::{CancelFutureExt, CancellationTokenHandle},
-    runner::util::is_recoverable_error,
-    tester::model::{canonical_join, BuildError},
-    util::tar::pack_as_tar,
+    runner::{
+        build_event::{
+            pull_event, BuildEvent, BuildEventLimits, BuildEventSink, BuildOutputParser,
+        },
+        util::is_recoverable_error,
+    },
+    tester::model::{canonical_join, BuildError, PullPolicy},
+    util::tar::{digest_packed_tar, ignore_from_dockerignore, pack_as_tar, SymlinkPolicy},
 };
 
-use bollard::{image::CreateImageOptions, models::BuildInfo, Docker};
+use bollard::{
+    auth::DockerCredentials,
+    image::{CreateImageOptions, TagImageOptions},
+    models::BuildInfo,
+    Docker,
+};
 use derive_builder::Builder;
-use futures::{future::FusedFuture, FutureExt};
+use futures::{future::FusedFuture, stream::BoxStream, FutureExt};
 use hyper::Body;
 use ignore::gitignore::Gitignore;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tokio::sync::mpsc::UnboundedSender;
 use tokio_stream::StreamExt;
+use tokio_util::{
+    codec::{FramedRead, LinesCodec, LinesCodecError},
+    io::StreamReader,
+};
 
 #[derive(Builder, Debug)]
 #[builder(setter(into), pattern = "owned")]
@@ -32,36 +50,80 @@ pub struct BuildImageOptions {
     #[builder(default)]
     cancellation: CancellationTokenHandle,
 
+    /// Files to leave out of the build context. Defaults to the patterns in
+    /// `.dockerignore` of the context.
     #[builder(default)]
     ignore: Option<Gitignore>,
 
+    /// What to do with symbolic links in the build context.
+    #[builder(default)]
+    symlinks: SymlinkPolicy,
+
+    /// Where to send the progress of the build.
+    #[builder(default)]
+    build_result_channel: Option<UnboundedSender<BuildEvent>>,
+
+    /// Limits of events sent to `build_result_channel`.
     #[builder(default)]
-    build_result_channel: Option<UnboundedSender<BuildInfo>>,
+    event_limits: BuildEventLimits,
 
     #[builder(default)]
     cpu_quota: Option<f64>,
 
+    /// Whether the build may access the outside world.
     #[builder(default = "true")]
     network_enabled: bool,
 
+    /// A network for the build to use when `network_enabled` is false, e.g.
+    /// the private network of a job.
+    #[builder(default)]
+    network: Option<String>,
+
     /// Build timeout, in milliseconds
     #[builder(default)]
     timeout: Option<Duration>,
+
+    /// Reuse an image built earlier from the same build context, Dockerfile
+    /// and build arguments, if there is one.
+    #[builder(default)]
+    cache: bool,
+
+    /// Extra data that tells cached builds apart, e.g. the id of the image
+    /// that the Dockerfile builds upon.
+    #[builder(default)]
+    cache_salt: Option<String>,
+
+    /// Memory available to the build, in bytes.
+    #[builder(default)]
+    memory_limit: Option<u64>,
+
+    /// Credentials of private registries, keyed by the registry host, e.g.
+    /// `docker.io` or `localhost:5000`.
+    #[builder(default)]
+    registry_credentials: HashMap<String, RegistryCredential>,
 }
 
 impl BuildImageOptions {
-    /// Send the specified build result to the sender, if possible
-    fn send_result(&self, create_msg: impl FnOnce() -> BuildInfo) {
-        if let Some(res) = self.build_result_channel.as_ref() {
-            let _ = res.send(create_msg());
-        }
+    /// A sink of build events that sends them to the result channel, if
+    /// there's one
+    fn event_sink(&self) -> BuildEventSink {
+        BuildEventSink::new(self.build_result_channel.clone(), self.event_limits)
     }
 }
 
+/// Repository of the tags that point to cached builds. See
+/// [`BuildImageOptions::cache`].
+pub const BUILD_CACHE_REPO: &str = "rurikawa-build-cache";
+
 /// The result of building an image
-pub struct BuildImageResult {}
+#[derive(Debug)]
+pub struct BuildImageResult {
+    /// Whether an image built earlier is reused instead of building a new one.
+    pub cache_hit: bool,
+}
 
-/// Build an image from the specified [`Image`] instance.
+/// Build an image from the specified [`Image`] instance. Dockerfiles are built
+/// by the local docker daemon, which `docker` should be connected to.
 pub async fn build_image(
     docker: Docker,
     image: &Image,
@@ -72,9 +134,24 @@ pub async fn build_image(
 
     let build_job = async {
         match image {
-            Image::Prebuilt { tag } => build_prebuilt_image(docker, tag, opt).await,
-            Image::Dockerfile { path, file } => {
-                build_image_from_dockerfile(docker, path, file.as_deref(), opt).await
+            Image::Prebuilt { tag, pull } => build_prebuilt_image(docker, tag, *pull, opt).await,
+            Image::Dockerfile {
+                path,
+                file,
+                args,
+                target,
+                labels,
+                platform,
+            } => {
+                let spec = DockerfileSpec {
+                    path,
+                    file: file.as_deref(),
+                    args,
+                    target: target.as_deref(),
+                    labels,
+                    platform: platform.as_deref(),
+                };
+                build_image_from_dockerfile(docker, spec, opt).await
             }
         }
     };
@@ -93,73 +170,460 @@ pub async fn build_image(
 async fn build_prebuilt_image(
     docker: Docker,
     tag: &str,
+    pull: PullPolicy,
     opt: BuildImageOptions,
 ) -> Result<BuildImageResult, BuildError> {
-    tracing::debug!(%tag, "Fetching prebuilt image");
-    let (name, tag) = tag
-        .split_once(":")
-        .map_or_else(|| (tag, None), |(name, tag)| (name, Some(tag)));
-    let mut create_img = docker.create_image(
-        Some(CreateImageOptions {
-            from_image: name,
-            tag: tag.unwrap_or("latest"),
+    tracing::debug!(%tag, ?pull, "Fetching prebuilt image");
+    let mut events = opt.event_sink();
+    let reference = ImageReference::parse(tag);
+    let local = reference.to_string();
+
+    let should_pull = match pull {
+        PullPolicy::Always => true,
+        PullPolicy::IfNotPresent | PullPolicy::Never => {
+            let present = match docker.inspect_image(&local).await {
+                Ok(_) => true,
+                Err(bollard::errors::Error::DockerResponseNotFoundError { .. }) => false,
+                Err(e) => return Err(BuildError::Internal(e.into())),
+            };
+            if !present && pull == PullPolicy::Never {
+                let message = format!(
+                    "Image {} is not present, and its pull policy is `never`",
+                    local
+                );
+                events.send(BuildEvent::Error {
+                    step: None,
+                    message: message.clone(),
+                });
+                return Err(BuildError::ImagePullFailure(message));
+            }
+            !present
+        }
+    };
+
+    if should_pull {
+        let credentials = opt
+            .registry_credentials
+            .get(reference.registry)
+            .map(|c| c.to_docker_credentials(reference.registry));
+        let mut create_img = docker.create_image(
+            Some(CreateImageOptions {
+                from_image: reference.repository,
+                // Docker takes digests in place of tags
+                tag: reference.tag_or_digest(),
+                ..Default::default()
+            }),
+            None,
+            credentials,
+        );
+        while let Some(Some(res)) = create_img
+            .next()
+            .with_cancel(opt.cancellation.cancelled())
+            .await
+        {
+            let info = res.map_err(|e| {
+                events.send(BuildEvent::Error {
+                    step: None,
+                    message: e.to_string(),
+                });
+                BuildError::ImagePullFailure(e.to_string())
+            })?;
+            events.send(pull_event(&local, info));
+        }
+
+        if opt.cancellation.is_cancelled() {
+            return Err(BuildError::Cancelled);
+        }
+    }
+
+    if local != opt.tag_as {
+        tag_image(&docker, &local, &opt.tag_as)
+            .await
+            .map_err(|e| BuildError::Internal(e.into()))?;
+    }
+
+    let image_id = docker.inspect_image(&local).await.ok().map(|i| i.id);
+    events.send(BuildEvent::Done { image_id });
+    Ok(BuildImageResult { cache_hit: false })
+}
+
+/// A parsed image reference, e.g. `localhost:5000/foo/bar:tag@sha256:...`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ImageReference<'a> {
+    /// The registry host, `docker.io` if absent.
+    pub registry: &'a str,
+    /// Everything before the tag and digest, including the registry.
+    pub repository: &'a str,
+    pub tag: Option<&'a str>,
+    pub digest: Option<&'a str>,
+}
+
+impl<'a> ImageReference<'a> {
+    pub fn parse(reference: &'a str) -> Self {
+        let (name, digest) = match reference.split_once('@') {
+            Some((name, digest)) => (name, Some(digest)),
+            None => (reference, None),
+        };
+        let (repository, tag) = split_tag(name);
+        // Like Docker, the first component is a registry only if it looks
+        // like a host name
+        let registry = match repository.split_once('/') {
+            Some((first, _)) if first.contains(['.', ':']) || first == "localhost" => first,
+            _ => "docker.io",
+        };
+        ImageReference {
+            registry,
+            repository,
+            tag,
+            digest,
+        }
+    }
+
+    /// The digest of this image if pinned, or its tag otherwise.
+    pub fn tag_or_digest(&self) -> &'a str {
+        self.digest.or(self.tag).unwrap_or("latest")
+    }
+}
+
+impl std::fmt::Display for ImageReference<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.digest {
+            Some(digest) => write!(f, "{}@{}", self.repository, digest),
+            None => write!(f, "{}:{}", self.repository, self.tag.unwrap_or("latest")),
+        }
+    }
+}
+
+/// Credentials used to pull images from a private registry.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum RegistryCredential {
+    /// A user name with its password or access token.
+    #[serde(rename_all = "camelCase")]
+    Password { username: String, password: String },
+    /// An identity token issued by the registry.
+    #[serde(rename_all = "camelCase")]
+    IdentityToken { token: String },
+}
+
+impl RegistryCredential {
+    pub fn to_docker_credentials(&self, registry: &str) -> DockerCredentials {
+        let credentials = DockerCredentials {
+            serveraddress: Some(registry.to_owned()),
             ..Default::default()
-        }),
-        None,
-        None,
-    );
-    while let Some(Some(res)) = create_img
-        .next()
-        .with_cancel(opt.cancellation.cancelled())
+        };
+        match self {
+            RegistryCredential::Password { username, password } => DockerCredentials {
+                username: Some(username.clone()),
+                password: Some(password.clone()),
+                ..credentials
+            },
+            RegistryCredential::IdentityToken { token } => DockerCredentials {
+                identitytoken: Some(token.clone()),
+                ..credentials
+            },
+        }
+    }
+}
+
+impl std::fmt::Debug for RegistryCredential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistryCredential::Password { username, .. } => f
+                .debug_struct("Password")
+                .field("username", username)
+                .field("password", &"<redacted>")
+                .finish(),
+            RegistryCredential::IdentityToken { .. } => f.write_str("IdentityToken { <redacted> }"),
+        }
+    }
+}
+
+/// Split an image reference into its repository and tag, e.g.
+/// `localhost:5000/foo:bar` into `localhost:5000/foo` and `bar`.
+pub(crate) fn split_tag(tag: &str) -> (&str, Option<&str>) {
+    match tag.rsplit_once(':') {
+        Some((repo, t)) if !t.contains('/') => (repo, Some(t)),
+        _ => (tag, None),
+    }
+}
+
+/// Tag the image `image` as `tag`.
+async fn tag_image(docker: &Docker, image: &str, tag: &str) -> Result<(), bollard::errors::Error> {
+    let (repo, tag) = split_tag(tag);
+    docker
+        .tag_image(
+            image,
+            Some(TagImageOptions {
+                repo,
+                tag: tag.unwrap_or("latest"),
+            }),
+        )
         .await
-    {
-        let _res = res.map_err(|e| BuildError::ImagePullFailure(e.to_string()))?;
+}
+
+/// The key of a cached build, derived from everything that affects the
+/// built image.
+fn build_cache_key(context_digest: &str, query: &BuildQuery, salt: Option<&str>) -> String {
+    let options = &query.options;
+    let mut hasher = Sha256::new();
+    hasher.update(context_digest);
+    hasher.update([0]);
+    hasher.update(options.dockerfile);
+    hasher.update([0]);
+    hasher.update(query.target.unwrap_or_default());
+    hasher.update([0]);
+    hasher.update(options.platform);
+    hasher.update([0]);
+    for map in [&options.buildargs, &options.labels] {
+        for (k, v) in map.iter().sorted() {
+            hasher.update(k);
+            hasher.update("=");
+            hasher.update(v);
+            hasher.update([0]);
+        }
+        hasher.update([0]);
     }
+    if let Some(salt) = salt {
+        hasher.update(salt);
+    }
+    hex::encode(hasher.finalize())
+}
 
-    if opt.cancellation.is_cancelled() {
-        return Err(BuildError::Cancelled);
+/// An image tagged in [`BUILD_CACHE_REPO`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CachedBuild {
+    /// When the image was last tagged, i.e. built or reused.
+    last_used: chrono::DateTime<chrono::Utc>,
+    /// Size of the layers not shared with other images, in bytes.
+    size: u64,
+    /// Tags of the image in [`BUILD_CACHE_REPO`].
+    tags: Vec<String>,
+}
+
+/// The least recently used builds to remove for the total size of `builds`
+/// to be within `limit`.
+fn builds_to_evict(mut builds: Vec<CachedBuild>, limit: u64) -> Vec<CachedBuild> {
+    let mut total_size: u64 = builds.iter().map(|build| build.size).sum();
+    builds.sort_by_key(|build| build.last_used);
+    builds
+        .into_iter()
+        .take_while(|build| {
+            let over = total_size > limit;
+            total_size = total_size.saturating_sub(build.size);
+            over
+        })
+        .collect()
+}
+
+/// Remove the least recently used cached builds until their total size is
+/// within `limit`. Images still used by containers are skipped by the daemon.
+pub async fn evict_build_cache(docker: &Docker, limit: u64) -> Result<(), bollard::errors::Error> {
+    let images = docker.df().await?.images.unwrap_or_default();
+    let mut builds = vec![];
+    for image in images {
+        let tags = image
+            .repo_tags
+            .into_iter()
+            .filter(|tag| {
+                tag.strip_prefix(BUILD_CACHE_REPO)
+                    .is_some_and(|rest| rest.starts_with(':'))
+            })
+            .collect::<Vec<_>>();
+        if tags.is_empty() {
+            continue;
+        }
+        let last_used = docker
+            .inspect_image(&image.id)
+            .await?
+            .metadata
+            .and_then(|m| m.last_tag_time)
+            .unwrap_or_else(|| chrono::DateTime::<chrono::Utc>::from(std::time::UNIX_EPOCH));
+        let size = (image.size - image.shared_size.max(0)).max(0) as u64;
+        builds.push(CachedBuild {
+            last_used,
+            size,
+            tags,
+        });
+    }
+
+    for build in builds_to_evict(builds, limit) {
+        tracing::info!(tags = ?build.tags, size = %build.size, "Evicting cached build");
+        for tag in &build.tags {
+            if let Err(e) = docker.remove_image(tag, None, None).await {
+                tracing::warn!(%tag, "Failed to evict cached build: {}", e);
+            }
+        }
     }
+    Ok(())
+}
+
+/// Everything about an [`Image::Dockerfile`].
+struct DockerfileSpec<'a> {
+    path: &'a Path,
+    file: Option<&'a str>,
+    args: &'a HashMap<String, String>,
+    target: Option<&'a str>,
+    labels: &'a HashMap<String, String>,
+    platform: Option<&'a str>,
+}
 
-    Ok(BuildImageResult {})
+/// The socket of the docker daemon that [`Docker::connect_with_local_defaults`]
+/// connects to.
+const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
+/// Query parameters of a build request. Bollard's options lack `target`,
+/// although the engine API has it since version 1.29.
+#[derive(Debug, Serialize)]
+struct BuildQuery<'a> {
+    #[serde(flatten)]
+    options: bollard::image::BuildImageOptions<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<&'a str>,
+}
+
+/// Send a build request with `query` to the local docker daemon, the way
+/// [`Docker::build_image`] does. Returns the build output.
+fn start_build(
+    query: &BuildQuery,
+    credentials: Option<HashMap<String, DockerCredentials>>,
+    tar: Body,
+) -> BoxStream<'static, Result<BuildInfo, bollard::errors::Error>> {
+    let request = (|| {
+        let version = bollard::API_DEFAULT_VERSION;
+        let path = format!(
+            "/v{}.{}/build?{}",
+            version.major_version,
+            version.minor_version,
+            serde_urlencoded::to_string(query)?
+        );
+        let credentials = serde_json::to_string(&credentials.unwrap_or_default())?;
+        Ok::<_, bollard::errors::Error>(
+            hyper::Request::post(hyperlocal::Uri::new(DOCKER_SOCKET, &path))
+                .header(hyper::header::CONTENT_TYPE, "application/x-tar")
+                .header("X-Registry-Config", base64::encode(credentials))
+                .body(tar)?,
+        )
+    })();
+
+    let response = async move {
+        let client = hyper::Client::builder().build(hyperlocal::UnixConnector);
+        let response = client.request(request?).await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+            // Errors come as `{"message": "..."}`
+            let message = serde_json::from_slice::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|x| x.get("message")?.as_str().map(str::to_owned))
+                .unwrap_or_else(|| String::from_utf8_lossy(&body).into_owned());
+            return Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: status.as_u16(),
+                message,
+            });
+        }
+        let body = futures::TryStreamExt::map_err(response.into_body(), std::io::Error::other);
+        let lines = FramedRead::new(StreamReader::new(body), LinesCodec::new());
+        Ok(lines.filter_map(|line| match line {
+            Ok(line) if line.trim().is_empty() => None,
+            Ok(line) => Some(serde_json::from_str::<BuildInfo>(&line).map_err(Into::into)),
+            Err(LinesCodecError::Io(err)) => Some(Err(err.into())),
+            Err(e) => Some(Err(
+                std::io::Error::new(std::io::ErrorKind::InvalidData, e).into()
+            )),
+        }))
+    };
+    Box::pin(futures::TryFutureExt::try_flatten_stream(response))
 }
 
 async fn build_image_from_dockerfile(
     docker: Docker,
-    path: &Path,
-    file: Option<&str>,
+    spec: DockerfileSpec<'_>,
     mut opt: BuildImageOptions,
 ) -> Result<BuildImageResult, BuildError> {
     tracing::debug!("Building image from dockerfile");
-    let source_path = canonical_join(&opt.base_path, path);
+    let source_path = canonical_join(&opt.base_path, spec.path);
+    let file = spec.file;
+    let dockerfile = file.unwrap_or("Dockerfile");
     let cpu_quota = opt.cpu_quota.map(|x| (x * 100_000f64).floor() as u64);
     let cpu_period = cpu_quota.map(|_| 100_000);
 
     tracing::debug!(?source_path, ?file, "Building image from local folder");
+    let mut events = opt.event_sink();
 
-    let build_options = bollard::image::BuildImageOptions {
-        dockerfile: file.unwrap_or("Dockerfile"),
+    let options = bollard::image::BuildImageOptions {
+        dockerfile,
         t: &opt.tag_as,
         cpuquota: cpu_quota,
         cpuperiod: cpu_period,
 
+        // No swap, so that the limit is the actual memory available
+        memory: opt.memory_limit,
+        memswap: opt.memory_limit.map(|x| x as i64),
+
         networkmode: if opt.network_enabled {
             "bridge"
         } else {
-            "none"
+            opt.network.as_deref().unwrap_or("none")
         },
 
+        // Only removes intermediate containers. Intermediate images are kept
+        // as Docker's layer cache, so `nocache` must stay unset.
         rm: true,
 
-        buildargs: [("CI", "true")].into(),
+        buildargs: [("CI", "true")]
+            .into_iter()
+            .chain(spec.args.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+            .collect(),
+        labels: spec
+            .labels
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect(),
+        platform: spec.platform.unwrap_or_default(),
 
         ..Default::default()
     };
+    let build_query = BuildQuery {
+        options,
+        target: spec.target,
+    };
+
+    let ignore = match opt.ignore.take() {
+        Some(ignore) => ignore.into(),
+        None => ignore_from_dockerignore(&source_path, dockerfile)
+            .map_err(|e| BuildError::FileTransferError(e.to_string()))?,
+    };
+
+    let cache_tag = if opt.cache {
+        let digest = digest_packed_tar(&source_path, ignore.clone(), opt.symlinks)
+            .with_cancel(opt.cancellation.cancelled())
+            .await
+            .ok_or(BuildError::Cancelled)?
+            .map_err(|e| BuildError::FileTransferError(e.to_string()))?;
+        let key = build_cache_key(&digest, &build_query, opt.cache_salt.as_deref());
+        let cache_tag = format!("{}:{}", BUILD_CACHE_REPO, key);
+        if let Ok(cached) = docker.inspect_image(&cache_tag).await {
+            tracing::debug!(%cache_tag, "Reusing cached image");
+            // The cached build may be evicted in between, and is built again
+            match tag_image(&docker, &cache_tag, &opt.tag_as).await {
+                Ok(()) => {
+                    events.send(BuildEvent::CacheHit { step: None });
+                    events.send(BuildEvent::Done {
+                        image_id: Some(cached.id),
+                    });
+                    return Ok(BuildImageResult { cache_hit: true });
+                }
+                Err(e) => tracing::warn!(%cache_tag, "Failed to reuse cached image: {}", e),
+            }
+        }
+        Some(cache_tag)
+    } else {
+        None
+    };
 
-    let (tar, join_tar) = pack_as_tar(
-        &source_path,
-        opt.ignore.take().unwrap_or_else(Gitignore::empty),
-    )
-    .map_err(|e| BuildError::FileTransferError(e.to_string()))?;
+    let (tar, join_tar) = pack_as_tar(&source_path, ignore, opt.symlinks)
+        .map_err(|e| BuildError::FileTransferError(e.to_string()))?;
 
     let timeout_future = match opt.timeout {
         Some(duration) => tokio::time::sleep(duration).left_future(),
@@ -167,40 +631,60 @@ async fn build_image_from_dockerfile(
     }
     .fuse();
     tokio::pin!(timeout_future);
-    let mut res = docker.build_image(build_options, None, Some(Body::wrap_stream(tar)));
-
-    while let Some(info) = tokio::select! {
-        res = res.next().with_cancel(opt.cancellation.cancelled()).map(|f| f.flatten())=> res,
-        _timeout = timeout_future.as_mut() => None
-    } {
-        match info {
-            Ok(info) => {
-                if let Some(e) = info.error {
-                    return Err(BuildError::BuildError {
-                        error: e,
-                        detail: info.error_detail,
-                    });
-                }
-                if let Some(stream) = &info.stream {
-                    tracing::debug!(stdout = %stream, "building");
-                }
-                opt.send_result(|| info);
-            }
-            Err(e) => {
-                let is_recoverable = is_recoverable_error(&e);
-                opt.send_result(|| {
-                    let e = format!("*** Internal error when building image: {:?}", e);
-                    BuildInfo {
-                        error: e.into(),
-                        ..Default::default()
+    let credentials = (!opt.registry_credentials.is_empty()).then(|| {
+        opt.registry_credentials
+            .iter()
+            .map(|(registry, c)| (registry.clone(), c.to_docker_credentials(registry)))
+            .collect()
+    });
+    let mut res = start_build(&build_query, credentials, Body::wrap_stream(tar));
+    let mut parser = BuildOutputParser::default();
+
+    let build_res = async {
+        while let Some(info) = tokio::select! {
+            res = res.next().with_cancel(opt.cancellation.cancelled()).map(|f| f.flatten())=> res,
+            _timeout = timeout_future.as_mut() => None
+        } {
+            match info {
+                Ok(info) => {
+                    if let Some(stream) = &info.stream {
+                        tracing::debug!(stdout = %stream, "building");
                     }
-                });
+                    let error = info.error.clone().map(|e| (e, info.error_detail.clone()));
+                    for event in parser.parse(info) {
+                        events.send(event);
+                    }
+                    if let Some((error, detail)) = error {
+                        return Err(BuildError::BuildError { error, detail });
+                    }
+                }
+                Err(e) => {
+                    let is_recoverable = is_recoverable_error(&e);
+                    events.send(BuildEvent::Error {
+                        step: parser.step(),
+                        message: format!("*** Internal error when building image: {:?}", e),
+                    });
 
-                if !is_recoverable {
-                    return Err(BuildError::Internal(e.into()));
+                    if !is_recoverable {
+                        return Err(BuildError::Internal(e.into()));
+                    }
                 }
             }
         }
+        if let Some(event) = parser.finish() {
+            events.send(event);
+        }
+        Ok(())
+    }
+    .await;
+    drop(res);
+    if let Err(e) = build_res {
+        // A failed packing task only shows up as a truncated build context,
+        // so its error is more useful
+        if let Ok(Err(tar_err)) = join_tar.await {
+            return Err(BuildError::FileTransferError(tar_err.to_string()));
+        }
+        return Err(e);
     }
 
     if timeout_future.is_terminated() {
@@ -210,7 +694,6 @@ async fn build_image_from_dockerfile(
         return Err(BuildError::Cancelled);
     }
 
-
     join_tar
         .await
         .map_err(|e| {
@@ -218,9 +701,79 @@ async fn build_image_from_dockerfile(
                 anyhow::Error::new(e).context("Internal panic when archiving files"),
             )
         })?
-        .map_err(|e| {
-            BuildError::Internal(anyhow::Error::new(e).context("Failed to archive files"))
+        .map_err(|e| BuildError::Internal(anyhow::Error::new(e).context("Failed to archive files")))
+        .map(|manifest| {
+            tracing::debug!(
+                files = manifest.entries.len(),
+                skipped = manifest.skipped.len(),
+                "Packed build context"
+            )
         })?;
 
-    Ok(BuildImageResult {})
+    if let Some(cache_tag) = cache_tag {
+        // A missing cache entry only costs a rebuild later
+        let _ = tag_image(&docker, &opt.tag_as, &cache_tag)
+            .await
+            .inspect_err(|e| tracing::warn!(%cache_tag, "Failed to cache built image: {}", e));
+    }
+
+    events.send(BuildEvent::Done {
+        image_id: parser.image_id().map(str::to_owned),
+    });
+    Ok(BuildImageResult { cache_hit: false })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_builds_to_evict() {
+        use chrono::TimeZone;
+
+        let build = |secs, size, tag: &str| CachedBuild {
+            last_used: chrono::Utc.timestamp(secs, 0),
+            size,
+            tags: vec![format!("{}:{}", BUILD_CACHE_REPO, tag)],
+        };
+        let builds = vec![
+            build(30, 100, "c"),
+            build(10, 300, "a"),
+            build(20, 200, "b"),
+        ];
+        let evicted = |limit| {
+            builds_to_evict(builds.clone(), limit)
+                .into_iter()
+                .map(|build| build.tags[0].rsplit(':').next().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(evicted(600), Vec::<String>::new());
+        assert_eq!(evicted(599), vec!["a"]);
+        assert_eq!(evicted(300), vec!["a"]);
+        assert_eq!(evicted(299), vec!["a", "b"]);
+        assert_eq!(evicted(0), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_build_query() {
+        let query = BuildQuery {
+            options: bollard::image::BuildImageOptions {
+                dockerfile: "Dockerfile",
+                t: "rurikawa/job",
+                buildargs: [("CI", "true")].into(),
+                ..Default::default()
+            },
+            target: Some("build"),
+        };
+        let query = serde_urlencoded::to_string(&query).unwrap();
+        let pairs = url::form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect::<HashMap<_, _>>();
+        assert_eq!(pairs["dockerfile"], "Dockerfile");
+        assert_eq!(pairs["t"], "rurikawa/job");
+        assert_eq!(pairs["buildargs"], r#"{"CI":"true"}"#);
+        assert_eq!(pairs["target"], "build");
+        assert!(!pairs.contains_key("memory"));
+    }
 }
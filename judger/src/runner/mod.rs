@@ -7,6 +7,7 @@ use std::borrow::Cow;
 
 use once_cell::sync::Lazy;
 use tokio::sync::mpsc::UnboundedSender;
+use tracing::Instrument;
 
 use crate::prelude::CancelFutureExt;
 use crate::tester::{
@@ -16,9 +17,11 @@ use crate::tester::{
 
 use self::model::{ExitStatus, OutputComparisonSource, ProcessOutput};
 
+pub mod build_event;
 pub mod exec;
 pub mod image;
 pub mod model;
+pub mod network;
 mod util;
 pub mod volume;
 
@@ -71,6 +74,7 @@ pub async fn run_exec_group(
                 opt,
             )
             .with_cancel(opt.cancel.cancelled())
+            .instrument(tracing::info_span!("exec_step", command = %exec.run))
             .await
         {
             None => return Ok(Err(JobFailure::Cancelled)),
@@ -84,6 +88,10 @@ pub async fn run_exec_group(
         let ret_code = run_res.ret_code.clone();
         if ret_code != ExitStatus::ReturnCode(0) {
             tracing::debug!(?ret_code, "Return code check failed");
+            let disk_full = group.run_in.is_disk_full().await.unwrap_or_else(|e| {
+                tracing::warn!("Failed to measure disk usage: {}", e);
+                false
+            });
             sink.send(run_res)?;
 
             if ret_code == ExitStatus::Timeout {
@@ -91,6 +99,11 @@ pub async fn run_exec_group(
                     command: exec.run.clone(),
                     kind: ExecErrorKind::TimedOut,
                 })));
+            } else if disk_full {
+                return Ok(Err(JobFailure::ExecError(ExecError {
+                    command: exec.run.clone(),
+                    kind: ExecErrorKind::DiskLimitExceeded,
+                })));
             } else if let ExitStatus::Signal(sig) = ret_code {
                 return Ok(Err(JobFailure::ExecError(ExecError {
                     command: exec.run.clone(),
@@ -136,8 +149,10 @@ pub async fn verify_output(
         OutputComparisonSource::InMemory(s) => s.into(),
     };
 
+    let stdout = output.full_stdout().await?;
+
     let expected = EOF_PATTERN.replace_all(expected.trim(), "\n");
-    let stdout = EOF_PATTERN.replace_all(output.stdout.trim(), "\n");
+    let stdout = EOF_PATTERN.replace_all(stdout.trim(), "\n");
 
     let diff = diff(&stdout, &expected);
     if diff.0 {
@@ -16,6 +16,31 @@ pub struct ProcessOutput {
     pub stderr: String,
 
     pub runned_inside: String,
+
+    /// The file holding the full standard output, if it was too large to be
+    /// kept in memory. `stdout` only contains its beginning in this case.
+    #[serde(skip)]
+    #[quickjs(skip)]
+    pub stdout_file: Option<PathBuf>,
+
+    /// The file holding the full standard error, see `stdout_file`.
+    #[serde(skip)]
+    #[quickjs(skip)]
+    pub stderr_file: Option<PathBuf>,
+}
+
+impl ProcessOutput {
+    /// Read the full standard output of this process, including the part that
+    /// was spilled into a file.
+    pub async fn full_stdout(&self) -> std::io::Result<std::borrow::Cow<'_, str>> {
+        match &self.stdout_file {
+            Some(path) => {
+                let bytes = tokio::fs::read(path).await?;
+                Ok(String::from_utf8_lossy(&bytes).into_owned().into())
+            }
+            None => Ok(self.stdout.as_str().into()),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, IntoJsByRef)]
@@ -103,17 +128,36 @@ pub trait CommandRunner: Sync + Send {
         env: &mut (dyn Iterator<Item = (&str, &str)> + Send),
         opt: &CommandRunOptions,
     ) -> anyhow::Result<ProcessOutput>;
+
+    /// Whether the disk space this runner may write has been used up, e.g. to
+    /// tell why a command has failed.
+    async fn is_disk_full(&self) -> anyhow::Result<bool> {
+        Ok(false)
+    }
 }
 
+/// The default size limit of captured stdout and stderr, in bytes.
+pub const DEFAULT_OUTPUT_SIZE_LIMIT: usize = 100 * 1024;
+
 #[derive(Debug, Default, Builder)]
 #[builder(setter(into), pattern = "owned")]
 pub struct CommandRunOptions {
-    #[builder(default = "100*1024")]
+    #[builder(default = "DEFAULT_OUTPUT_SIZE_LIMIT")]
     pub stdout_size_limit: usize,
 
-    #[builder(default = "100*1024")]
+    #[builder(default = "DEFAULT_OUTPUT_SIZE_LIMIT")]
     pub stderr_size_limit: usize,
 
+    /// Output larger than this size is written into a file inside
+    /// `spill_folder` instead of being kept in memory. Only takes effect when
+    /// `spill_folder` is set.
+    #[builder(default = "DEFAULT_OUTPUT_SIZE_LIMIT")]
+    pub in_memory_size_limit: usize,
+
+    /// The folder to put oversized outputs in.
+    #[builder(default)]
+    pub spill_folder: Option<PathBuf>,
+
     #[builder(default)]
     pub timeout: Option<Duration>,
 
@@ -0,0 +1,61 @@
+//! Private networks shared by containers of the same job
+
+use async_trait::async_trait;
+use bollard::{network::CreateNetworkOptions, Docker};
+use drop_bomb::DropBomb;
+
+pub struct Network {
+    docker: Docker,
+    name: String,
+
+    _drop_bomb: DropBomb,
+}
+
+impl Network {
+    /// Create an internal network, where containers can reach each other but
+    /// not the outside world.
+    pub async fn create_internal(
+        docker: Docker,
+        name: String,
+    ) -> Result<Self, bollard::errors::Error> {
+        tracing::debug!(%name, "Creating network");
+        docker
+            .create_network(CreateNetworkOptions {
+                name: name.as_str(),
+                check_duplicate: true,
+                driver: "bridge",
+                internal: true,
+                ..Default::default()
+            })
+            .await?;
+
+        Ok(Self {
+            docker,
+            name,
+
+            _drop_bomb: DropBomb::new("`Network::teardown()` must be called before dropping!"),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub async fn remove(&mut self) -> Result<(), bollard::errors::Error> {
+        tracing::debug!(%self.name, "Removing network");
+
+        // Defuse the teardown drop bomb.
+        self._drop_bomb.defuse();
+
+        self.docker.remove_network(&self.name).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl crate::util::AsyncTeardown for Network {
+    async fn teardown(&mut self) {
+        let _ = self.remove().await;
+    }
+}
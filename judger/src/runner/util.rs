@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 pub fn is_recoverable_error(e: &bollard::errors::Error) -> bool {
     matches!(
         &e,
@@ -8,3 +10,29 @@ pub fn is_recoverable_error(e: &bollard::errors::Error) -> bool {
             | bollard::errors::Error::URLEncodedError { .. }
     )
 }
+
+/// Whether `e` means the storage driver cannot limit the size of a container or
+/// a volume, e.g. overlay2 on a filesystem without project quotas.
+pub fn is_storage_quota_unsupported(e: &bollard::errors::Error) -> bool {
+    let message = match e {
+        bollard::errors::Error::DockerResponseServerError { message, .. }
+        | bollard::errors::Error::DockerResponseBadParameterError { message } => message,
+        _ => return false,
+    };
+    let message = message.to_lowercase();
+    ["storage-opt", "storage opt", "quota", "invalid option"]
+        .iter()
+        .any(|x| message.contains(x))
+}
+
+/// Options of the `local` volume driver for a tmpfs-backed volume of at most
+/// `size` bytes, for when the storage driver can't limit the size of volumes.
+/// The contents of such a volume are lost once no container mounts it.
+pub fn tmpfs_volume_options(size: u64) -> HashMap<String, String> {
+    [
+        ("type".to_owned(), "tmpfs".to_owned()),
+        ("device".to_owned(), "tmpfs".to_owned()),
+        ("o".to_owned(), format!("size={}", size)),
+    ]
+    .into()
+}
@@ -2,7 +2,10 @@
 
 use std::path::Path;
 
-use crate::util::tar::pack_as_tar;
+use crate::{
+    runner::util::{is_storage_quota_unsupported, tmpfs_volume_options},
+    util::tar::{pack_as_tar, PackedEntryKind, SymlinkPolicy},
+};
 use async_trait::async_trait;
 use bollard::{
     container::{RemoveContainerOptions, UploadToContainerOptions},
@@ -18,24 +21,66 @@ use ignore::gitignore::Gitignore;
 pub struct Volume {
     docker: Docker,
     volume: bollard::models::Volume,
+    size_limit: Option<u64>,
+    /// A container keeping a tmpfs-backed volume mounted, since its contents
+    /// are lost once no container mounts it.
+    holder: Option<String>,
 
     _drop_bomb: DropBomb,
 }
 
 impl Volume {
-    pub async fn create(docker: Docker, name: String) -> Result<Self, bollard::errors::Error> {
-        tracing::debug!(%name, "Creating volume");
-        let vol_res = docker
-            .create_volume(CreateVolumeOptions {
-                name: name.as_str(),
-                driver: "local",
-                ..Default::default()
-            })
-            .await?;
+    /// Create a volume named `name`. If `size_limit` is set, the volume is
+    /// created with a size quota, or backed by tmpfs of that size where the
+    /// storage doesn't support quotas. Files larger than the limit are never
+    /// copied into it.
+    pub async fn create(
+        docker: Docker,
+        name: String,
+        size_limit: Option<u64>,
+    ) -> Result<Self, bollard::errors::Error> {
+        tracing::debug!(%name, ?size_limit, "Creating volume");
+        let options = |driver_opts| CreateVolumeOptions {
+            name: name.clone(),
+            driver: "local".into(),
+            driver_opts,
+            ..Default::default()
+        };
+        let quota = size_limit
+            .map(|size| [("size".to_owned(), size.to_string())].into())
+            .unwrap_or_default();
+        let mut tmpfs_backed = false;
+        let vol_res = match docker.create_volume(options(quota)).await {
+            Err(e) if size_limit.is_some() && is_storage_quota_unsupported(&e) => {
+                tracing::warn!(%name, "Volume storage has no size quota support, using tmpfs instead: {}", e);
+                tmpfs_backed = true;
+                let size = size_limit.unwrap();
+                docker
+                    .create_volume(options(tmpfs_volume_options(size)))
+                    .await?
+            }
+            res => res?,
+        };
+
+        let holder = if tmpfs_backed {
+            match create_holder(&docker, &name).await {
+                Ok(holder) => Some(holder),
+                Err(e) => {
+                    let _ = docker
+                        .remove_volume(&name, Some(RemoveVolumeOptions { force: true }))
+                        .await;
+                    return Err(e);
+                }
+            }
+        } else {
+            None
+        };
 
         Ok(Self {
             docker,
             volume: vol_res,
+            size_limit,
+            holder,
 
             _drop_bomb: DropBomb::new("`Volume::teardown()` must be called before dropping!"),
         })
@@ -48,24 +93,27 @@ impl Volume {
     ) -> anyhow::Result<()> {
         tracing::debug!(?path, %self.volume.name, "Copying local files into volume");
 
-        let (stream, join) = pack_as_tar(path, ignore)?;
+        if let Some(limit) = self.size_limit {
+            let size = packed_size(path, ignore.clone()).await?;
+            if size > limit {
+                anyhow::bail!(
+                    "Files to copy take {} bytes, larger than the volume size limit of {} bytes",
+                    size,
+                    limit
+                );
+            }
+        }
 
-        // Ensure the dummy image is present.
-        // We use busybox as the dummy image here.
-        self.docker
-            .create_image(
-                Some(CreateImageOptions {
-                    from_image: "busybox",
-                    tag: "latest",
-                    ..Default::default()
-                }),
-                None,
-                None,
-            )
-            .map_ok(|_| ())
-            .try_collect::<()>()
-            .await?;
+        let (stream, join) = pack_as_tar(path, ignore, SymlinkPolicy::Preserve)?;
+
+        if let Some(holder) = &self.holder {
+            let res = upload_files(&self.docker, holder, stream).await;
+            let manifest = join.await??;
+            tracing::debug!(files = manifest.entries.len(), %self.volume.name, "Copied files into volume");
+            return res.map_err(|e| e.into());
+        }
 
+        ensure_dummy_image(&self.docker).await?;
         let mount = self.as_mount("/files/", false);
         let container = self
             .docker
@@ -83,17 +131,7 @@ impl Volume {
             )
             .await?;
 
-        let res = self
-            .docker
-            .upload_to_container(
-                &container.id,
-                Some(UploadToContainerOptions {
-                    path: "/files/",
-                    no_overwrite_dir_non_dir: "false",
-                }),
-                hyper::Body::wrap_stream(stream),
-            )
-            .await;
+        let res = upload_files(&self.docker, &container.id, stream).await;
 
         self.docker
             .remove_container(
@@ -105,7 +143,8 @@ impl Volume {
             )
             .await?;
 
-        join.await??;
+        let manifest = join.await??;
+        tracing::debug!(files = manifest.entries.len(), %self.volume.name, "Copied files into volume");
         res.map_err(|e| e.into())
     }
 
@@ -116,6 +155,17 @@ impl Volume {
         // It's not our fault if Docker blows up at this point (*/ω＼*)
         self._drop_bomb.defuse();
 
+        if let Some(holder) = self.holder.take() {
+            self.docker
+                .remove_container(
+                    &holder,
+                    Some(RemoveContainerOptions {
+                        force: true,
+                        ..Default::default()
+                    }),
+                )
+                .await?;
+        }
         self.docker
             .remove_volume(&self.volume.name, Some(RemoveVolumeOptions { force: true }))
             .await?;
@@ -140,3 +190,95 @@ impl crate::util::AsyncTeardown for Volume {
         let _ = self.remove().await;
     }
 }
+
+/// Ensure the dummy image is present. We use busybox as the dummy image here.
+async fn ensure_dummy_image(docker: &Docker) -> Result<(), bollard::errors::Error> {
+    docker
+        .create_image(
+            Some(CreateImageOptions {
+                from_image: "busybox",
+                tag: "latest",
+                ..Default::default()
+            }),
+            None,
+            None,
+        )
+        .map_ok(|_| ())
+        .try_collect::<()>()
+        .await
+}
+
+/// Start a container that keeps volume `name` mounted at `/files/` until it's
+/// removed.
+async fn create_holder(docker: &Docker, name: &str) -> Result<String, bollard::errors::Error> {
+    ensure_dummy_image(docker).await?;
+    let container = docker
+        .create_container::<String, String>(
+            None,
+            bollard::container::Config {
+                image: Some("busybox".into()),
+                cmd: Some(vec!["sleep".into(), "2147483647".into()]),
+                host_config: Some(bollard::service::HostConfig {
+                    mounts: Some(vec![Mount {
+                        target: Some("/files/".into()),
+                        source: Some(name.into()),
+                        typ: Some(MountTypeEnum::VOLUME),
+                        ..Default::default()
+                    }]),
+                    network_mode: Some("none".into()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        )
+        .await?;
+    if let Err(e) = docker.start_container::<String>(&container.id, None).await {
+        let _ = docker
+            .remove_container(
+                &container.id,
+                Some(RemoveContainerOptions {
+                    force: true,
+                    ..Default::default()
+                }),
+            )
+            .await;
+        return Err(e);
+    }
+    Ok(container.id)
+}
+
+/// Upload a tar archive into `/files/` of `container`.
+async fn upload_files(
+    docker: &Docker,
+    container: &str,
+    tar: impl futures::Stream<Item = std::io::Result<bytes::BytesMut>> + Send + 'static,
+) -> Result<(), bollard::errors::Error> {
+    docker
+        .upload_to_container(
+            container,
+            Some(UploadToContainerOptions {
+                path: "/files/",
+                no_overwrite_dir_non_dir: "false",
+            }),
+            hyper::Body::wrap_stream(tar),
+        )
+        .await
+}
+
+/// Total size of the files [`pack_as_tar`] packs from `path`, i.e. what ends up
+/// inside the volume.
+async fn packed_size(path: &Path, ignore: Gitignore) -> std::io::Result<u64> {
+    let (stream, join) = pack_as_tar(path, ignore, SymlinkPolicy::Preserve)?;
+    stream
+        .try_for_each(|_| futures::future::ready(Ok(())))
+        .await?;
+    let manifest = join.await.map_err(std::io::Error::other)??;
+    Ok(manifest
+        .entries
+        .iter()
+        .map(|entry| match entry.kind {
+            PackedEntryKind::File { size, .. } => size,
+            PackedEntryKind::Symlink { .. } => 0,
+        })
+        .sum())
+}
@@ -0,0 +1,100 @@
+//! Tests to verify that the control socket of [`crate::client::control`]
+//! answers requests.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::Instant;
+
+use futures::FutureExt;
+use test_env_log::test;
+
+use crate::client::config::{ClientConfig, RunningJobInfo, SharedClientData};
+use crate::client::control::{
+    send_control_request, serve_control, ControlRequest, ControlResponse, ReloadConfig,
+};
+use crate::client::model::JobStage;
+use crate::prelude::FlowSnake;
+
+use super::util::TempDir;
+
+#[test(tokio::test)]
+async fn test_control_socket() {
+    let folder = TempDir::new();
+    let cfg = Arc::new(
+        SharedClientData::new(ClientConfig {
+            cache_folder: folder.to_path_buf(),
+            max_concurrent_tasks: 2,
+            ..Default::default()
+        })
+        .unwrap(),
+    );
+    let reloads = Arc::new(AtomicUsize::new(0));
+    let reload: ReloadConfig = Arc::new({
+        let reloads = reloads.clone();
+        move || {
+            reloads.fetch_add(1, Ordering::SeqCst);
+            async { Ok(()) }.boxed()
+        }
+    });
+    let path = cfg.cfg().control_socket_path();
+    let server = tokio::spawn(serve_control(path.clone(), cfg.clone(), reload));
+    while tokio::fs::metadata(&path).await.is_err() {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    let job_id = FlowSnake::generate();
+    cfg.running_job_info.insert(
+        job_id,
+        RunningJobInfo {
+            stage: JobStage::Dispatched,
+            received_at: Instant::now(),
+        },
+    );
+    cfg.set_job_stage(job_id, JobStage::Compiling);
+    let status = match send_control_request(&path, &ControlRequest::Status).await {
+        Ok(ControlResponse::Status(status)) => status,
+        res => panic!("Unexpected response: {:?}", res),
+    };
+    assert_eq!(status.max_concurrent_tasks, 2);
+    assert!(!status.polling_paused);
+    assert_eq!(status.jobs.len(), 1);
+    assert_eq!(status.jobs[0].id, job_id);
+    assert!(matches!(status.jobs[0].stage, JobStage::Compiling));
+
+    let res = send_control_request(&path, &ControlRequest::Cancel { job_id })
+        .await
+        .unwrap();
+    assert!(matches!(res, ControlResponse::Error { .. }), "{:?}", res);
+
+    send_control_request(&path, &ControlRequest::Pause)
+        .await
+        .unwrap();
+    assert!(!cfg.accepts_new_jobs());
+    send_control_request(&path, &ControlRequest::Resume)
+        .await
+        .unwrap();
+    assert!(cfg.accepts_new_jobs());
+
+    send_control_request(&path, &ControlRequest::Reload)
+        .await
+        .unwrap();
+    assert_eq!(reloads.load(Ordering::SeqCst), 1);
+
+    // Nothing is running, so draining exits once the request for new jobs
+    // is answered
+    cfg.waiting_for_jobs
+        .store(Some(Arc::new(FlowSnake::generate())));
+    let res = send_control_request(&path, &ControlRequest::Drain)
+        .await
+        .unwrap();
+    assert!(matches!(res, ControlResponse::Done));
+    assert!(!cfg.accepts_new_jobs());
+    tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+    assert!(!cfg.abort_handle.is_cancelled());
+    cfg.waiting_for_jobs.store(None);
+    server.await.unwrap().unwrap();
+    assert!(cfg.abort_handle.is_cancelled());
+    assert!(tokio::fs::metadata(&path).await.is_err());
+}
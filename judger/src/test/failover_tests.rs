@@ -0,0 +1,73 @@
+//! Tests to verify that [`select_coordinator`] fails over by the status of
+//! each coordinator, without registering again.
+
+use std::convert::Infallible;
+
+use hyper::{service::service_fn, Body, Request, Response, StatusCode};
+
+use crate::client::{
+    config::{ClientConfig, SharedClientData},
+    select_coordinator,
+};
+
+/// A stand-in coordinator answering every request with `status`. Returns its
+/// host.
+async fn serve_status(status: StatusCode) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let host = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let service = service_fn(move |req: Request<Body>| async move {
+                    assert_eq!(req.uri().path(), "/api/v1/judger/verify");
+                    let mut res = Response::new(Body::empty());
+                    *res.status_mut() = status;
+                    Ok::<_, Infallible>(res)
+                });
+                let _ = hyper::server::conn::Http::new()
+                    .serve_connection(stream, service)
+                    .await;
+            });
+        }
+    });
+    host
+}
+
+fn client_data(host: String, fallback_hosts: Vec<String>) -> SharedClientData {
+    SharedClientData::new(ClientConfig {
+        host,
+        fallback_hosts,
+        access_token: Some("token".into()),
+        register_token: Some("register".into()),
+        ..Default::default()
+    })
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_select_coordinator() {
+    let unavailable = serve_status(StatusCode::BAD_GATEWAY).await;
+    let rejecting = serve_status(StatusCode::UNAUTHORIZED).await;
+    let accepting = serve_status(StatusCode::OK).await;
+
+    let data = client_data(unavailable.clone(), vec![accepting.clone()]);
+    assert!(select_coordinator(&data).await.unwrap());
+    assert_eq!(data.host(), accepting);
+    assert_eq!(data.access_token().as_deref(), Some("token"));
+
+    // A coordinator that can't tell doesn't count as rejecting
+    let data = client_data(unavailable.clone(), vec![]);
+    assert!(select_coordinator(&data).await.is_err());
+    assert_eq!(data.host(), unavailable);
+
+    let data = client_data(unavailable, vec![rejecting.clone()]);
+    assert!(!select_coordinator(&data).await.unwrap());
+    assert_eq!(data.host_at(1), rejecting);
+    // Nothing is registered, and the active coordinator is kept
+    assert_eq!(
+        data.active_host.load(std::sync::atomic::Ordering::SeqCst),
+        0
+    );
+    assert_eq!(data.access_token().as_deref(), Some("token"));
+}
@@ -0,0 +1,191 @@
+//! Tests to verify that [`crate::fs`] functions behave correctly.
+//!
+//! Git tests in this module need `git` to be present in `PATH`.
+
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+use test_env_log::test;
+use tokio::process::Command;
+
+use crate::fs::net::{
+    download_unzip, git_cache_is_corrupt, git_clone_cached, GitAuth, GitCloneOptions,
+};
+use crate::fs::{hash_dir, stamp_dir};
+
+use super::util::TempDir;
+
+async fn git(dir: &Path, args: &[&str]) {
+    let output = Command::new("git")
+        .current_dir(dir)
+        .args([
+            "-c",
+            "user.name=rurikawa",
+            "-c",
+            "user.email=rurikawa@example.com",
+        ])
+        .args(args)
+        .output()
+        .await
+        .expect("Failed to run git");
+    assert!(
+        output.status.success(),
+        "git {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+async fn commit_file(repo: &Path, name: &str, content: &str) {
+    tokio::fs::write(repo.join(name), content).await.unwrap();
+    git(repo, &["add", "-A"]).await;
+    git(repo, &["commit", "-m", name]).await;
+}
+
+#[test(tokio::test)]
+async fn test_git_clone_cached() {
+    let root = TempDir::new();
+    let remote = root.join("remote");
+    let cache = root.join("cache");
+    tokio::fs::create_dir_all(&remote).await.unwrap();
+    git(&remote, &["init", "-b", "main"]).await;
+    commit_file(&remote, "judge.toml", "first").await;
+
+    let options = || GitCloneOptions {
+        repo: remote.to_string_lossy().into_owned(),
+        revision: "main".into(),
+        depth: 3,
+    };
+
+    git_clone_cached(&cache, &root.join("job1"), options(), &GitAuth::default())
+        .await
+        .expect("Failed to clone into empty cache");
+    let content = tokio::fs::read_to_string(root.join("job1/judge.toml"))
+        .await
+        .unwrap();
+    assert_eq!(content, "first");
+
+    commit_file(&remote, "judge.toml", "second").await;
+    git_clone_cached(&cache, &root.join("job2"), options(), &GitAuth::default())
+        .await
+        .expect("Failed to clone from existing cache");
+    let content = tokio::fs::read_to_string(root.join("job2/judge.toml"))
+        .await
+        .unwrap();
+    assert_eq!(content, "second");
+    assert!(!git_cache_is_corrupt(&cache).await);
+
+    tokio::fs::remove_file(cache.join("HEAD")).await.unwrap();
+    assert!(git_cache_is_corrupt(&cache).await);
+    assert!(!git_cache_is_corrupt(&root.join("missing")).await);
+}
+
+/// Serve `body` once over HTTP on a local port, returning its URL.
+async fn serve_once(body: Vec<u8>) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 4096];
+        let _ = socket.read(&mut buf).await.unwrap();
+        let header = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        socket.write_all(header.as_bytes()).await.unwrap();
+        socket.write_all(&body).await.unwrap();
+        socket.shutdown().await.unwrap();
+    });
+    format!("http://{}/suite.tar", addr)
+}
+
+async fn suite_tar() -> Vec<u8> {
+    let mut builder = tokio_tar::Builder::new(Vec::new());
+    let mut header = tokio_tar::Header::new_gnu();
+    header.set_size(2);
+    header.set_mode(0o644);
+    builder
+        .append_data(&mut header, "testconf.json", &b"{}"[..])
+        .await
+        .unwrap();
+    builder.into_inner().await.unwrap()
+}
+
+#[test(tokio::test)]
+async fn test_download_unzip_hash() {
+    let folder = TempDir::new();
+    let tar = suite_tar().await;
+    let expected = hex::encode(Sha256::digest(&tar));
+    let client = reqwest::Client::new();
+
+    let url = serve_once(tar.clone()).await;
+    let hash = download_unzip(
+        client.clone(),
+        client.get(&url).build().unwrap(),
+        &folder.join("ok"),
+        Some(&expected.to_uppercase()),
+    )
+    .await
+    .unwrap();
+    assert_eq!(hash, expected);
+    assert!(folder.join("ok/testconf.json").exists());
+
+    let url = serve_once(tar).await;
+    let res = download_unzip(
+        client.clone(),
+        client.get(&url).build().unwrap(),
+        &folder.join("bad"),
+        Some(&"0".repeat(64)),
+    )
+    .await;
+    assert!(res.is_err());
+}
+
+#[test(tokio::test)]
+async fn test_hash_dir() {
+    let folder = TempDir::new();
+    tokio::fs::create_dir_all(folder.join("data"))
+        .await
+        .unwrap();
+    tokio::fs::write(folder.join("testconf.json"), "{}")
+        .await
+        .unwrap();
+    tokio::fs::write(folder.join("data/1.in"), "1 2")
+        .await
+        .unwrap();
+
+    let hashes = hash_dir(&folder).await.unwrap();
+    assert_eq!(
+        hashes.keys().collect::<Vec<_>>(),
+        vec!["data/1.in", "testconf.json"]
+    );
+    assert_eq!(hash_dir(&folder).await.unwrap(), hashes);
+
+    tokio::fs::write(folder.join("data/1.in"), "1 3")
+        .await
+        .unwrap();
+    assert_ne!(hash_dir(&folder).await.unwrap(), hashes);
+}
+
+#[test(tokio::test)]
+async fn test_stamp_dir() {
+    let folder = TempDir::new();
+    tokio::fs::create_dir_all(folder.join("data"))
+        .await
+        .unwrap();
+    tokio::fs::write(folder.join("data/1.in"), "1 2")
+        .await
+        .unwrap();
+
+    let stamps = stamp_dir(&folder).await.unwrap();
+    assert_eq!(stamps.keys().collect::<Vec<_>>(), vec!["data/1.in"]);
+    assert_eq!(stamps["data/1.in"].size, 3);
+    assert_eq!(stamp_dir(&folder).await.unwrap(), stamps);
+
+    tokio::fs::write(folder.join("data/1.in"), "1 2 3")
+        .await
+        .unwrap();
+    assert_ne!(stamp_dir(&folder).await.unwrap(), stamps);
+}
@@ -1,3 +1,11 @@
+#[cfg(unix)]
+mod control_tests;
+mod failover_tests;
+mod fs_tests;
+mod registry_tests;
 mod runner_image;
 mod runner_tests;
-mod util;
+mod tar_tests;
+mod telemetry_tests;
+mod tls_tests;
+pub(crate) mod util;
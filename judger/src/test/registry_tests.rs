@@ -0,0 +1,359 @@
+//! Tests to verify that prebuilt images are pulled correctly.
+//!
+//! Pulling is done by the Docker daemon, so these tests talk to a stand-in of
+//! the daemon instead, which records the requests it receives.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use bollard::{auth::DockerCredentials, Docker};
+use test_env_log::test;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::config::Image;
+use crate::runner::build_event::BuildEvent;
+use crate::runner::image::{
+    build_image, BuildImageOptionsBuilder, ImageReference, RegistryCredential,
+};
+use crate::tester::model::{BuildError, PullPolicy};
+
+/// A request received by [`DaemonStandIn`].
+#[derive(Debug, Clone)]
+struct Request {
+    method: String,
+    /// The path without the API version prefix, e.g. `/images/create`.
+    path: String,
+    query: Vec<(String, String)>,
+    /// The decoded `X-Registry-Auth` header.
+    auth: Option<DockerCredentials>,
+}
+
+impl Request {
+    fn query(&self, key: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// A stand-in of the Docker daemon that serves image inspection, pulling and
+/// tagging against an in-memory set of images.
+struct DaemonStandIn {
+    docker: Docker,
+    requests: Arc<Mutex<Vec<Request>>>,
+    images: Arc<Mutex<HashSet<String>>>,
+}
+
+impl DaemonStandIn {
+    async fn start(images: &[&str]) -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let images = Arc::new(Mutex::new(
+            images.iter().map(|s| s.to_string()).collect::<HashSet<_>>(),
+        ));
+
+        tokio::spawn({
+            let requests = requests.clone();
+            let images = images.clone();
+            async move {
+                loop {
+                    let (mut socket, _) = listener.accept().await.unwrap();
+                    let req = read_request(&mut socket).await;
+                    let (status, body) = respond(&req, &mut images.lock().unwrap());
+                    requests.lock().unwrap().push(req);
+                    let header = format!(
+                        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        status,
+                        body.len()
+                    );
+                    socket.write_all(header.as_bytes()).await.unwrap();
+                    socket.write_all(body.as_bytes()).await.unwrap();
+                    socket.shutdown().await.unwrap();
+                }
+            }
+        });
+
+        let docker = Docker::connect_with_http(
+            &format!("http://{}", addr),
+            10,
+            bollard::API_DEFAULT_VERSION,
+        )
+        .unwrap();
+        DaemonStandIn {
+            docker,
+            requests,
+            images,
+        }
+    }
+
+    fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn pulls(&self) -> Vec<Request> {
+        self.requests()
+            .into_iter()
+            .filter(|r| r.path == "/images/create")
+            .collect()
+    }
+
+    fn has_image(&self, image: &str) -> bool {
+        self.images.lock().unwrap().contains(image)
+    }
+}
+
+async fn read_request(socket: &mut tokio::net::TcpStream) -> Request {
+    let mut buf = vec![];
+    let mut chunk = [0u8; 4096];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = socket.read(&mut chunk).await.unwrap();
+        assert!(n > 0, "connection closed before the request ends");
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let head = String::from_utf8_lossy(&buf).into_owned();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap().split(' ');
+    let method = request_line.next().unwrap().to_owned();
+    let url =
+        url::Url::parse(&format!("http://localhost{}", request_line.next().unwrap())).unwrap();
+    let auth = lines
+        .filter_map(|l| l.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("x-registry-auth"))
+        .map(|(_, value)| {
+            let json = base64::decode_config(value.trim(), base64::URL_SAFE).unwrap();
+            serde_json::from_slice(&json).unwrap()
+        });
+    Request {
+        method,
+        path: url.path().to_owned(),
+        query: url.query_pairs().into_owned().collect(),
+        auth,
+    }
+}
+
+fn respond(req: &Request, images: &mut HashSet<String>) -> (&'static str, String) {
+    if req.method == "POST" && req.path == "/images/create" {
+        let from_image = req.query("fromImage").unwrap();
+        let tag = req.query("tag").unwrap();
+        let separator = if tag.starts_with("sha256:") { '@' } else { ':' };
+        images.insert(format!("{}{}{}", from_image, separator, tag));
+        return ("200 OK", r#"{"status":"Downloaded newer image"}"#.into());
+    }
+    if let Some(name) = req.path.strip_prefix("/images/") {
+        if let (Some(name), "GET") = (name.strip_suffix("/json"), req.method.as_str()) {
+            return if images.contains(name) {
+                let image = bollard::models::Image {
+                    id: format!("sha256:{}", name.len()),
+                    ..Default::default()
+                };
+                ("200 OK", serde_json::to_string(&image).unwrap())
+            } else {
+                ("404 Not Found", r#"{"message":"No such image"}"#.into())
+            };
+        }
+        if let (Some(name), "POST") = (name.strip_suffix("/tag"), req.method.as_str()) {
+            if !images.contains(name) {
+                return ("404 Not Found", r#"{"message":"No such image"}"#.into());
+            }
+            images.insert(format!(
+                "{}:{}",
+                req.query("repo").unwrap(),
+                req.query("tag").unwrap()
+            ));
+            return ("201 Created", String::new());
+        }
+    }
+    ("404 Not Found", r#"{"message":"page not found"}"#.into())
+}
+
+async fn pull(
+    daemon: &DaemonStandIn,
+    tag: &str,
+    pull: PullPolicy,
+    tag_as: &str,
+) -> Result<(), BuildError> {
+    let credentials: HashMap<_, _> = [(
+        "localhost:5000".to_owned(),
+        RegistryCredential::Password {
+            username: "judger".into(),
+            password: "secret".into(),
+        },
+    )]
+    .into();
+    let opt = BuildImageOptionsBuilder::default()
+        .base_path(".")
+        .tag_as(tag_as)
+        .registry_credentials(credentials)
+        .build()
+        .unwrap();
+    let image = Image::Prebuilt {
+        tag: tag.into(),
+        pull,
+    };
+    build_image(daemon.docker.clone(), &image, opt)
+        .await
+        .map(|_| ())
+}
+
+#[test]
+fn test_parse_image_reference() {
+    let parse = ImageReference::parse;
+    assert_eq!(
+        parse("gcc"),
+        ImageReference {
+            registry: "docker.io",
+            repository: "gcc",
+            tag: None,
+            digest: None
+        }
+    );
+    assert_eq!(parse("library/gcc:11").registry, "docker.io");
+    assert_eq!(parse("library/gcc:11").tag, Some("11"));
+    assert_eq!(parse("gcc:11").to_string(), "gcc:11");
+    assert_eq!(parse("gcc").to_string(), "gcc:latest");
+
+    let local = parse("localhost:5000/foo/bar");
+    assert_eq!(local.registry, "localhost:5000");
+    assert_eq!(local.repository, "localhost:5000/foo/bar");
+    assert_eq!(local.tag, None);
+    assert_eq!(parse("ghcr.io/foo/bar:1.0").registry, "ghcr.io");
+
+    let digest = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let pinned_str = format!("localhost:5000/foo@{}", digest);
+    let pinned = parse(&pinned_str);
+    assert_eq!(pinned.repository, "localhost:5000/foo");
+    assert_eq!(pinned.tag, None);
+    assert_eq!(pinned.digest, Some(digest));
+    assert_eq!(pinned.tag_or_digest(), digest);
+    assert_eq!(pinned.to_string(), pinned_str);
+
+    let both_str = format!("alpine:3.14@{}", digest);
+    let both = parse(&both_str);
+    assert_eq!(both.tag, Some("3.14"));
+    assert_eq!(both.tag_or_digest(), digest);
+}
+
+#[test(tokio::test)]
+async fn test_pull_with_credentials() {
+    let daemon = DaemonStandIn::start(&[]).await;
+    pull(
+        &daemon,
+        "localhost:5000/suite/gcc:11",
+        PullPolicy::IfNotPresent,
+        "rurikawa/job",
+    )
+    .await
+    .unwrap();
+    pull(&daemon, "alpine", PullPolicy::IfNotPresent, "rurikawa/job2")
+        .await
+        .unwrap();
+
+    let pulls = daemon.pulls();
+    assert_eq!(pulls.len(), 2);
+    assert_eq!(
+        pulls[0].query("fromImage"),
+        Some("localhost:5000/suite/gcc")
+    );
+    assert_eq!(pulls[0].query("tag"), Some("11"));
+    let auth = pulls[0].auth.as_ref().unwrap();
+    assert_eq!(auth.username.as_deref(), Some("judger"));
+    assert_eq!(auth.password.as_deref(), Some("secret"));
+    assert_eq!(auth.serveraddress.as_deref(), Some("localhost:5000"));
+    // Credentials are only sent to their own registry
+    assert_eq!(pulls[1].query("fromImage"), Some("alpine"));
+    assert_eq!(pulls[1].query("tag"), Some("latest"));
+    assert_eq!(pulls[1].auth.as_ref().unwrap().username, None);
+
+    assert!(daemon.has_image("rurikawa/job:latest"));
+    assert!(daemon.has_image("rurikawa/job2:latest"));
+}
+
+#[test(tokio::test)]
+async fn test_pull_policy() {
+    let daemon = DaemonStandIn::start(&["gcc:11"]).await;
+
+    pull(&daemon, "gcc:11", PullPolicy::IfNotPresent, "gcc:11")
+        .await
+        .unwrap();
+    pull(&daemon, "gcc:11", PullPolicy::Never, "gcc:11")
+        .await
+        .unwrap();
+    assert!(daemon.pulls().is_empty());
+
+    pull(&daemon, "gcc:11", PullPolicy::Always, "gcc:11")
+        .await
+        .unwrap();
+    assert_eq!(daemon.pulls().len(), 1);
+
+    let err = pull(&daemon, "gcc:12", PullPolicy::Never, "gcc:12")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, BuildError::ImagePullFailure(_)));
+    assert_eq!(daemon.pulls().len(), 1);
+}
+
+#[test(tokio::test)]
+async fn test_pull_digest_pinned() {
+    let daemon = DaemonStandIn::start(&[]).await;
+    let digest = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    pull(
+        &daemon,
+        &format!("localhost:5000/suite/gcc@{}", digest),
+        PullPolicy::IfNotPresent,
+        "rurikawa/job",
+    )
+    .await
+    .unwrap();
+
+    let pulls = daemon.pulls();
+    assert_eq!(pulls.len(), 1);
+    assert_eq!(
+        pulls[0].query("fromImage"),
+        Some("localhost:5000/suite/gcc")
+    );
+    assert_eq!(pulls[0].query("tag"), Some(digest));
+    assert!(daemon.has_image(&format!("localhost:5000/suite/gcc@{}", digest)));
+    assert!(daemon.has_image("rurikawa/job:latest"));
+}
+
+#[test(tokio::test)]
+async fn test_pull_events() {
+    let daemon = DaemonStandIn::start(&[]).await;
+    let (send, mut recv) = tokio::sync::mpsc::unbounded_channel();
+    let opt = BuildImageOptionsBuilder::default()
+        .base_path(".")
+        .tag_as("rurikawa/job")
+        .build_result_channel(send)
+        .build()
+        .unwrap();
+    let image = Image::Prebuilt {
+        tag: "alpine:3.14".into(),
+        pull: PullPolicy::IfNotPresent,
+    };
+    build_image(daemon.docker.clone(), &image, opt)
+        .await
+        .unwrap();
+
+    let mut events = vec![];
+    while let Some(event) = recv.recv().await {
+        events.push(event);
+    }
+    assert_eq!(
+        events,
+        vec![
+            BuildEvent::Pull {
+                image: Some("alpine:3.14".into()),
+                layer: None,
+                status: "Downloaded newer image".into(),
+                current: None,
+                total: None,
+            },
+            BuildEvent::Done {
+                image_id: Some(format!("sha256:{}", "alpine:3.14".len())),
+            },
+        ]
+    );
+}
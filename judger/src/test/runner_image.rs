@@ -10,8 +10,12 @@ use test_env_log::test;
 use crate::config::Image;
 use crate::runner::exec::{Container, CreateContainerConfig};
 use crate::runner::image::BuildImageOptionsBuilder;
+use crate::runner::model::{CommandRunOptionsBuilder, ExitStatus};
+use crate::runner::network::Network;
+use crate::tester::model::ServiceDefinition;
+use crate::tester::start_service_container;
 
-use super::util::project_root_dir;
+use super::util::{project_root_dir, TempDir};
 
 #[test(tokio::test)]
 #[ignore]
@@ -42,11 +46,156 @@ async fn test_docker_container_creation() {
     let _ = docker.remove_image(image_name, None, None).await;
 }
 
+#[test(tokio::test)]
+#[ignore]
+async fn test_docker_image_build_cache() {
+    let (docker, image_name) = build_golem_image().await;
+
+    let folder = TempDir::new();
+    std::fs::write(
+        folder.join("Dockerfile"),
+        format!("FROM {}\nCOPY . /src\n", image_name),
+    )
+    .unwrap();
+    std::fs::write(folder.join("main.c"), "int main() {}").unwrap();
+
+    let image = Image::Dockerfile {
+        path: ".".into(),
+        file: None,
+        args: Default::default(),
+        target: None,
+        labels: Default::default(),
+        platform: None,
+    };
+    let build = |tag: &'static str| {
+        let opt = BuildImageOptionsBuilder::default()
+            .base_path(&*folder)
+            .tag_as(tag)
+            .cache(true)
+            .build()
+            .unwrap();
+        crate::runner::image::build_image(docker.clone(), &image, opt)
+    };
+
+    let first = build("rurikawa/test_build_cache_1").await.unwrap();
+    let second = build("rurikawa/test_build_cache_2").await.unwrap();
+    std::fs::write(folder.join("main.c"), "int main() { return 1; }").unwrap();
+    let third = build("rurikawa/test_build_cache_3").await.unwrap();
+
+    let first_id = docker
+        .inspect_image("rurikawa/test_build_cache_1")
+        .await
+        .unwrap()
+        .id;
+    let second_id = docker
+        .inspect_image("rurikawa/test_build_cache_2")
+        .await
+        .unwrap()
+        .id;
+
+    for tag in [
+        "rurikawa/test_build_cache_1",
+        "rurikawa/test_build_cache_2",
+        "rurikawa/test_build_cache_3",
+    ] {
+        let _ = docker.remove_image(tag, None, None).await;
+    }
+
+    assert!(!first.cache_hit);
+    assert!(second.cache_hit);
+    assert!(!third.cache_hit);
+    assert_eq!(first_id, second_id);
+}
+
+#[test(tokio::test)]
+#[ignore]
+async fn test_service_container_on_private_network() {
+    let (docker, image_name) = build_golem_image().await;
+
+    // The service is built upon the local image, so no outside network is needed
+    let folder = TempDir::new();
+    std::fs::write(
+        folder.join("Dockerfile"),
+        format!(
+            "FROM {}\nCMD [\"sh\", \"-c\", \"sleep 1 && touch /tmp/ready && sleep 600\"]\n",
+            image_name
+        ),
+    )
+    .unwrap();
+
+    let service = ServiceDefinition {
+        name: "db".into(),
+        image: Image::Dockerfile {
+            path: ".".into(),
+            file: None,
+            args: Default::default(),
+            target: None,
+            labels: Default::default(),
+            platform: None,
+        },
+        env: [("SERVICE_MODE".to_string(), "test".to_string())].into(),
+        ready_probe: Some("test -f /tmp/ready".into()),
+        ready_timeout: Some(30.0),
+    };
+
+    let mut network = Network::create_internal(docker.clone(), "rurikawa-test-services".into())
+        .await
+        .expect("Failed to create network");
+    let mut service_container = start_service_container(
+        docker.clone(),
+        &service,
+        &folder,
+        "test",
+        network.name(),
+        &Default::default(),
+        Default::default(),
+    )
+    .await
+    .expect("Failed to start service");
+
+    let cfg = CreateContainerConfig::builder()
+        .network(Some(network.name().to_owned()))
+        .build()
+        .expect("Failed to build create container config");
+    let mut container = Container::create(docker.clone(), image_name.to_string(), cfg)
+        .await
+        .expect("Failed to build container");
+
+    let opt = CommandRunOptionsBuilder::default().build().unwrap();
+    let env = service_container
+        .exec(
+            "test \"$SERVICE_MODE\" = test",
+            &mut std::iter::empty(),
+            &opt,
+        )
+        .await
+        .unwrap();
+    let resolve = container
+        .exec("getent hosts db", &mut std::iter::empty(), &opt)
+        .await
+        .unwrap();
+
+    container.remove().await.unwrap();
+    service_container.remove().await.unwrap();
+    network.remove().await.unwrap();
+    let _ = docker
+        .remove_image("test-service-db:test", None, None)
+        .await;
+    let _ = docker.remove_image(image_name, None, None).await;
+
+    assert_eq!(env.ret_code, ExitStatus::ReturnCode(0));
+    assert_eq!(resolve.ret_code, ExitStatus::ReturnCode(0));
+}
+
 async fn build_golem_image() -> (Docker, &'static str) {
     let docker = Docker::connect_with_local_defaults().expect("Failed to connect docker");
     let image = Image::Dockerfile {
         path: ".".into(),
         file: None,
+        args: Default::default(),
+        target: None,
+        labels: Default::default(),
+        platform: None,
     };
     let image_name = "rurikawa/test_suite_basic_image";
     let opt = BuildImageOptionsBuilder::default()
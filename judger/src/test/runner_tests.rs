@@ -153,3 +153,42 @@ async fn test_exec_timeout_error() {
         Err(e) => panic!("The test should fail with timeout, got {:?}", e),
     };
 }
+
+#[test(tokio::test)]
+async fn test_exec_disk_limit_error() {
+    let mut container = MockRunner::new();
+    container
+        .when("python ./golemc.py /src/succ.py -o /src/succ.pyc")
+        .returns(1)
+        .stderr("OSError: [Errno 28] No space left on device")
+        .finish();
+    container.disk_full();
+
+    match run_simple_test_with_mock_runner(container).await {
+        Ok(_) => panic!("The test should fail"),
+        Err(JobFailure::ExecError(ExecError {
+            kind: ExecErrorKind::DiskLimitExceeded,
+            ..
+        })) => {}
+        Err(e) => panic!("The test should fail with disk limit exceeded, got {:?}", e),
+    };
+}
+
+#[test(tokio::test)]
+async fn test_exec_disk_full_message_alone() {
+    let mut container = MockRunner::new();
+    container
+        .when("python ./golemc.py /src/succ.py -o /src/succ.pyc")
+        .returns(1)
+        .stderr("No space left on device")
+        .finish();
+
+    match run_simple_test_with_mock_runner(container).await {
+        Ok(_) => panic!("The test should fail"),
+        Err(JobFailure::ExecError(ExecError {
+            kind: ExecErrorKind::ReturnCodeCheckFailed,
+            ..
+        })) => {}
+        Err(e) => panic!("The test should fail the return code check, got {:?}", e),
+    };
+}
@@ -0,0 +1,296 @@
+//! Tests to verify that [`crate::util::tar`] functions behave correctly.
+
+use std::path::Path;
+
+use bytes::Bytes;
+use futures::StreamExt;
+use ignore::gitignore::Gitignore;
+use test_env_log::test;
+use tokio_util::io::StreamReader;
+
+use crate::{
+    test::util::{tar_with_files, TempDir},
+    util::tar::{
+        digest_packed_tar, ignore_from_dockerignore, ignore_from_file, pack_as_tar, PackIgnore,
+        PackManifest, PackedEntry, PackedEntryKind, SymlinkPolicy, PACKED_MTIME,
+    },
+};
+
+/// Unpack files with the given relative paths into a new temporary folder.
+async fn make_folder(files: &[(&str, &str)]) -> TempDir {
+    let folder = TempDir::new();
+    let files = files
+        .iter()
+        .map(|(name, content)| (name.to_string(), Bytes::copy_from_slice(content.as_bytes())))
+        .collect::<Vec<_>>();
+    let (stream, archiving) = tar_with_files(files.into_iter());
+    let mut archive = tokio_tar::Archive::new(StreamReader::new(stream));
+    archive.unpack(&*folder).await.unwrap();
+    archiving.await.unwrap();
+    folder
+}
+
+/// Pack `path`, returning the archive and the result of the packing task.
+async fn pack(
+    path: &Path,
+    ignore: impl Into<PackIgnore>,
+    symlinks: SymlinkPolicy,
+) -> (Vec<u8>, std::io::Result<PackManifest>) {
+    let (stream, join) = pack_as_tar(path, ignore, symlinks).unwrap();
+    let chunks = stream.collect::<Vec<_>>().await;
+    let manifest = join.await.unwrap();
+    let mut archive = vec![];
+    for chunk in chunks {
+        match chunk {
+            Ok(chunk) => archive.extend_from_slice(&chunk),
+            Err(_) => break,
+        }
+    }
+    (archive, manifest)
+}
+
+/// Headers of all entries in `archive`, keyed by path.
+async fn headers_of(archive: &[u8]) -> Vec<(String, tokio_tar::Header)> {
+    let mut archive = tokio_tar::Archive::new(archive);
+    let mut entries = archive.entries().unwrap();
+    let mut headers = vec![];
+    while let Some(entry) = entries.next().await {
+        let entry = entry.unwrap();
+        let path = entry.path().unwrap().to_string_lossy().replace('\\', "/");
+        headers.push((path, entry.header().clone()));
+    }
+    headers
+}
+
+/// Pack `path` and return the sorted names of all files in the archive.
+async fn packed_files(path: &Path, ignore: impl Into<PackIgnore>) -> Vec<String> {
+    let (archive, manifest) = pack(path, ignore, SymlinkPolicy::Preserve).await;
+    manifest.unwrap();
+    let mut names = headers_of(&archive)
+        .await
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[test(tokio::test)]
+async fn test_pack_dockerignore() {
+    let folder = make_folder(&[
+        (
+            ".dockerignore",
+            "# build outputs\ntarget\n/node_modules/\n!node_modules/keep.js\n*.log\nDockerfile\n",
+        ),
+        ("Dockerfile", "FROM alpine"),
+        ("src/main.rs", "fn main() {}"),
+        ("src/target/a.txt", "nested"),
+        ("target/debug/main", "binary"),
+        ("node_modules/left-pad/index.js", "pad"),
+        ("node_modules/keep.js", "keep"),
+        ("build.log", "log"),
+        ("src/build.log", "log"),
+    ])
+    .await;
+
+    let ignore = ignore_from_dockerignore(&folder, "Dockerfile").unwrap();
+    assert_eq!(
+        packed_files(&folder, ignore).await,
+        vec![
+            ".dockerignore",
+            "Dockerfile",
+            "node_modules/keep.js",
+            "src/build.log",
+            "src/main.rs",
+            "src/target/a.txt",
+        ]
+    );
+}
+
+#[test(tokio::test)]
+async fn test_dockerignore_walks_ignored_dirs() {
+    let folder = make_folder(&[(".dockerignore", "target\n!README.md\n")]).await;
+    let ignore = ignore_from_dockerignore(&folder, "Dockerfile").unwrap();
+    assert!(!ignore.walks_ignored_dirs());
+
+    tokio::fs::write(
+        folder.join(".dockerignore"),
+        "target\n!target/release/app\n",
+    )
+    .await
+    .unwrap();
+    let ignore = ignore_from_dockerignore(&folder, "Dockerfile").unwrap();
+    assert!(ignore.walks_ignored_dirs());
+}
+
+#[test(tokio::test)]
+async fn test_pack_without_dockerignore() {
+    let folder = make_folder(&[("Dockerfile", "FROM alpine"), ("a/b.txt", "b")]).await;
+
+    let ignore = ignore_from_dockerignore(&folder, "Dockerfile").unwrap();
+    assert_eq!(
+        packed_files(&folder, ignore).await,
+        vec!["Dockerfile", "a/b.txt"]
+    );
+}
+
+#[test(tokio::test)]
+async fn test_pack_test_ignore() {
+    let folder = make_folder(&[
+        ("testignore", "*.bak\ntmp/\n"),
+        ("data/1.in", "1"),
+        ("data/1.in.bak", "1"),
+        ("data/tmp/scratch", "x"),
+    ])
+    .await;
+    let data = folder.join("data");
+
+    let ignore = ignore_from_file(&data, &folder.join("testignore")).unwrap();
+    assert_eq!(packed_files(&data, ignore).await, vec!["1.in"]);
+}
+
+#[cfg(unix)]
+#[test(tokio::test)]
+async fn test_pack_symlink_preserve() {
+    let folder = make_folder(&[("a.txt", "a")]).await;
+    std::os::unix::fs::symlink("/etc/passwd", folder.join("passwd")).unwrap();
+    std::os::unix::fs::symlink("a.txt", folder.join("b.txt")).unwrap();
+
+    let (archive, manifest) = pack(&folder, Gitignore::empty(), SymlinkPolicy::Preserve).await;
+    assert_eq!(
+        manifest.unwrap().entries,
+        vec![
+            PackedEntry {
+                path: "a.txt".into(),
+                kind: PackedEntryKind::File {
+                    size: 1,
+                    executable: false
+                }
+            },
+            PackedEntry {
+                path: "b.txt".into(),
+                kind: PackedEntryKind::Symlink {
+                    target: "a.txt".into()
+                }
+            },
+            PackedEntry {
+                path: "passwd".into(),
+                kind: PackedEntryKind::Symlink {
+                    target: "/etc/passwd".into()
+                }
+            },
+        ]
+    );
+
+    let headers = headers_of(&archive).await;
+    let (_, passwd) = headers.iter().find(|(name, _)| name == "passwd").unwrap();
+    assert!(passwd.entry_type().is_symlink());
+    assert_eq!(passwd.size().unwrap(), 0);
+    assert_eq!(
+        passwd.link_name().unwrap().unwrap().as_ref(),
+        Path::new("/etc/passwd")
+    );
+}
+
+#[cfg(unix)]
+#[test(tokio::test)]
+async fn test_pack_symlink_reject() {
+    let folder = make_folder(&[("a.txt", "a")]).await;
+    std::os::unix::fs::symlink("/etc/passwd", folder.join("passwd")).unwrap();
+
+    let (_, manifest) = pack(&folder, Gitignore::empty(), SymlinkPolicy::Reject).await;
+    assert_eq!(
+        manifest.unwrap_err().kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+
+    // Ignored links don't count
+    tokio::fs::write(folder.join(".dockerignore"), "passwd")
+        .await
+        .unwrap();
+    let ignore = ignore_from_dockerignore(&folder, "Dockerfile").unwrap();
+    let (_, manifest) = pack(&folder, ignore, SymlinkPolicy::Reject).await;
+    manifest.unwrap();
+}
+
+#[cfg(unix)]
+#[test(tokio::test)]
+async fn test_pack_deterministic_metadata() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let folder = make_folder(&[("run.sh", "echo"), ("data.txt", "data"), ("z/y.txt", "y")]).await;
+    let set_mode = |name: &str, mode| {
+        std::fs::set_permissions(folder.join(name), std::fs::Permissions::from_mode(mode)).unwrap()
+    };
+    set_mode("run.sh", 0o700);
+    set_mode("data.txt", 0o600);
+    let status = std::process::Command::new("mkfifo")
+        .arg(folder.join("pipe"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let (first, manifest) = pack(&folder, Gitignore::empty(), SymlinkPolicy::Preserve).await;
+    let manifest = manifest.unwrap();
+    assert_eq!(manifest.skipped, vec![Path::new("pipe")]);
+
+    let headers = headers_of(&first).await;
+    let modes = headers
+        .iter()
+        .map(|(name, header)| (name.as_str(), header.mode().unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        modes,
+        vec![("data.txt", 0o644), ("run.sh", 0o755), ("z/y.txt", 0o644)]
+    );
+    assert!(headers
+        .iter()
+        .all(|(_, header)| header.mtime().unwrap() == PACKED_MTIME));
+
+    // Touching files doesn't change the archive
+    set_mode("data.txt", 0o640);
+    tokio::fs::write(folder.join("z/y.txt"), "y").await.unwrap();
+    let (second, _) = pack(&folder, Gitignore::empty(), SymlinkPolicy::Preserve).await;
+    assert_eq!(first, second);
+}
+
+#[test(tokio::test)]
+async fn test_digest_packed_tar() {
+    let files = [
+        ("Dockerfile", "FROM scratch\nCOPY . /src\n"),
+        (".dockerignore", "target\n"),
+        ("src/main.c", "int main() {}"),
+    ];
+    let first = make_folder(&files).await;
+    let second = make_folder(&files).await;
+    async fn digest(folder: &Path) -> String {
+        let ignore = ignore_from_dockerignore(folder, "Dockerfile").unwrap();
+        digest_packed_tar(folder, ignore, SymlinkPolicy::Preserve)
+            .await
+            .unwrap()
+    }
+
+    // Same contents in different places
+    let original = digest(&first).await;
+    assert_eq!(original.len(), 64);
+    assert_eq!(original, digest(&second).await);
+
+    // Ignored files don't matter
+    tokio::fs::create_dir_all(second.join("target"))
+        .await
+        .unwrap();
+    tokio::fs::write(second.join("target/main.o"), "obj")
+        .await
+        .unwrap();
+    assert_eq!(original, digest(&second).await);
+
+    // Both sources and the Dockerfile do
+    tokio::fs::write(second.join("src/main.c"), "int main() { return 1; }")
+        .await
+        .unwrap();
+    assert_ne!(original, digest(&second).await);
+    tokio::fs::write(first.join("Dockerfile"), "FROM scratch\n")
+        .await
+        .unwrap();
+    assert_ne!(original, digest(&first).await);
+}
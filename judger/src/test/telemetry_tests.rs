@@ -0,0 +1,87 @@
+//! Tests to verify that spans are exported by [`crate::util::telemetry`] to a
+//! collector, under the span of the coordinator.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+use hyper::{service::service_fn, Body, Request, Response};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tracing_subscriber::prelude::*;
+
+use crate::util::telemetry::{otlp_tracer, set_remote_parent, shutdown_tracer};
+
+const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+const PARENT_ID: &str = "00f067aa0ba902b7";
+
+/// A stand-in OTLP/HTTP collector, sending the body of every request into
+/// `bodies`.
+async fn serve_collector(
+    listener: tokio::net::TcpListener,
+    bodies: UnboundedSender<(String, Vec<u8>)>,
+) {
+    loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        let bodies = bodies.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req: Request<Body>| {
+                let bodies = bodies.clone();
+                async move {
+                    let path = req.uri().path().to_owned();
+                    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                    let _ = bodies.send((path, body.to_vec()));
+                    Ok::<_, Infallible>(Response::new(Body::empty()))
+                }
+            });
+            let _ = hyper::server::conn::Http::new()
+                .serve_connection(stream, service)
+                .await;
+        });
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_otlp_export() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+    let (send, mut recv) = unbounded_channel();
+    let collector = tokio::spawn(serve_collector(listener, send));
+
+    let tracer = otlp_tracer(&endpoint).unwrap();
+    let subscriber =
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::with_default(subscriber, || {
+        let trace_context = HashMap::from([(
+            "traceparent".to_owned(),
+            format!("00-{}-{}-01", TRACE_ID, PARENT_ID),
+        )]);
+        let span = tracing::info_span!("handle_job", job_id = "1");
+        set_remote_parent(&span, &trace_context);
+        span.in_scope(|| {
+            tracing::info_span!("test_case", case = "a").in_scope(|| {
+                tracing::info_span!("exec_step", command = "echo a").in_scope(|| {});
+            });
+        });
+    });
+    tokio::task::spawn_blocking(shutdown_tracer).await.unwrap();
+
+    let mut exported = vec![];
+    while let Ok((path, body)) = recv.try_recv() {
+        assert_eq!(path, "/v1/traces");
+        exported.extend(body);
+    }
+    for name in ["handle_job", "test_case", "exec_step"] {
+        assert!(
+            contains(&exported, name.as_bytes()),
+            "{} not exported",
+            name
+        );
+    }
+    assert!(contains(&exported, &hex::decode(TRACE_ID).unwrap()));
+    assert!(contains(&exported, &hex::decode(PARENT_ID).unwrap()));
+
+    collector.abort();
+}
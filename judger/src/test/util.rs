@@ -2,7 +2,8 @@
 
 use std::borrow::Cow;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
 
 use bytes::{Bytes, BytesMut};
 
@@ -10,6 +11,7 @@ use tokio::task::JoinHandle;
 use tokio_stream::Stream;
 use tokio_tar::Header;
 
+use crate::prelude::FlowSnake;
 use crate::runner::model::{CommandRunOptions, ExitStatus, ProcessOutput};
 use crate::runner::CommandRunner;
 
@@ -18,6 +20,38 @@ pub fn project_root_dir() -> PathBuf {
     PathBuf::from(env!("CARGO_MANIFEST_DIR"))
 }
 
+/// A new folder inside the system temporary folder. It's removed with
+/// everything inside when dropped, even if the test panics.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(FlowSnake::generate().to_string());
+        std::fs::create_dir_all(&path).expect("Failed to create temporary folder");
+        TempDir(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
 pub fn tar_with_files(
     files: impl Iterator<Item = (String, Bytes)> + Send + 'static,
 ) -> (
@@ -33,7 +67,9 @@ pub fn tar_with_files(
         for (name, file) in files {
             let mut header = Header::new_gnu();
             header.set_path(name).unwrap();
-            header.set_mode(0o777);
+            header.set_size(file.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
             tar.append(&header, &*file)
                 .await
                 .expect("Failed to append file");
@@ -46,15 +82,22 @@ pub fn tar_with_files(
 
 pub struct MockRunner {
     input_output: HashMap<String, ProcessOutput>,
+    disk_full: bool,
 }
 
 impl MockRunner {
     pub fn new() -> Self {
         MockRunner {
             input_output: Default::default(),
+            disk_full: false,
         }
     }
 
+    /// Report the disk space of this runner as used up.
+    pub fn disk_full(&mut self) {
+        self.disk_full = true;
+    }
+
     pub fn insert(&mut self, command: String, output: ProcessOutput) {
         self.input_output.insert(command, output);
     }
@@ -129,4 +172,8 @@ impl CommandRunner for MockRunner {
             )),
         }
     }
+
+    async fn is_disk_full(&self) -> anyhow::Result<bool> {
+        Ok(self.disk_full)
+    }
 }
@@ -3,16 +3,28 @@
 //! This module is not responsible for any concrete judging implementation. See
 //! [`crate::runner`] for detail on image builder and command runners.
 
+use std::collections::HashMap;
 use std::path::Path;
+use std::time::{Duration, Instant};
 
 use bollard::Docker;
 
+use crate::prelude::{CancelFutureExt, CancellationTokenHandle};
 use crate::runner;
 use crate::runner::exec::{Container, CreateContainerConfig, CreateContainerConfigBuilder};
-use crate::runner::image::{build_image, BuildImageOptionsBuilder, BuildImageResult};
+use crate::runner::image::{
+    build_image, BuildImageOptionsBuilder, BuildImageResult, RegistryCredential,
+};
+use crate::runner::model::{CommandRunOptionsBuilder, ExitStatus};
 use crate::tester::model::BuildError;
 
-use self::model::{Image, JudgeExecKind, JudgerPublicConfig};
+use self::model::{BaseImage, Image, JudgeExecKind, JudgerPublicConfig, ServiceDefinition};
+
+/// Default time to wait for a service to be ready
+const DEFAULT_SERVICE_READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Interval between two readiness probes of a service
+const SERVICE_PROBE_INTERVAL: Duration = Duration::from_millis(500);
 
 pub mod model;
 pub mod runner_plan;
@@ -25,6 +37,7 @@ pub async fn build_judger_container(
     pub_cfg: &JudgerPublicConfig,
     base_path: &Path,
     guid: &str,
+    registry_credentials: &HashMap<String, RegistryCredential>,
     cfg: CreateContainerConfig,
 ) -> anyhow::Result<Option<Container>> {
     tracing::info!(%guid, "Building judger container");
@@ -34,11 +47,16 @@ pub async fn build_judger_container(
             tracing::info!("Legacy judging, no container");
             Ok(None)
         }
-        JudgeExecKind::Isolated => {
-            make_isolated_judger_container(docker, pub_cfg, base_path, guid, cfg)
-                .await
-                .map(Some)
-        }
+        JudgeExecKind::Isolated => make_isolated_judger_container(
+            docker,
+            pub_cfg,
+            base_path,
+            guid,
+            registry_credentials,
+            cfg,
+        )
+        .await
+        .map(Some),
     }
 }
 
@@ -52,6 +70,7 @@ async fn make_isolated_judger_container(
     pub_cfg: &JudgerPublicConfig,
     base_path: &Path,
     guid: &str,
+    registry_credentials: &HashMap<String, RegistryCredential>,
     cfg: CreateContainerConfig,
 ) -> anyhow::Result<Container> {
     debug_assert!(pub_cfg.exec_kind == JudgeExecKind::Isolated);
@@ -74,9 +93,10 @@ async fn make_isolated_judger_container(
                 .base_path(base_path)
                 .tag_as(tag.clone())
                 .cancellation(cfg.cancellation.clone())
+                .registry_credentials(registry_credentials.clone())
                 .build()
                 .expect("Failed to generate build options");
-            let BuildImageResult {} =
+            let BuildImageResult { .. } =
                 runner::image::build_image(docker.clone(), exec_environment, opt).await?;
             docker.inspect_image(&tag).await?
         }
@@ -105,9 +125,9 @@ pub async fn build_user_code_container(
         .build()
         .expect("Failed to generate build options");
 
-    let _image = build_image(docker.clone(), image, cfg).await?;
+    let res = build_image(docker.clone(), image, cfg).await?;
 
-    tracing::info!(%image_name, "Creating container from user code image");
+    tracing::info!(%image_name, cache_hit = res.cache_hit, "Creating container from user code image");
 
     let cfg = config_create_container_configs(CreateContainerConfigBuilder::default())
         .build()
@@ -119,3 +139,146 @@ pub async fn build_user_code_container(
 
     Ok(container)
 }
+
+/// Build the shared base image of a test suite, reusing the image built from
+/// the same sources if there is one. Returns the id of the image.
+pub async fn build_base_image(
+    docker: Docker,
+    base_image: &BaseImage,
+    base_path: &Path,
+    registry_credentials: &HashMap<String, RegistryCredential>,
+    cancel: CancellationTokenHandle,
+) -> Result<String, BuildError> {
+    tracing::info!(tag = %base_image.tag, "Building base image");
+    if let Image::Dockerfile { path, .. } = &base_image.image {
+        crate::util::path_security::assert_child_path(path)
+            .map_err(|e| BuildError::Internal(e.into()))?;
+    }
+
+    let opt = BuildImageOptionsBuilder::default()
+        .base_path(base_path)
+        .tag_as(base_image.tag.clone())
+        .cancellation(cancel)
+        .registry_credentials(registry_credentials.clone())
+        .cache(true)
+        .build()
+        .expect("Failed to generate build options");
+    let res = build_image(docker.clone(), &base_image.image, opt).await?;
+    tracing::info!(tag = %base_image.tag, cache_hit = res.cache_hit, "Built base image");
+
+    let image = docker
+        .inspect_image(&base_image.tag)
+        .await
+        .map_err(|e| BuildError::Internal(e.into()))?;
+    Ok(image.id)
+}
+
+/// Start a service container inside `network`, and wait until it's ready.
+///
+/// The service is reachable from other containers in `network` by its name.
+pub async fn start_service_container(
+    docker: Docker,
+    service: &ServiceDefinition,
+    base_path: &Path,
+    guid: &str,
+    network: &str,
+    registry_credentials: &HashMap<String, RegistryCredential>,
+    cancel: CancellationTokenHandle,
+) -> anyhow::Result<Container> {
+    service.validate_name()?;
+    tracing::info!(%guid, name = %service.name, "Starting service container");
+
+    let image = match &service.image {
+        Image::Prebuilt { tag, .. } => {
+            let opt = BuildImageOptionsBuilder::default()
+                .base_path(base_path)
+                .tag_as(tag.clone())
+                .cancellation(cancel.clone())
+                .registry_credentials(registry_credentials.clone())
+                .build()
+                .expect("Failed to generate build options");
+            let BuildImageResult { .. } = build_image(docker.clone(), &service.image, opt).await?;
+            tag.clone()
+        }
+        Image::Dockerfile { path, .. } => {
+            crate::util::path_security::assert_child_path(path)?;
+            let tag = format!("test-service-{}:{}", service.name, guid);
+            match docker.inspect_image(&tag).await {
+                Ok(_) => {}
+                Err(bollard::errors::Error::DockerResponseNotFoundError { .. }) => {
+                    let opt = BuildImageOptionsBuilder::default()
+                        .base_path(base_path)
+                        .tag_as(tag.clone())
+                        .cancellation(cancel.clone())
+                        .registry_credentials(registry_credentials.clone())
+                        .build()
+                        .expect("Failed to generate build options");
+                    let BuildImageResult { .. } =
+                        build_image(docker.clone(), &service.image, opt).await?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+            tag
+        }
+    };
+
+    let cfg = CreateContainerConfigBuilder::default()
+        .cancellation(cancel.clone())
+        .tag_name(format!("service_{}_{}", service.name, guid))
+        .network(Some(network.to_owned()))
+        .network_aliases(vec![service.name.clone()])
+        .env(service.env.clone())
+        .keep_entrypoint(true)
+        .build()
+        .expect("Failed to generate CreateContainerConfig");
+    let mut container = Container::create(docker, image, cfg).await?;
+
+    if let Some(probe) = &service.ready_probe {
+        let timeout = service
+            .ready_timeout
+            .map_or(DEFAULT_SERVICE_READY_TIMEOUT, Duration::from_secs_f64);
+        if let Err(e) = wait_for_service(&container, probe, timeout, cancel).await {
+            let _ = container.remove().await;
+            return Err(e.context(format!("Service `{}` is not ready", service.name)));
+        }
+    }
+
+    Ok(container)
+}
+
+/// Run `probe` inside `container` until it returns 0.
+async fn wait_for_service(
+    container: &Container,
+    probe: &str,
+    timeout: Duration,
+    cancel: CancellationTokenHandle,
+) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let opt = CommandRunOptionsBuilder::default()
+        .timeout(Some(timeout))
+        .cancel(cancel.clone())
+        .build()
+        .expect("Failed to generate CommandRunOptions");
+    loop {
+        let output = container
+            .exec(probe, &mut std::iter::empty(), &opt)
+            .with_cancel(cancel.cancelled())
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Cancelled"))??;
+        if output.ret_code == ExitStatus::ReturnCode(0) {
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            anyhow::bail!(
+                "Readiness probe did not succeed in {:?}; last output: {}{}",
+                timeout,
+                output.stdout,
+                output.stderr
+            );
+        }
+        tokio::time::sleep(SERVICE_PROBE_INTERVAL)
+            .with_cancel(cancel.cancelled())
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Cancelled"))?;
+    }
+}
@@ -12,11 +12,14 @@ use std::{
     string::String,
 };
 
+use crate::runner::exec::{RelaxationLimits, SecurityProfile};
+
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub enum ExecErrorKind {
     RuntimeError(String),
     ReturnCodeCheckFailed,
     TimedOut,
+    DiskLimitExceeded,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
@@ -125,7 +128,14 @@ pub fn canonical_join(base: impl AsRef<Path>, relative: impl AsRef<Path>) -> Pat
 pub enum Image {
     /// An existing image.
     #[serde(alias = "image")]
-    Prebuilt { tag: String },
+    Prebuilt {
+        /// Reference of the image, e.g. `gcc:11`, `ghcr.io/foo/bar:latest` or
+        /// `alpine@sha256:...`.
+        tag: String,
+        /// When to pull the image from its registry.
+        #[serde(default)]
+        pull: PullPolicy,
+    },
     /// An image to be built with a Dockerfile.
     Dockerfile {
         /// Path of the context directory, must be relative to the current directory.
@@ -133,19 +143,53 @@ pub enum Image {
         /// Path of the dockerfile itself, relative to the context directory.
         /// Leaving this value to None means using the default dockerfile: `path/Dockerfile`.
         file: Option<String>,
+        /// Build arguments, i.e. values of `ARG`s in the dockerfile. Arguments
+        /// of submitted images must be allowed by the test suite, see
+        /// [`JudgerPublicConfig::allowed_build_args`].
+        #[serde(default)]
+        args: HashMap<String, String>,
+        /// The stage to build in a multi-stage dockerfile. Leaving this value
+        /// to None means building the last stage.
+        #[serde(default)]
+        target: Option<String>,
+        /// Labels of the built image.
+        #[serde(default)]
+        labels: HashMap<String, String>,
+        /// The platform to build for, e.g. `linux/amd64`.
+        #[serde(default)]
+        platform: Option<String>,
     },
 }
 
+impl Image {
+    /// Remove build arguments that are not in `allowed`, returning the names
+    /// of those removed.
+    pub fn retain_build_args(&mut self, allowed: &[String]) -> Vec<String> {
+        let mut removed = vec![];
+        if let Image::Dockerfile { args, .. } = self {
+            args.retain(|name, _| {
+                let keep = allowed.contains(name);
+                if !keep {
+                    removed.push(name.clone());
+                }
+                keep
+            });
+        }
+        removed.sort();
+        removed
+    }
+}
+
 impl<'js, 'a> rquickjs::IntoJs<'js> for &'a Image {
     fn into_js(self, ctx: rquickjs::Ctx<'js>) -> rquickjs::Result<rquickjs::Value<'js>> {
         Ok(match self {
-            Image::Prebuilt { tag } => {
+            Image::Prebuilt { tag, .. } => {
                 let obj = rquickjs::Object::new(ctx)?;
                 obj.set("source", "image")?;
                 obj.set("tag", tag)?;
                 obj.into_value()
             }
-            Image::Dockerfile { path, file } => {
+            Image::Dockerfile { path, file, .. } => {
                 let obj = rquickjs::Object::new(ctx)?;
                 obj.set("source", "dockerfile")?;
                 obj.set("path", path.display().to_string())?;
@@ -158,6 +202,19 @@ impl<'js, 'a> rquickjs::IntoJs<'js> for &'a Image {
     }
 }
 
+/// When to pull a prebuilt image from its registry.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum PullPolicy {
+    /// Pull the image every time it's used.
+    Always,
+    /// Pull the image only if it's not present on the host.
+    #[default]
+    IfNotPresent,
+    /// Never pull the image. It must be present on the host.
+    Never,
+}
+
 pub fn random_tag() -> String {
     Generator::with_naming(Name::Plain).next().unwrap()
 }
@@ -195,6 +252,25 @@ impl FromStr for TestCaseDefinition {
 pub struct JudgerPublicConfig {
     pub time_limit: Option<f64>,
     pub memory_limit: Option<i32>,
+
+    /// Maximum size of the standard output captured from each command, in
+    /// bytes. Capped by the judger's own limit.
+    #[quickjs(skip)]
+    #[serde(default)]
+    pub stdout_size_limit: Option<usize>,
+
+    /// Maximum size of the standard error captured from each command, in
+    /// bytes. Capped by the judger's own limit.
+    #[quickjs(skip)]
+    #[serde(default)]
+    pub stderr_size_limit: Option<usize>,
+
+    /// Maximum disk space that user code may write, in bytes. Capped by the
+    /// judger's own limit.
+    #[quickjs(skip)]
+    #[serde(default)]
+    pub disk_limit: Option<u64>,
+
     pub name: String,
     pub test_groups: HashMap<String, Vec<TestCaseDefinition>>,
 
@@ -238,6 +314,11 @@ pub struct JudgerPublicConfig {
     #[serde(default)]
     pub network: NetworkOptions,
 
+    /// Parts of the judger's security profile to relax for this suite.
+    #[quickjs(skip)]
+    #[serde(default)]
+    pub security: SecurityRelaxation,
+
     /// Test suite execution kind. See [`JudgeExecKind`] for more information.
     #[serde(default)]
     pub exec_kind: JudgeExecKind,
@@ -245,6 +326,87 @@ pub struct JudgerPublicConfig {
     /// Test suite execution environment.
     #[serde(default)]
     pub exec_environment: Option<Image>,
+
+    /// Names of build arguments that submitted Dockerfiles may set. Other
+    /// arguments are ignored.
+    #[quickjs(skip)]
+    #[serde(default)]
+    pub allowed_build_args: Vec<String>,
+
+    /// An image shared by all jobs of this test suite, e.g. one with the
+    /// toolchains installed. It's built once per judger, so that Dockerfiles
+    /// of submissions can build `FROM` it.
+    #[quickjs(skip)]
+    #[serde(default)]
+    pub base_image: Option<BaseImage>,
+
+    /// Service containers (databases, mock servers, etc.) started before the
+    /// user container. They share a private network with the user container,
+    /// where each service is reachable by its name.
+    #[quickjs(skip)]
+    #[serde(default)]
+    pub services: Vec<ServiceDefinition>,
+}
+
+/// An image shared by all jobs of a test suite.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BaseImage {
+    /// The tag to build the image as, which submissions refer to.
+    pub tag: String,
+
+    /// The image itself. Dockerfile paths are relative to the test suite
+    /// folder.
+    pub image: Image,
+}
+
+/// A service container that runs alongside the user container.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceDefinition {
+    /// Name of this service, also its host name inside the job's network.
+    pub name: String,
+
+    /// The image of this service. Dockerfile paths are relative to the test
+    /// suite folder.
+    pub image: Image,
+
+    /// Environment variables of this service.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+
+    /// A command executed inside the service container to check if it's
+    /// ready. The service is ready when the command returns 0. Leaving this
+    /// value to None means the service is ready once it starts.
+    #[serde(default)]
+    pub ready_probe: Option<String>,
+
+    /// Maximum time to wait for the service to be ready, in seconds. Defaults
+    /// to 60 seconds.
+    #[serde(default)]
+    pub ready_timeout: Option<f64>,
+}
+
+impl ServiceDefinition {
+    /// Checks if the name of this service is a valid host name label.
+    pub fn validate_name(&self) -> Result<()> {
+        let valid = !self.name.is_empty()
+            && self.name.len() <= 63
+            && !self.name.starts_with('-')
+            && !self.name.ends_with('-')
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        if !valid {
+            anyhow::bail!(
+                "Service name `{}` is not a valid host name: only lowercase letters, digits and \
+                 dashes are allowed",
+                self.name
+            );
+        }
+        Ok(())
+    }
 }
 
 /// Judger execution kind of the specific test suite
@@ -266,31 +428,183 @@ impl Default for JudgeExecKind {
     }
 }
 
+/// Network access of a judging stage.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum NetworkMode {
+    /// No network access. Service containers are still reachable.
+    None,
+    /// Access to a private network of the job, shared by the judger
+    /// container, the user container and service containers, but not the
+    /// outside world.
+    Internal,
+    /// Full network access, including the outside world.
+    Full,
+}
+
+impl NetworkMode {
+    /// The name of this mode in `testconf.json`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NetworkMode::None => "none",
+            NetworkMode::Internal => "internal",
+            NetworkMode::Full => "full",
+        }
+    }
+}
+
 /// Network options for judge containers.
-#[derive(Serialize, Deserialize, Debug, Clone, IntoJsByRef)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
-#[quickjs(rename_all = "camelCase")]
 pub struct NetworkOptions {
-    /// Disable networking when running. Defaults to be true.
-    #[serde(default)]
-    pub enable_running: bool,
-    /// Disable networking when building. Defaults to be false.
-    #[serde(default = "return_true")]
-    pub enable_build: bool,
+    /// Network access when running. Defaults to be none.
+    ///
+    /// For compatibility, `true` and `false` are accepted as full and none.
+    #[serde(
+        default = "NetworkOptions::default_run",
+        alias = "enableRunning",
+        deserialize_with = "network_mode_or_bool"
+    )]
+    pub run: NetworkMode,
+    /// Network access when building. Defaults to be full.
+    ///
+    /// For compatibility, `true` and `false` are accepted as full and none.
+    #[serde(
+        default = "NetworkOptions::default_build",
+        alias = "enableBuild",
+        deserialize_with = "network_mode_or_bool"
+    )]
+    pub build: NetworkMode,
 }
 
 impl Default for NetworkOptions {
     fn default() -> Self {
         NetworkOptions {
-            enable_running: false,
-            enable_build: true,
+            run: Self::default_run(),
+            build: Self::default_build(),
         }
     }
 }
 
 impl NetworkOptions {
+    fn default_run() -> NetworkMode {
+        NetworkMode::None
+    }
+
+    fn default_build() -> NetworkMode {
+        NetworkMode::Full
+    }
+
+    /// Whether any stage of the job has network access.
     pub fn use_network(&self) -> bool {
-        !(self.enable_build && self.enable_running)
+        self.build != NetworkMode::None || self.run != NetworkMode::None
+    }
+
+    /// Whether the job needs a private network shared by its containers.
+    pub fn use_job_network(&self) -> bool {
+        self.build == NetworkMode::Internal || self.run == NetworkMode::Internal
+    }
+}
+
+/// Special judge scripts written before network modes existed read
+/// `enableRunning` and `enableBuild` as booleans, so these are kept besides
+/// `run` and `build`.
+impl<'js> rquickjs::IntoJs<'js> for &NetworkOptions {
+    fn into_js(self, ctx: rquickjs::Ctx<'js>) -> rquickjs::Result<rquickjs::Value<'js>> {
+        let obj = rquickjs::Object::new(ctx)?;
+        obj.set("run", self.run.as_str())?;
+        obj.set("build", self.build.as_str())?;
+        obj.set("enableRunning", self.run != NetworkMode::None)?;
+        obj.set("enableBuild", self.build != NetworkMode::None)?;
+        Ok(obj.into_value())
+    }
+}
+
+fn network_mode_or_bool<'de, D>(deserializer: D) -> Result<NetworkMode, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Enabled(bool),
+        Mode(NetworkMode),
+    }
+
+    Ok(match Repr::deserialize(deserializer)? {
+        Repr::Enabled(true) => NetworkMode::Full,
+        Repr::Enabled(false) => NetworkMode::None,
+        Repr::Mode(mode) => mode,
+    })
+}
+
+/// Parts of the judger's [`SecurityProfile`] that a test suite may relax, e.g.
+/// when its tests need to write outside of temporary directories.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SecurityRelaxation {
+    /// Capabilities to keep, e.g. `SYS_PTRACE` for debuggers.
+    pub cap_add: Vec<String>,
+    /// Allow writing to the root filesystem.
+    pub writable_rootfs: bool,
+    /// Allow processes to gain privileges through setuid binaries.
+    pub allow_new_privileges: bool,
+    /// Replaces the limit of processes and threads.
+    pub pids_limit: Option<i64>,
+    /// Replaces the size limit of tmpfs directories, in bytes.
+    pub tmpfs_size: Option<u64>,
+    /// Replaces the limit of open files.
+    pub nofile_limit: Option<i64>,
+}
+
+impl SecurityRelaxation {
+    /// Returns `profile` with the relaxations of this suite applied, within
+    /// the [`RelaxationLimits`] of `profile`.
+    pub fn relax(&self, profile: &SecurityProfile) -> SecurityProfile {
+        let mut profile = profile.clone();
+        let limits = &profile.relaxation_limits;
+        for cap in &self.cap_add {
+            if limits.allows_capability(cap) {
+                profile.cap_add.push(cap.clone());
+            } else {
+                tracing::warn!(
+                    "Test suite asked for capability {}, which is not allowed",
+                    cap
+                );
+            }
+        }
+        if self.writable_rootfs {
+            if limits.allow_writable_rootfs {
+                profile.read_only_rootfs = false;
+            } else {
+                tracing::warn!(
+                    "Test suite asked for a writable root filesystem, which is not allowed"
+                );
+            }
+        }
+        if self.allow_new_privileges {
+            if limits.allow_new_privileges {
+                profile.no_new_privileges = false;
+            } else {
+                tracing::warn!("Test suite asked for new privileges, which is not allowed");
+            }
+        }
+        if let Some(pids_limit) = self.pids_limit {
+            profile.pids_limit = Some(RelaxationLimits::clamp_limit(
+                pids_limit,
+                limits.max_pids_limit,
+            ));
+        }
+        if let Some(tmpfs_size) = self.tmpfs_size {
+            profile.tmpfs_size = tmpfs_size.min(limits.max_tmpfs_size);
+        }
+        if let Some(nofile_limit) = self.nofile_limit {
+            profile.nofile_limit = Some(RelaxationLimits::clamp_limit(
+                nofile_limit,
+                limits.max_nofile_limit,
+            ));
+        }
+        profile
     }
 }
 
@@ -421,6 +735,55 @@ mod de {
     }
 }
 
-const fn return_true() -> bool {
-    true
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_retain_build_args() {
+        let mut image: Image = serde_json::from_str(
+            r#"{"source": "dockerfile", "path": ".", "args": {"OPT": "2", "CC": "clang", "EVIL": "1"}}"#,
+        )
+        .unwrap();
+        let removed = image.retain_build_args(&["OPT".into(), "CC".into()]);
+        assert_eq!(removed, vec!["EVIL".to_owned()]);
+        match image {
+            Image::Dockerfile { args, .. } => {
+                assert_eq!(args.len(), 2);
+                assert_eq!(args["CC"], "clang");
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_network_options_compat() {
+        let opt: NetworkOptions = serde_json::from_str("{}").unwrap();
+        assert_eq!(opt.run, NetworkMode::None);
+        assert_eq!(opt.build, NetworkMode::Full);
+        assert!(opt.use_network());
+
+        let opt: NetworkOptions =
+            serde_json::from_str(r#"{"enableRunning": false, "enableBuild": false}"#).unwrap();
+        assert_eq!(opt.run, NetworkMode::None);
+        assert_eq!(opt.build, NetworkMode::None);
+        assert!(!opt.use_network());
+
+        let opt: NetworkOptions =
+            serde_json::from_str(r#"{"run": "internal", "enableBuild": true}"#).unwrap();
+        assert_eq!(opt.run, NetworkMode::Internal);
+        assert_eq!(opt.build, NetworkMode::Full);
+        assert!(opt.use_job_network());
+
+        let rt = rquickjs::Runtime::new().unwrap();
+        let ctx = rquickjs::Context::full(&rt).unwrap();
+        ctx.with(|ctx| {
+            ctx.globals().set("network", &opt).unwrap();
+            let seen: String = ctx.eval("JSON.stringify(network)").unwrap();
+            assert_eq!(
+                seen,
+                r#"{"run":"internal","build":"full","enableRunning":true,"enableBuild":true}"#
+            );
+        });
+    }
 }
@@ -1,16 +1,26 @@
 //! Code for transforming test suite configs into something that [`crate::runner`]
 //! can efficiently use.
 
-use std::{collections::HashMap, path::Path, pin::Pin, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
 
 use futures::{Sink, SinkExt};
 use itertools::Itertools;
 use path_slash::PathBufExt;
+use tracing::Instrument;
 
+use crate::client::config::DockerConfig;
 use crate::config::JudgeTomlTestConfig;
 use crate::prelude::CancellationTokenHandle;
 use crate::runner::{
-    model::{CommandRunOptionsBuilder, ProcessOutput},
+    model::{
+        CommandRunOptions, CommandRunOptionsBuilder, ProcessOutput, DEFAULT_OUTPUT_SIZE_LIMIT,
+    },
     CommandRunner,
 };
 use crate::{
@@ -39,7 +49,7 @@ pub async fn run_job_test_cases<'a>(
     judger_container: Option<Arc<dyn CommandRunner>>,
     mut raw_result_sink: Pin<Box<dyn Sink<RawTestCaseResult, Error = ()> + Send>>,
     test_suite_base_dir: &'a Path,
-    cancel: CancellationTokenHandle,
+    run_option: &'a CommandRunOptions,
 ) -> anyhow::Result<()> {
     tracing::info!(%job.id, "Planning to run job");
 
@@ -53,12 +63,6 @@ pub async fn run_job_test_cases<'a>(
         .map(|case| (case.name.as_str(), case))
         .collect::<HashMap<_, _>>();
 
-    let run_option = CommandRunOptionsBuilder::default()
-        .cancel(cancel.clone())
-        .timeout(public_cfg.time_limit.map(Duration::from_secs_f64))
-        .build()
-        .expect("Failed to build command run options");
-
     for case in job
         .tests
         .iter()
@@ -85,7 +89,9 @@ pub async fn run_job_test_cases<'a>(
             res
         });
 
-        let case_res = crate::runner::run_test_case(&runner_case, &run_option, sink).await?;
+        let case_res = crate::runner::run_test_case(&runner_case, run_option, sink)
+            .instrument(tracing::info_span!("test_case", case = %case.name))
+            .await?;
         let case_res = apply_additional_run_flags(case_res, additional_flags);
         let output = output_collector
             .await
@@ -102,6 +108,32 @@ pub async fn run_job_test_cases<'a>(
     Ok(())
 }
 
+/// Create the options for running commands of a test suite, applying the
+/// judger's limits on top of the suite's.
+pub fn make_run_options(
+    public_cfg: &JudgerPublicConfig,
+    docker_cfg: &DockerConfig,
+    spill_folder: PathBuf,
+    cancel: CancellationTokenHandle,
+) -> CommandRunOptions {
+    let cap = |limit: Option<usize>| {
+        let limit = limit.unwrap_or(DEFAULT_OUTPUT_SIZE_LIMIT);
+        docker_cfg
+            .max_output_size
+            .map_or(limit, |max| limit.min(max))
+    };
+
+    CommandRunOptionsBuilder::default()
+        .cancel(cancel)
+        .timeout(public_cfg.time_limit.map(Duration::from_secs_f64))
+        .stdout_size_limit(cap(public_cfg.stdout_size_limit))
+        .stderr_size_limit(cap(public_cfg.stderr_size_limit))
+        .in_memory_size_limit(docker_cfg.output_spill_threshold)
+        .spill_folder(spill_folder)
+        .build()
+        .expect("Failed to build command run options")
+}
+
 pub fn apply_additional_run_flags(
     mut result: Result<(), JobFailure>,
     additional: AdditionalRunFlags,
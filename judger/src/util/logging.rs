@@ -0,0 +1,223 @@
+//! A `tracing` layer that tees the logs of each job into a file of its own.
+
+use serde_json::{Map, Value};
+use std::{fmt::Write as _, fs::File, io::Write, path::Path, sync::Mutex};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id},
+    Event, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer, Registry};
+
+/// The span field that names the log file of a span. Events inside a span
+/// with this field are also written into that file by [`JobLogLayer`].
+pub const LOG_FILE_FIELD: &str = "log_file";
+
+/// Fields of a span or an event, as JSON values.
+#[derive(Debug, Default)]
+struct JsonFields(Map<String, Value>);
+
+impl Visit for JsonFields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{:?}", value).into());
+    }
+}
+
+fn timestamp() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true)
+}
+
+/// The log file of a span, see [`LOG_FILE_FIELD`].
+struct JobLogFile(Mutex<File>);
+
+/// Why the log file of a span could not be opened.
+struct JobLogError(std::io::Error);
+
+/// Tees events inside spans with a [`LOG_FILE_FIELD`] into that file, as
+/// human-readable lines.
+#[derive(Debug, Default)]
+pub struct JobLogLayer;
+
+impl JobLogLayer {
+    fn open(path: &Path) -> std::io::Result<File> {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+    }
+
+    /// Take the error that kept this layer from opening the log file of
+    /// `span`, if any. The error can't be logged when it happens, since the
+    /// log would end up in this layer again.
+    pub fn take_error(span: &tracing::Span) -> Option<std::io::Error> {
+        span.with_subscriber(|(id, dispatch)| {
+            let registry = dispatch.downcast_ref::<Registry>()?;
+            let span = registry.span(id)?;
+            let error = span.extensions_mut().remove::<JobLogError>();
+            error.map(|e| e.0)
+        })
+        .flatten()
+    }
+}
+
+impl<S> Layer<S> for JobLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if attrs.metadata().fields().field(LOG_FILE_FIELD).is_none() {
+            return;
+        }
+        let mut fields = JsonFields::default();
+        attrs.record(&mut fields);
+        let path = match fields.0.get(LOG_FILE_FIELD) {
+            Some(Value::String(path)) => path.clone(),
+            _ => return,
+        };
+        let span = ctx.span(id).expect("the span being created should exist");
+        let mut extensions = span.extensions_mut();
+        match Self::open(Path::new(&path)) {
+            Ok(file) => extensions.insert(JobLogFile(Mutex::new(file))),
+            Err(e) => extensions.insert(JobLogError(e)),
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let scope = match ctx.event_scope(event) {
+            Some(scope) => scope,
+            None => return,
+        };
+        let span = match scope
+            .into_iter()
+            .find(|span| span.extensions().get::<JobLogFile>().is_some())
+        {
+            Some(span) => span,
+            None => return,
+        };
+
+        let mut fields = JsonFields::default();
+        event.record(&mut fields);
+        let meta = event.metadata();
+        let mut line = format!(
+            "{} {:>5} {}:",
+            timestamp(),
+            meta.level().as_str(),
+            meta.target()
+        );
+        if let Some(Value::String(message)) = fields.0.remove("message") {
+            let _ = write!(line, " {}", message);
+        }
+        for (name, value) in &fields.0 {
+            match value {
+                Value::String(s) => write!(line, " {}={}", name, s),
+                value => write!(line, " {}={}", name, value),
+            }
+            .unwrap();
+        }
+        line.push('\n');
+
+        let extensions = span.extensions();
+        let file = extensions.get::<JobLogFile>().unwrap();
+        let _ = file.0.lock().unwrap().write_all(line.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::util::TempDir;
+    use std::sync::Arc;
+    use tracing_subscriber::prelude::*;
+
+    /// Collects everything written into it.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_and_job_log_layers() {
+        let folder = TempDir::new();
+        let log_file = folder.join("job.log");
+
+        let buffer = Buffer::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(true)
+                    .with_writer({
+                        let buffer = buffer.clone();
+                        move || buffer.clone()
+                    }),
+            )
+            .with(JobLogLayer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("outside");
+            let span =
+                tracing::info_span!("handle_job", job_id = 42, log_file = %log_file.display());
+            span.in_scope(|| tracing::warn!(case = "1", "inside"));
+            assert!(JobLogLayer::take_error(&span).is_none());
+
+            let span = tracing::info_span!(
+                "handle_job",
+                log_file = %folder.join("missing").join("job.log").display()
+            );
+            assert!(JobLogLayer::take_error(&span).is_some());
+            assert!(JobLogLayer::take_error(&span).is_none());
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines = output
+            .lines()
+            .map(|l| serde_json::from_str::<Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["fields"]["message"], "outside");
+        assert_eq!(lines[1]["level"], "WARN");
+        assert_eq!(lines[1]["fields"]["case"], "1");
+        assert_eq!(lines[1]["spans"][0]["name"], "handle_job");
+        assert_eq!(lines[1]["spans"][0]["job_id"], 42);
+
+        let job_log = std::fs::read_to_string(&log_file).unwrap();
+        assert_eq!(job_log.lines().count(), 1);
+        assert!(
+            job_log
+                .trim_end()
+                .ends_with("WARN rurikawa_judger::util::logging::test: inside case=1"),
+            "{}",
+            job_log
+        );
+    }
+}
@@ -1,7 +1,9 @@
 pub mod async_teardown;
 mod deserialize;
+pub mod logging;
 pub mod path_security;
 pub mod tar;
+pub mod telemetry;
 pub mod names;
 
 pub use async_teardown::*;
@@ -43,6 +43,23 @@ pub fn assert_child_path(path: &Path) -> std::io::Result<()> {
     Ok(())
 }
 
+/// Checks if an archive entry can be safely extracted, i.e. its path is a child
+/// path and it is a regular file or directory instead of a link or special file.
+/// Returns `Err` if it's not.
+pub fn assert_safe_archive_entry(path: &Path, is_file_or_dir: bool) -> std::io::Result<()> {
+    assert_child_path(path)?;
+    if !is_file_or_dir {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Archive entry {} is a link or special file, which is not allowed",
+                path.to_string_lossy()
+            ),
+        ));
+    }
+    Ok(())
+}
+
 /// Checks if any parent of the given path is a symbolic link, and returns `Err`
 /// if that's true.
 pub async fn assert_no_symlink_in_path(path: &Path) -> std::io::Result<()> {
@@ -1,17 +1,20 @@
 //! Operations related to TAR archives
 //!
-//!
+//! Files can be excluded from archives with [`Gitignore`] matchers, which are
+//! built from `.gitignore`-style lists or `.dockerignore` files.
 
 use bytes::BytesMut;
-use futures::{future::BoxFuture, FutureExt};
+use futures::{future::BoxFuture, FutureExt, TryStreamExt};
 use ignore::gitignore::{Gitignore, GitignoreBuilder};
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
 use tokio::{
-    io::{self, AsyncWrite},
+    io::{self, AsyncReadExt, AsyncWrite},
     task::JoinHandle,
 };
 use tokio_stream::Stream;
-use tokio_tar::{Builder, Header};
+use tokio_tar::{Builder, EntryType, Header};
 
 #[tracing::instrument(skip(input))]
 pub fn ignore_from_string_list<'a>(
@@ -29,17 +32,156 @@ pub fn ignore_from_string_list<'a>(
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
 }
 
+/// Read ignore patterns from the file at `file`, using the same rules as
+/// `.gitignore`. Patterns are matched relative to `root`.
+pub fn ignore_from_file(root: &Path, file: &Path) -> std::io::Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(root);
+    if let Some(e) = builder.add(file) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+    }
+    builder
+        .build()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Ignore patterns used by [`pack_as_tar`].
+#[derive(Debug, Clone)]
+pub struct PackIgnore {
+    glob: Gitignore,
+    /// Whether some `!pattern` may match paths below the packed root, so
+    /// ignored directories have to be walked to find them.
+    nested_whitelists: bool,
+}
+
+impl PackIgnore {
+    pub fn empty() -> Self {
+        Gitignore::empty().into()
+    }
+
+    /// Whether ignored directories are walked for files included again by
+    /// `!pattern`.
+    pub fn walks_ignored_dirs(&self) -> bool {
+        self.nested_whitelists
+    }
+}
+
+impl From<Gitignore> for PackIgnore {
+    /// Without knowing the patterns, any `!pattern` is assumed to match
+    /// nested paths.
+    fn from(glob: Gitignore) -> Self {
+        let nested_whitelists = glob.num_whitelists() > 0;
+        PackIgnore {
+            glob,
+            nested_whitelists,
+        }
+    }
+}
+
+/// Read `.dockerignore` from the build context at `root`. An empty matcher is
+/// returned if the file does not exist.
+///
+/// Docker anchors every pattern at the context root, unlike `.gitignore` where
+/// a pattern without a slash matches at any depth. `.dockerignore` and the
+/// Dockerfile at `dockerfile` are always kept, like what `docker build` does.
+pub fn ignore_from_dockerignore(root: &Path, dockerfile: &str) -> std::io::Result<PackIgnore> {
+    let content = match std::fs::read_to_string(root.join(".dockerignore")) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(PackIgnore::empty()),
+        Err(e) => return Err(e),
+    };
+    let lines = content
+        .lines()
+        .filter_map(dockerignore_line_to_gitignore)
+        .collect::<Vec<_>>();
+    // Every translated pattern is anchored at the root, so only those with
+    // more than one path component can match below it
+    let nested_whitelists = lines.iter().any(|line| {
+        line.strip_prefix("!/")
+            .is_some_and(|pattern| pattern.contains('/') || pattern.contains("**"))
+    });
+    let builtin = [
+        "!/.dockerignore".to_owned(),
+        format!("!/{}", dockerfile.trim_start_matches("./")),
+    ];
+    let glob = ignore_from_string_list(root, lines.iter().chain(&builtin).map(|x| x.as_str()))?;
+    Ok(PackIgnore {
+        glob,
+        nested_whitelists,
+    })
+}
+
+/// Translate a line of `.dockerignore` into an equivalent `.gitignore` line.
+fn dockerignore_line_to_gitignore(line: &str) -> Option<String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (negate, pattern) = match line.strip_prefix('!') {
+        Some(p) => ("!", p.trim()),
+        None => ("", line),
+    };
+    let pattern = pattern.trim_start_matches("./").trim_start_matches('/');
+    let pattern = pattern.trim_end_matches('/');
+    if pattern.is_empty() || pattern == "." {
+        return None;
+    }
+    Some(format!("{}/{}", negate, pattern))
+}
+
+/// Modification time of every entry packed by [`pack_as_tar`], so that packing
+/// the same files always yields the same archive.
+pub const PACKED_MTIME: u64 = 0;
+
+/// What [`pack_as_tar`] does with symbolic links.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SymlinkPolicy {
+    /// Store symbolic links as links. Their targets are never read.
+    #[default]
+    Preserve,
+    /// Fail packing when a symbolic link is found.
+    Reject,
+}
+
+/// An entry packed by [`pack_as_tar`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackedEntry {
+    /// Path relative to the packed directory.
+    pub path: PathBuf,
+    pub kind: PackedEntryKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PackedEntryKind {
+    File { size: u64, executable: bool },
+    Symlink { target: PathBuf },
+}
+
+/// Everything [`pack_as_tar`] has packed, in archive order.
+#[derive(Debug, Clone, Default)]
+pub struct PackManifest {
+    pub entries: Vec<PackedEntry>,
+    /// Sockets, FIFOs and device files that were left out.
+    pub skipped: Vec<PathBuf>,
+}
+
 /// Spawn a task to pack the given `path` into a Tar file, with ignore pattern
 /// supplied as `glob`.
 ///
+/// Symbolic links are never followed; `symlinks` decides whether they are
+/// stored as links or fail the packing. Only regular files, directories and
+/// symbolic links are packed. Entries are packed in name order, with only the
+/// executable bit kept from permissions and a fixed mtime of [`PACKED_MTIME`].
+///
 /// Returns the tar file stream to read from and the join handle to the packing
-/// task.
+/// task, which resolves to the manifest of packed files.
 pub fn pack_as_tar(
     path: &Path,
-    ignore: Gitignore,
+    ignore: impl Into<PackIgnore>,
+    symlinks: SymlinkPolicy,
 ) -> io::Result<(
     impl Stream<Item = io::Result<BytesMut>> + 'static,
-    JoinHandle<io::Result<()>>,
+    JoinHandle<io::Result<PackManifest>>,
 )> {
     let (pipe_recv, pipe_send) = tokio::io::duplex(8192);
     let read_codec = tokio_util::codec::BytesCodec::new();
@@ -47,42 +189,144 @@ pub fn pack_as_tar(
 
     // Own the `path` to make `tokio` happy.
     let path = path.to_owned();
+    let ignore = ignore.into();
 
     // Launch a task for archiving.
     let archiving = tokio::spawn(async move {
         let mut tar = tokio_tar::Builder::new(pipe_recv);
-        add_dir_glob(&path, &path, &ignore, &mut tar).await?;
+        let mut manifest = PackManifest::default();
+        let mut ctx = PackContext {
+            root: &path,
+            glob: &ignore,
+            symlinks,
+            tar: &mut tar,
+            manifest: &mut manifest,
+        };
+        add_dir_glob(&mut ctx, &path).await?;
         tar.finish().await?;
-        Ok(())
+        Ok(manifest)
     });
 
     Ok((frame, archiving))
 }
 
-/// Add the given directory into the given tar, using the given glob pattern.
-fn add_dir_glob<'a, W: AsyncWrite + Send + Sync + Unpin>(
+/// Compute the content digest of what [`pack_as_tar`] packs, as a
+/// hex-encoded SHA-256 hash of the tar file. Since packed metadata is
+/// deterministic, the same contents always have the same digest.
+pub async fn digest_packed_tar(
+    path: &Path,
+    ignore: impl Into<PackIgnore>,
+    symlinks: SymlinkPolicy,
+) -> io::Result<String> {
+    let (tar, archiving) = pack_as_tar(path, ignore, symlinks)?;
+    let mut hasher = Sha256::new();
+    tar.try_for_each(|chunk| {
+        hasher.update(&chunk);
+        futures::future::ready(Ok(()))
+    })
+    .await?;
+    archiving.await.map_err(io::Error::other)??;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+struct PackContext<'a, W: AsyncWrite + Send + Sync + Unpin + 'static> {
     root: &'a Path,
-    dir: &'a Path,
-    glob: &'a Gitignore,
+    glob: &'a PackIgnore,
+    symlinks: SymlinkPolicy,
     tar: &'a mut Builder<W>,
+    manifest: &'a mut PackManifest,
+}
+
+fn packed_header(entry_type: EntryType, mode: u32, size: u64) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_mode(mode);
+    header.set_size(size);
+    header.set_mtime(PACKED_MTIME);
+    header.set_uid(0);
+    header.set_gid(0);
+    header
+}
+
+#[cfg(unix)]
+fn is_executable(meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_meta: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// Add the given directory into the given tar, using the given glob pattern.
+fn add_dir_glob<'a, 'c, W: AsyncWrite + Send + Sync + Unpin + 'static>(
+    ctx: &'a mut PackContext<'c, W>,
+    dir: &'a Path,
 ) -> BoxFuture<'a, std::io::Result<()>> {
     async move {
         let mut read_dir = tokio::fs::read_dir(dir).await?;
+        let mut paths = vec![];
         while let Some(next) = read_dir.next_entry().await? {
-            let path = next.path();
-            let meta = tokio::fs::metadata(&path).await?;
-            if glob.matched(&path, meta.is_dir()).is_ignore() {
+            paths.push(next.path());
+        }
+        paths.sort();
+
+        for path in paths {
+            let meta = tokio::fs::symlink_metadata(&path).await?;
+            let file_type = meta.file_type();
+            let relative = path.strip_prefix(ctx.root).unwrap();
+            let ignored = ctx
+                .glob
+                .glob
+                .matched_path_or_any_parents(relative, file_type.is_dir())
+                .is_ignore();
+            // An ignored directory may still contain files that are explicitly
+            // included again by `!pattern`
+            if ignored && (!file_type.is_dir() || !ctx.glob.nested_whitelists) {
                 continue;
             }
 
-            if meta.is_dir() {
-                add_dir_glob(root, &path, glob, tar).await?;
-            } else if meta.is_file() {
-                let mut file = tokio::fs::File::open(&path).await?;
-                let mut header = Header::new_gnu();
-                header.set_metadata(&meta);
-                tar.append_data(&mut header, path.strip_prefix(root).unwrap(), &mut file)
+            if file_type.is_dir() {
+                add_dir_glob(ctx, &path).await?;
+            } else if file_type.is_symlink() {
+                if ctx.symlinks == SymlinkPolicy::Reject {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "{} is a symbolic link, which is not allowed",
+                            relative.display()
+                        ),
+                    ));
+                }
+                let target = tokio::fs::read_link(&path).await?;
+                let mut header = packed_header(EntryType::Symlink, 0o777, 0);
+                header.set_link_name(&target)?;
+                ctx.tar
+                    .append_data(&mut header, relative, tokio::io::empty())
+                    .await?;
+                ctx.manifest.entries.push(PackedEntry {
+                    path: relative.to_owned(),
+                    kind: PackedEntryKind::Symlink { target },
+                });
+            } else if file_type.is_file() {
+                let executable = is_executable(&meta);
+                let mode = if executable { 0o755 } else { 0o644 };
+                let mut header = packed_header(EntryType::Regular, mode, meta.len());
+                let file = tokio::fs::File::open(&path).await?;
+                ctx.tar
+                    .append_data(&mut header, relative, file.take(meta.len()))
                     .await?;
+                ctx.manifest.entries.push(PackedEntry {
+                    path: relative.to_owned(),
+                    kind: PackedEntryKind::File {
+                        size: meta.len(),
+                        executable,
+                    },
+                });
+            } else {
+                tracing::debug!(?path, "Skipping special file");
+                ctx.manifest.skipped.push(relative.to_owned());
             }
         }
         Ok(())
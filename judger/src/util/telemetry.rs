@@ -0,0 +1,44 @@
+//! Export of `tracing` spans to an OpenTelemetry collector over OTLP.
+
+use opentelemetry::{
+    propagation::TextMapPropagator,
+    sdk::{propagation::TraceContextPropagator, trace, Resource},
+    trace::TraceError,
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use std::collections::HashMap;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+pub use opentelemetry::sdk::trace::Tracer;
+
+/// Build a tracer that sends spans in batches to the OTLP/HTTP collector at
+/// `endpoint`, e.g. `http://localhost:4318/v1/traces`. Must be called inside
+/// a Tokio runtime.
+pub fn otlp_tracer(endpoint: &str) -> Result<Tracer, TraceError> {
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(Resource::new([
+            KeyValue::new("service.name", "rurikawa-judger"),
+            KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+        ])))
+        .install_batch(opentelemetry::runtime::Tokio)
+}
+
+/// Send all spans not yet exported and stop exporting. Blocks until done.
+pub fn shutdown_tracer() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Make `span` a child of the remote span described by `trace_context`, in
+/// the W3C Trace Context format (`traceparent` and `tracestate`).
+pub fn set_remote_parent(span: &Span, trace_context: &HashMap<String, String>) {
+    let cx = TraceContextPropagator::new().extract(trace_context);
+    span.set_parent(cx);
+}